@@ -1,28 +1,234 @@
 mod prelude;
 use prelude::*;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input file
-    input: PathBuf,
+    #[arg(required = true)]
+    input: Option<PathBuf>,
 
     /// Output file
     #[arg(short, long, default_value = "a.out")]
     output: PathBuf,
+
+    /// Emit DWARF debug information
+    #[arg(short = 'g')]
+    debug: bool,
+
+    /// Write a listing of addresses and encoded bytes beside each source line
+    #[arg(short, long)]
+    listing: Option<PathBuf>,
+
+    /// Write a map of every segment, label and constant sorted by address
+    #[arg(long)]
+    map: Option<PathBuf>,
+
+    /// Format of the map file
+    #[arg(long, value_enum, default_value = "text")]
+    map_format: map::MapFormat,
+
+    /// Address the program is loaded at, overriding ORG
+    #[arg(long, value_parser = parse_address)]
+    base: Option<u64>,
+
+    /// Label to start execution at, overriding ENTRY
+    #[arg(long)]
+    entry: Option<String>,
+
+    /// Layout file placing segments at chosen addresses, one '<segment> <address>' per line
+    #[arg(long)]
+    layout: Option<PathBuf>,
+
+    /// Produce a position independent executable that can be loaded at any address
+    #[arg(long, conflicts_with = "shared")]
+    pie: bool,
+
+    /// Produce a shared library exporting the labels marked by EXPORT
+    #[arg(long)]
+    shared: bool,
+
+    /// Name the shared library is loaded by, defaults to the output file's name
+    #[arg(long, requires = "shared")]
+    soname: Option<String>,
+
+    /// Assemble to a relocatable object for 'tasm link' instead of an executable
+    #[arg(short = 'c', conflicts_with_all = ["pie", "shared", "debug", "listing", "map", "layout"])]
+    object: bool,
+
+    /// Define a constant as if by 'EQU NAME VALUE', VALUE defaults to 1
+    #[arg(short = 'D', value_name = "NAME[=VALUE]", value_parser = code_gen::parse_define)]
+    define: Vec<(String, String)>,
+
+    /// Directory to search for INCLUDE files not found beside the including file, searched
+    /// before the directories in TASM_INCLUDE
+    #[arg(short = 'I', value_name = "DIR")]
+    include: Vec<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Link relocatable objects into an executable
+    Link {
+        /// Relocatable objects made by 'tasm -c', 'nasm -f elf32' or 'gcc -m32 -c', and static
+        /// libraries made by 'tasm ar' or 'ar'
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Output file
+        #[arg(short, long, default_value = "a.out")]
+        output: PathBuf,
+
+        /// Global symbol to start execution at
+        #[arg(long, default_value = "_start")]
+        entry: String,
+
+        /// Address the program is loaded at
+        #[arg(long, value_parser = parse_address)]
+        base: Option<u64>,
+
+        /// Write a map of every segment and global symbol sorted by address
+        #[arg(long)]
+        map: Option<PathBuf>,
+
+        /// Format of the map file
+        #[arg(long, value_enum, default_value = "text")]
+        map_format: map::MapFormat,
+    },
+    /// Create, list or search static libraries (System V ar archives) of relocatable objects
+    Ar {
+        /// Archive file
+        archive: PathBuf,
+
+        /// Relocatable objects to add to the archive, replacing members of the same name
+        objects: Vec<PathBuf>,
+
+        /// List the archive's members
+        #[arg(short = 't', long)]
+        list: bool,
+
+        /// Print the member defining a global symbol
+        #[arg(long)]
+        find: Option<String>,
+    },
+}
+
+/// Reads the file at 'path', exiting on error.
+fn read_input(path: &PathBuf) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Error: can't read \"{}\" ({}).", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Reads the archive at 'path', exiting on error.
+fn read_archive(path: &PathBuf) -> archive::Archive {
+    let data = read_input(path);
+    match archive::Archive::parse(&path.display().to_string(), &data) {
+        Ok(archive) => archive,
+        Err(e) => {
+            println!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Adds objects to an archive, creating it if it doesn't exist, then lists its members or
+/// searches it for a symbol.
+fn ar(path: PathBuf, objects: Vec<PathBuf>, list: bool, find: Option<String>) {
+    if !objects.is_empty() {
+        let mut archive = if path.exists() { read_archive(&path) } else { archive::Archive::new() };
+        for object in objects {
+            let data = read_input(&object);
+            if let Err(e) = object::ObjectFile::parse(&object.display().to_string(), &data) {
+                println!("Error: {}", e);
+                std::process::exit(1);
+            }
+            let name = object.file_name().unwrap().to_string_lossy().to_string();
+            archive.add(&name, data);
+        }
+        fs::write(&path, archive.as_vec()).expect("failed to save archive.");
+    }
+
+    if list {
+        for name in read_archive(&path).member_names() {
+            println!("{}", name);
+        }
+    }
+
+    if let Some(symbol) = find {
+        match read_archive(&path).find_symbol(&symbol) {
+            Some(member) => println!("{}", member.name),
+            None => {
+                println!("Error: no member of \"{}\" defines '{}'.", path.display(), symbol);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Links relocatable objects and the members of static libraries they need into an executable.
+fn link(inputs: Vec<PathBuf>, output: PathBuf, entry: String, base: Option<u64>, map: Option<PathBuf>, map_format: map::MapFormat) {
+    let mut objects = Vec::new();
+    let mut archives = Vec::new();
+    for path in &inputs {
+        let data = read_input(path);
+        if data.starts_with(archive::ARCHIVE_MAGIC) {
+            archives.push((path.display().to_string(), read_archive(path)));
+            continue;
+        }
+        match object::ObjectFile::parse(&path.display().to_string(), &data) {
+            Ok(object) => objects.push(object),
+            Err(e) => {
+                println!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let elf = match linker::Linker::new(objects, &archives).and_then(|linker| linker.link(&entry, base)) {
+        Ok(elf) => elf,
+        Err(e) => {
+            println!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    elf.save(&output).expect("failed to save elf binary.");
+
+    if let Some(path) = map {
+        fs::write(path, map::SymbolMap::new(&elf).format(map_format)).expect("failed to save map.");
+    }
+
+    fs::set_permissions(output, fs::Permissions::from_mode(0o755)).expect("failed to set permissions.");
 }
 
 fn main() {
     // Handle the arguments
     let args = Args::parse();
+    if let Some(Command::Link { inputs, output, entry, base, map, map_format }) = args.command {
+        link(inputs, output, entry, base, map, map_format);
+        return;
+    }
+    if let Some(Command::Ar { archive, objects, list, find }) = args.command {
+        ar(archive, objects, list, find);
+        return;
+    }
+    let input = args.input.unwrap();
 
     // Load and parse the code.
     //let code: String = fs::read_to_string(args.input).expect("failed to open file."); 
-    let program = match CodeGenerator::generate(&args.input) {
+    let include_dirs = code_gen::include_dirs(&args.include, std::env::var_os("TASM_INCLUDE").as_deref());
+    let mut program = match CodeGenerator::generate(&input, &args.define, &include_dirs) {
         Ok(p) => p,
         Err(e) => {
             println!("Error on line {} in \"{}\": {}", e.line_no, e.file, e.message);
@@ -30,10 +236,78 @@ fn main() {
         }
     };
 
+    // Apply the layout options
+    if let Some(base) = args.base {
+        program.base = Some(base);
+    }
+    if let Some(entry) = args.entry {
+        program.entry = entry;
+    }
+    for (label, location) in program.unused_labels.iter().filter(|(label, _)| *label != program.entry) {
+        println!("Warning on line {} in \"{}\": label '{}' is never used.", location.line_no, location.file, label);
+    }
+    if args.shared {
+        let soname = args.output.file_name().map(|name| name.to_string_lossy().to_string());
+        program.soname = args.soname.clone().or(soname);
+    }
+    if let Some(path) = &args.layout {
+        let layout = match fs::read_to_string(path) {
+            Ok(layout) => layout,
+            Err(e) => {
+                println!("Error: can't read \"{}\" ({}).", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = program.apply_layout(&layout) {
+            println!("Error in layout \"{}\": {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    // Write the relocatable object
+    if args.object {
+        match object::ObjectFile::from_program(program, &input.display().to_string()) {
+            Ok(object) => fs::write(&args.output, object.as_vec()).expect("failed to save object file."),
+            Err(e) => {
+                println!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     // Write the ELF binary
-    let elf = elf::ELF::new_x86(program);
+    let kind = if args.shared {
+        elf::OutputKind::SharedLibrary
+    } else if args.pie {
+        elf::OutputKind::PositionIndependent
+    } else {
+        elf::OutputKind::Executable
+    };
+    let mut elf = match elf::ELF::new_x86(program, kind) {
+        Ok(elf) => elf,
+        Err(e) => {
+            println!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.debug {
+        elf.add_debug_info(&input.display().to_string());
+    }
     elf.save(args.output.clone()).expect("failed to save elf binary.");
 
+    // Write the listing
+    if let Some(path) = args.listing {
+        let listing = listing::Listing::generate(&elf.program, &input.display().to_string())
+            .expect("failed to generate listing.");
+        fs::write(path, listing).expect("failed to save listing.");
+    }
+
+    // Write the symbol map
+    if let Some(path) = args.map {
+        fs::write(path, map::SymbolMap::new(&elf).format(args.map_format)).expect("failed to save map.");
+    }
+
     // Set the permissions
     fs::set_permissions(args.output, fs::Permissions::from_mode(0o755)).expect("failed to set permissions.");
 }
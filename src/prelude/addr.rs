@@ -1,5 +1,15 @@
 use std::ops::{Add, AddAssign};
 
+/// Parses an address written in hex (0x prefix) or decimal.
+pub fn parse_address(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let result = match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse::<u64>(),
+    };
+    result.map_err(|_| format!("invalid address '{}'", s))
+}
+
 #[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Addr {
     pub addr: u64,
@@ -0,0 +1,217 @@
+use super::elf::{ELFSymbolBinding, SHN_UNDEF};
+use super::object::ObjectFile;
+use super::{utils::*, Endianness};
+
+/// The magic string every archive starts with.
+pub const ARCHIVE_MAGIC: &[u8] = b"!<arch>\n";
+
+/// The length of the header before each member.
+const HEADER_LEN: usize = 60;
+
+/// A file stored in an archive, normally a relocatable object.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ArchiveMember {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// A System V (GNU) `ar` archive of relocatable objects, i.e. a static library. The symbol
+/// index maps each global symbol to the member defining it so linkers only pull in the members
+/// they need.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Archive {
+    pub members: Vec<ArchiveMember>,
+    /// Each global symbol defined by the members along with the index of the member.
+    pub symbols: Vec<(String, usize)>,
+}
+
+impl Archive {
+    /// Constructs an empty archive.
+    pub fn new() -> Archive {
+        Archive::default()
+    }
+
+    /// Parses an archive read from 'path'. The symbol index is rebuilt from the members rather
+    /// than trusted.
+    pub fn parse(path: &str, data: &[u8]) -> Result<Archive, String> {
+        let error = |message: String| Err(format!("\"{}\": {}", path, message));
+        if !data.starts_with(ARCHIVE_MAGIC) {
+            return error("not an archive.".to_string());
+        }
+
+        let mut archive = Archive::new();
+        let mut long_names: &[u8] = &[];
+        let mut offset = ARCHIVE_MAGIC.len();
+        while offset < data.len() {
+            if offset + HEADER_LEN > data.len() || &data[offset + 58..offset + HEADER_LEN] != b"`\n" {
+                return error(format!("member header at offset {} is corrupt.", offset));
+            }
+            let field = |start: usize, len: usize| String::from_utf8_lossy(&data[offset + start..offset + start + len]).trim_end().to_string();
+            let name = field(0, 16);
+            let size: usize = match field(48, 10).parse() {
+                Ok(size) if offset + HEADER_LEN + size <= data.len() => size,
+                _ => return error(format!("member size at offset {} is invalid.", offset)),
+            };
+            let contents = &data[offset + HEADER_LEN..offset + HEADER_LEN + size];
+            offset += HEADER_LEN + size.next_multiple_of(2);
+
+            // '/' is the symbol index, '//' holds names too long for the header, which are
+            // referred to as '/<offset>'. Short names end with '/'.
+            let name = match name.as_str() {
+                "/" | "/SYM64/" => continue,
+                "//" => {
+                    long_names = contents;
+                    continue;
+                }
+                _ if name.starts_with('/') => {
+                    let start: usize = match name[1..].parse() {
+                        Ok(start) if start < long_names.len() => start,
+                        _ => return error(format!("member name '{}' is invalid.", name)),
+                    };
+                    let end = long_names[start..].iter().position(|b| *b == b'\n').map(|len| start + len).unwrap_or(long_names.len());
+                    String::from_utf8_lossy(&long_names[start..end]).trim_end_matches('/').to_string()
+                }
+                _ => name.trim_end_matches('/').to_string(),
+            };
+
+            archive.add(&name, contents.to_vec());
+        }
+
+        Ok(archive)
+    }
+
+    /// Adds a member to the archive, replacing any member of the same name, and indexes the
+    /// global symbols it defines if it's a relocatable object.
+    pub fn add(&mut self, name: &str, data: Vec<u8>) {
+        let member = ArchiveMember { name: name.to_string(), data };
+        let idx = match self.members.iter().position(|m| m.name == name) {
+            Some(idx) => {
+                self.members[idx] = member;
+                self.symbols.retain(|(_, member)| *member != idx);
+                idx
+            }
+            None => {
+                self.members.push(member);
+                self.members.len() - 1
+            }
+        };
+
+        if let Ok(object) = ObjectFile::parse(name, &self.members[idx].data) {
+            for (symbol, _) in object.symbols.iter().filter(|(_, s)| s.binding != ELFSymbolBinding::Local && s.st_shndx != SHN_UNDEF) {
+                self.symbols.push((symbol.clone(), idx));
+            }
+        }
+    }
+
+    /// Lists the names of the members in order.
+    pub fn member_names(&self) -> Vec<&str> {
+        self.members.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    /// Finds the member defining the global symbol named 'symbol'.
+    pub fn find_symbol(&self, symbol: &str) -> Option<&ArchiveMember> {
+        self.symbols.iter().find(|(name, _)| name == symbol).map(|(_, idx)| &self.members[*idx])
+    }
+
+    /// Converts the archive into a vector of bytes: the magic string, the symbol index, the long
+    /// name table if any names don't fit in a header, then each member. Dates, owners and modes
+    /// are fixed so archives are reproducible.
+    pub fn as_vec(&self) -> Vec<u8> {
+        // Names longer than 15 characters (the header holds 16 including the trailing '/') go in
+        // the long name table.
+        let mut long_names = Vec::new();
+        let names: Vec<String> = self.members.iter()
+            .map(|member| {
+                if member.name.len() < 16 {
+                    format!("{}/", member.name)
+                } else {
+                    let name = format!("/{}", long_names.len());
+                    long_names.extend_from_slice(member.name.as_bytes());
+                    long_names.extend_from_slice(b"/\n");
+                    name
+                }
+            })
+            .collect();
+
+        // The index is the symbol count, the offset of each symbol's member header then the
+        // symbols' names, with numbers in big endian.
+        let mut index_len = 4 + 4 * self.symbols.len() + self.symbols.iter().map(|(name, _)| name.len() + 1).sum::<usize>();
+        index_len = index_len.next_multiple_of(2);
+        let mut offset = ARCHIVE_MAGIC.len() + HEADER_LEN + index_len;
+        if !long_names.is_empty() {
+            offset += HEADER_LEN + long_names.len().next_multiple_of(2);
+        }
+        let mut offsets = Vec::new();
+        for member in &self.members {
+            offsets.push(offset);
+            offset += HEADER_LEN + member.data.len().next_multiple_of(2);
+        }
+
+        let mut index = dump_dword(self.symbols.len() as u32, Endianness::Big).to_vec();
+        for (_, member) in &self.symbols {
+            index.extend_from_slice(&dump_dword(offsets[*member] as u32, Endianness::Big));
+        }
+        for (name, _) in &self.symbols {
+            index.extend_from_slice(name.as_bytes());
+            index.push(0);
+        }
+
+        let mut dump = ARCHIVE_MAGIC.to_vec();
+        push_member(&mut dump, "/", &index);
+        if !long_names.is_empty() {
+            push_member(&mut dump, "//", &long_names);
+        }
+        for (name, member) in names.iter().zip(&self.members) {
+            push_member(&mut dump, name, &member.data);
+        }
+
+        dump
+    }
+}
+
+/// Pushes a member's header and contents, padded to an even length.
+fn push_member(dump: &mut Vec<u8>, name: &str, data: &[u8]) {
+    let mode = if name.starts_with('/') { "0" } else { "644" };
+    let header = format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, mode, data.len());
+    dump.extend_from_slice(header.as_bytes());
+    dump.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        dump.push(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::{Instruction, Program, SourceLocation};
+
+    /// Assembles an object defining the global 'name'.
+    fn object(name: &str) -> Vec<u8> {
+        let mut program = Program::new();
+        program.new_block(name).push(Instruction::Return);
+        program.new_block("local").push(Instruction::Return);
+        program.declare(name, SourceLocation { file: "test.s".to_string(), line_no: 1 });
+        ObjectFile::from_program(program, "test.o").unwrap().as_vec()
+    }
+
+    #[test]
+    fn archive() {
+        let mut archive = Archive::new();
+        archive.add("print.o", object("print"));
+        archive.add("a_long_member_name.o", object("exit"));
+        archive.add("readme.txt", b"not an object".to_vec());
+        assert_eq!(archive.member_names(), vec!["print.o", "a_long_member_name.o", "readme.txt"]);
+        assert_eq!(archive.find_symbol("exit").unwrap().name, "a_long_member_name.o");
+        assert!(archive.find_symbol("local").is_none());
+
+        let data = archive.as_vec();
+        assert!(data.starts_with(b"!<arch>\n/               0           0     0     0       "));
+        assert_eq!(Archive::parse("test.a", &data).unwrap(), archive);
+
+        // Adding a member of the same name replaces it.
+        archive.add("print.o", object("puts"));
+        assert_eq!(archive.member_names().len(), 3);
+        assert!(archive.find_symbol("print").is_none());
+        assert_eq!(archive.find_symbol("puts").unwrap().name, "print.o");
+    }
+}
@@ -4,45 +4,57 @@ use super::{Register, JumpCondition};
 #[derive(Debug)]
 pub enum Node {
     Program(Vec<Node>),
+    Statement(usize, Box<Node>),
     Label(String),
     Entry(String),
-    DS(u32),
-    Db(Vec<u8>),
-    DW(Vec<u16>),
-    DL(Vec<u32>),
-    Int(u8),
+    Org(u32),
+    Segment(String, Option<u32>),
+    Extern(String),
+    Needed(String),
+    Export(String),
+    Global(String),
+    Weak(String),
+    Hidden(String),
+    MacroDef { name: String, min_args: usize, max_args: Option<usize>, defaults: Vec<String>, body: Vec<String> },
+    MacroCall(String, Vec<String>),
+    If { branches: Vec<(Node, Vec<Node>)>, otherwise: Vec<Node> },
+    IfDef(String),
+    IfNDef(String),
+    Invalid(String),
+    Times(Box<Node>, Box<Node>),
+    Align(Box<Node>, Option<Box<Node>>),
+    Rep { count: Box<Node>, counter: Option<String>, body: Vec<Node> },
+    DS(Box<Node>),
+    Db(Vec<Node>),
+    DW(Vec<Node>),
+    DL(Vec<Node>),
+    DQ(Vec<Node>),
+    DT(Vec<Node>),
+    Int(Box<Node>),
     Inc(Register),
     Dec(Register),
-    Jump { condition: JumpCondition, label: String },
-    JumpImm { condition: JumpCondition, addr: u32 },
+    Jump { condition: JumpCondition, addr: Box<Node> },
     Mov(Register, Register),
-    MovImm(Register, u32),
-    MovImmPointer(Register, String),
-    MovMemory(u32, Register),
-    MovMemoryPointer(String, Register),
+    MovImm(Register, Box<Node>),
+    MovMemory(Box<Node>, Register),
     MovMemoryRegister(Register, Register),
-    MovFromMemory(Register, u32),
-    MovFromMemoryPointer(Register, String),
+    MovFromMemory(Register, Box<Node>),
     MovFromMemoryRegister(Register, Register),
     Add(Register, Register),
-    AddImm(Register, u32),
-    AddImmPointer(Register, String),
+    AddImm(Register, Box<Node>),
     Sub(Register, Register),
-    SubImm(Register, u32),
-    SubImmPointer(Register, String),
+    SubImm(Register, Box<Node>),
     Mul(Register),
     Div(Register),
     And(Register, Register),
     Or(Register, Register),
     XOr(Register, Register),
     CMP(Register, Register),
-    CMPImm(Register, u32),
-    CMPImmPointer(Register, String),
+    CMPImm(Register, Box<Node>),
     BSWAP(Register),
     Push(Register),
     Pop(Register),
-    Call(u32),
-    CallPointer(String),
+    Call(Box<Node>),
     CallRegister(Register),
     Return,
     Not(Register),
@@ -50,15 +62,20 @@ pub enum Node {
     SHL(Register),
     SHR(Register),
     Register(Register),
-    Integer(u32),
+    Integer(i64),
+    Float(f64),
     Pointer(String),
+    String(Vec<u8>),
     Newline,
     EQU(String, Box<Node>),
     Expr(Vec<Node>),    
     ParenExpr(Box<Node>),
     Operator(Token),
+    Unary(Token, Box<Node>),
     Dollar,
+    DoubleDollar,
     Include(String),
+    IncBin(String, Option<Box<Node>>, Option<Box<Node>>),
 }
 
 impl Node {
@@ -73,6 +90,10 @@ impl Node {
                 println!("Program");
                 for n in stmts { n.print_impl(depth + 1); }
             }
+            Node::Statement(line_no, node) => {
+                println!("Statement(line {})", line_no);
+                node.print_impl(depth + 1);
+            }
             Node::Label(ident) => println!("Label({})", ident),
             Node::EQU(ident, node) => {
                 println!("EQU({})", ident);
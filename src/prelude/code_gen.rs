@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use super::*;
+use super::macros::{Macro, MAX_MACRO_DEPTH, LOCAL_LABEL_PREFIX};
 
 #[derive(Debug, Clone)]
 enum Expr {
@@ -9,9 +10,14 @@ enum Expr {
         lhs: Box<Expr>,
         rhs: Box<Expr>,
     },
+    UnaryOp {
+        op: Token,
+        operand: Box<Expr>,
+    },
     PC,
+    SegmentStart,
     Pointer(String),
-    Number(u32),
+    Number(i64),
 }
 
 impl Expr {
@@ -27,57 +33,320 @@ impl Expr {
                 lhs.print_impl(depth + 1);
                 rhs.print_impl(depth + 1);
             }
+            Expr::UnaryOp { op, operand } => {
+                println!("UnaryOp({:?})", op);
+                operand.print_impl(depth + 1);
+            }
             _ => println!("{:?}", self),
         }
     }
 }
 
+/// The value of an expression. Labels count as their offset into their segment, and how many
+/// times each is added is kept so an address plus a constant can be resolved once the program
+/// is laid out.
+#[derive(Debug, Default)]
+struct Term {
+    value: i64,
+    /// The labels added (positive) or subtracted (negative) and how many times.
+    labels: Vec<(String, i64)>,
+    /// Whether a label was used other than by adding or subtracting it.
+    scaled: bool,
+    /// Symbols that weren't found at all.
+    unknown: Vec<String>,
+}
+
+impl Term {
+    /// Combines the labels and unknown symbols of 'a' and 'b', with those of 'b' counted
+    /// 'sign' times.
+    fn combine(value: i64, mut a: Term, b: Term, sign: i64) -> Term {
+        for (label, count) in b.labels {
+            match a.labels.iter_mut().find(|(l, _)| *l == label) {
+                Some((_, c)) => *c += sign * count,
+                None => a.labels.push((label, sign * count)),
+            }
+        }
+        a.labels.retain(|(_, count)| *count != 0);
+        a.unknown.extend(b.unknown);
+        Term { value, labels: a.labels, scaled: a.scaled || b.scaled, unknown: a.unknown }
+    }
+}
+
+/// Gets the precedence of a binary operator, higher binds tighter as in C.
 fn precedence(token: &Token) -> usize {
     match token {
-        Token::Plus => 0,
-        Token::Minus => 0,
-        Token::Multiply => 1,
-        Token::Divide => 1,
+        Token::LogicalOr => 0,
+        Token::LogicalAnd => 1,
+        Token::BitOr => 2,
+        Token::BitXor => 3,
+        Token::BitAnd => 4,
+        Token::Equal | Token::NotEqual => 5,
+        Token::Less | Token::LessEqual | Token::Greater | Token::GreaterEqual => 6,
+        Token::LeftShift | Token::RightShift => 7,
+        Token::Plus | Token::Minus => 8,
+        Token::Multiply | Token::Divide | Token::SignedDivide | Token::Modulo | Token::SignedModulo => 9,
         _ => panic!("not an operator!"),
     }
 }
 
+/// How many times a program is assembled waiting for the values of its symbols to settle.
+const MAX_PASSES: usize = 16;
+
 pub struct CodeGenError {
     pub file: String,
     pub line_no: usize,
     pub message: String,
 }
 
+/// Parses a command line define, `NAME=VALUE` or `NAME` which defines it as 1.
+pub fn parse_define(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s.split_once('=').unwrap_or((s, "1"));
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric());
+    if !valid {
+        return Err(format!("invalid symbol name '{}'", name));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Appends the `:` separated directories of a TASM_INCLUDE value to the `-I` directories, so
+/// they're searched last.
+pub fn include_dirs(dirs: &[PathBuf], tasm_include: Option<&std::ffi::OsStr>) -> Vec<PathBuf> {
+    let mut dirs = dirs.to_vec();
+    if let Some(paths) = tasm_include {
+        dirs.extend(std::env::split_paths(paths).filter(|dir| !dir.as_os_str().is_empty()));
+    }
+    dirs
+}
+
 pub struct CodeGenerator {
     program: Program,
     cwd: PathBuf,
+    include_dirs: Vec<PathBuf>,
+    file: String,
+    line_no: usize,
     entry_point: String,
     block_addrs: HashMap<String, u32>,
-    variables: HashMap<String, u32>,
+    variables: HashMap<String, i64>,
     current_block: usize,
+    macros: HashMap<String, Macro>,
+    /// The macros being expanded, innermost last, with the body line being processed.
+    macro_stack: Vec<(Macro, usize)>,
+    macro_expansions: usize,
+    /// The last label not starting with '.', which '.<name>' labels belong to.
+    scope: String,
+    /// How many times each numeric label has been defined so far.
+    numeric_labels: HashMap<String, usize>,
+    /// The labels and constants found by the previous pass, used by expressions referring to
+    /// symbols defined later on.
+    forward_labels: HashMap<String, u32>,
+    forward_variables: HashMap<String, i64>,
+    /// Symbols used by expressions, to find the labels that never are.
+    used: HashSet<String>,
+    /// Symbols used by expressions that weren't found at all, with where they were used.
+    unresolved: Vec<(String, SourceLocation, Option<MacroLocation>)>,
+    /// The symbols each constant's expression refers to, with where it's defined.
+    dependencies: HashMap<String, (Vec<String>, SourceLocation)>,
 }
 
 impl CodeGenerator {
-    pub fn generate(path: &PathBuf) -> Result<Program, CodeGenError> {
+    /// Assembles the file at 'path'. Each define is an `EQU` constant set before the file is
+    /// processed, and includes not found beside the including file are searched for in
+    /// 'include_dirs' in order.
+    ///
+    /// Expressions can refer to symbols defined later on, so the program is assembled again
+    /// with the values found by the previous pass until they stop changing.
+    pub fn generate(path: &PathBuf, defines: &[(String, String)], include_dirs: &[PathBuf]) -> Result<Program, CodeGenError> {
+        let mut forward = (HashMap::new(), HashMap::new());
+        let mut previous = forward.clone();
+        let mut last_pass = None;
+        for _ in 0..MAX_PASSES {
+            let mut gen = CodeGenerator::new(path, include_dirs, forward.clone());
+            let result = gen.pass(path, defines);
+            gen.check_circular()?;
+            let found = (gen.block_addrs.clone(), gen.variables.clone());
+
+            match result {
+                // The error may be down to a symbol defined further on, in which case the next
+                // pass knows more.
+                Err(e) if !gen.unresolved.is_empty() => {
+                    let mut merged = forward.clone();
+                    merged.0.extend(found.0);
+                    merged.1.extend(found.1);
+                    if merged == forward {
+                        return Err(e);
+                    }
+                    forward = merged;
+                }
+                Err(e) => return Err(e),
+                Ok(()) if found != forward => previous = std::mem::replace(&mut forward, found),
+                Ok(()) => {
+                    if let Some((name, location, macro_location)) = gen.unresolved.first() {
+                        let note = macro_location.as_ref().map(MacroLocation::note).unwrap_or_default();
+                        return Err(CodeGenError {
+                            file: location.file.clone(),
+                            line_no: location.line_no,
+                            message: format!("undefined symbol '{}' in expression.{}", source_name(name), note),
+                        });
+                    }
+
+                    gen.check_symbols()?;
+                    gen.program.entry = gen.entry_point.clone();
+                    gen.program.unused_labels = gen.unused_labels();
+                    return Ok(gen.program);
+                }
+            }
+            last_pass = Some(gen);
+        }
+
+        // Report a constant or label whose value kept changing between the last two passes.
+        let gen = last_pass.unwrap();
+        let blocks = gen.program.blocks();
+        let unsettled = gen.program.constants.iter()
+            .find(|c| previous.1.get(&c.name).map(|v| *v as u32) != Some(c.value))
+            .map(|c| (c.name.as_str(), Some(&c.location)))
+            .or_else(|| blocks.iter()
+                .find(|(block, _)| gen.block_addrs.get(block.label()) != previous.0.get(block.label()))
+                .map(|(block, _)| (block.label(), block.location())));
+        let (name, location) = match unsettled {
+            Some((name, Some(location))) => (name, location.clone()),
+            _ => ("", SourceLocation { file: path.display().to_string(), line_no: 0 }),
+        };
+        Err(CodeGenError {
+            file: location.file,
+            line_no: location.line_no,
+            message: format!("the value of '{}' didn't settle after {} passes, it may depend on itself through the size of the code.", name, MAX_PASSES),
+        })
+    }
+
+    fn new(path: &Path, include_dirs: &[PathBuf], forward: (HashMap<String, u32>, HashMap<String, i64>)) -> CodeGenerator {
         let cwd = Path::new(path).parent().unwrap().to_path_buf();
 
-        let mut gen = CodeGenerator { 
+        CodeGenerator { 
             program: Program::new(), 
             cwd,
+            include_dirs: include_dirs.to_vec(),
+            file: path.display().to_string(),
+            line_no: 0,
             entry_point: "__entry_point__".to_string(),
             block_addrs: HashMap::new(),
             variables: HashMap::new(),
             current_block: 0, 
+            macros: HashMap::new(),
+            macro_stack: Vec::new(),
+            macro_expansions: 0,
+            scope: String::new(),
+            numeric_labels: HashMap::new(),
+            forward_labels: forward.0,
+            forward_variables: forward.1,
+            used: HashSet::new(),
+            unresolved: Vec::new(),
+            dependencies: HashMap::new(),
+        }
+    }
+
+    /// Assembles the defines and the file at 'path' once.
+    fn pass(&mut self, path: &Path, defines: &[(String, String)]) -> Result<(), CodeGenError> {
+        self.program.new_block("__entry_point__");
+        self.block_addrs.insert("__entry_point__".to_string(), 0);
+
+        for (name, value) in defines {
+            self.file = format!("-D {}={}", name, value);
+            self.line_no = 1;
+            match Parser::parse(&format!("EQU {} {}\n", name, value)) {
+                Ok(root) => self.process(&root)?,
+                Err(e) => return Err(CodeGenError { file: self.file.clone(), line_no: 1, message: e.message }),
+            }
+        }
+        self.file = path.display().to_string();
+
+        let root = self.parse_file(path)?;
+        self.process(&root)
+    }
+
+    /// Checks no constant is defined in terms of itself, e.g. 'EQU a b' and 'EQU b a'.
+    fn check_circular(&self) -> Result<(), CodeGenError> {
+        let mut constants: Vec<(&String, &SourceLocation)> = self.dependencies.iter().map(|(name, (_, location))| (name, location)).collect();
+        constants.sort_by_key(|(_, location)| (&location.file, location.line_no));
+        for (name, location) in constants {
+            // Search the constants it depends on for a path back to it.
+            let mut stack = vec![vec![name.as_str()]];
+            let mut visited = Vec::new();
+            while let Some(path) = stack.pop() {
+                let Some((dependencies, _)) = self.dependencies.get(*path.last().unwrap()) else { continue };
+                for dependency in dependencies {
+                    let mut path = path.clone();
+                    path.push(dependency);
+                    if dependency == name {
+                        return Err(CodeGenError {
+                            file: location.file.clone(),
+                            line_no: location.line_no,
+                            message: format!("circular definition of '{}' ({}).", name, path.join(" -> ")),
+                        });
+                    }
+                    if !visited.contains(&dependency) {
+                        visited.push(dependency);
+                        stack.push(path);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks every label referenced by an instruction is defined or declared EXTERN, so typos
+    /// aren't silently assembled as address 0, and every symbol given a binding exists.
+    fn check_symbols(&self) -> Result<(), CodeGenError> {
+        let error = |location: &SourceLocation, message: String| CodeGenError {
+            file: location.file.clone(),
+            line_no: location.line_no,
+            message,
         };
 
-        gen.program.new_block("__entry_point__");
-        gen.block_addrs.insert("__entry_point__".to_string(), 0);
+        for external in &self.program.externs {
+            if self.program.segment_of(&external.name).is_some() {
+                return Err(error(&external.location, format!("'{}' is declared EXTERN but defined as a label.", external.name)));
+            }
+        }
+
+        for (label, location, macro_location) in self.program.references() {
+            if self.program.get_addr(label).is_none() {
+                let mut message = format!("undefined symbol '{}', declare it with EXTERN if it's defined elsewhere.", source_name(label));
+                message += &macro_location.map(MacroLocation::note).unwrap_or_default();
+                return Err(error(location.unwrap_or(&self.location()), message));
+            }
+        }
+
+        for symbol in &self.program.symbols {
+            if self.program.get_addr(&symbol.name).is_none() {
+                return Err(error(&symbol.location, format!("undefined symbol '{}'.", symbol.name)));
+            }
+        }
 
-        let root = gen.parse_file(&path)?;
-        gen.process(&root)?;
+        Ok(())
+    }
 
-        gen.program.set_entrypoint(&gen.entry_point);
-        Ok(gen.program)
+    /// Finds the labels no expression uses, other than the entry point, symbols given a binding
+    /// and the internal numeric and macro labels which are often there just in case.
+    fn unused_labels(&self) -> Vec<(String, SourceLocation)> {
+        self.program.blocks().into_iter()
+            .filter_map(|(block, _)| Some((block.label(), block.location()?)))
+            .filter(|(label, _)| !self.used.contains(*label) && *label != self.entry_point)
+            .filter(|(label, _)| !is_internal_label(label))
+            .filter(|(label, _)| !self.program.symbols.iter().any(|symbol| symbol.name == *label))
+            .map(|(label, location)| (label.to_string(), location.clone()))
+            .collect()
+    }
+
+    /// Finds an included file, first relative to the including file then in each include
+    /// directory.
+    fn find_include(&self, filename: &str) -> Option<PathBuf> {
+        std::iter::once(&self.cwd)
+            .chain(&self.include_dirs)
+            .map(|dir| dir.join(filename))
+            .find(|path| path.is_file())
     }
 
     fn parse_file(&self, path: &Path) -> Result<Node, CodeGenError> {
@@ -93,21 +362,23 @@ impl CodeGenerator {
         }
     }
 
-    fn push_instr(&mut self, instr: Instruction) {
-        self.program.get_block_mut(self.current_block).unwrap().push(instr);
+    fn location(&self) -> SourceLocation {
+        SourceLocation { file: self.file.clone(), line_no: self.line_no }
     }
 
-    fn lookup_pointer(&self, ident: &str) -> Value {
-        match self.variables.get(ident) {
-            Some(v) => Value::UInt(*v),
-            None => Value::Pointer(ident.to_string())
-        }
+    /// Gets the line of the innermost macro being expanded.
+    fn macro_location(&self) -> Option<MacroLocation> {
+        let (mac, body_line) = self.macro_stack.last()?;
+        Some(MacroLocation { name: mac.name.clone(), location: mac.body_location(*body_line) })
     }
 
-    fn lookup_rel_pointer(&self, ident: &str) -> Value {
-        match self.variables.get(ident) {
-            Some(v) => Value::UInt(*v),
-            None => Value::RelPointer(ident.to_string())
+    fn push_instr(&mut self, instr: Instruction) {
+        let location = self.location();
+        let macro_location = self.macro_location();
+        let block = self.program.get_block_mut(self.current_block).unwrap();
+        match macro_location {
+            Some(macro_location) => block.push_expanded(instr, location, macro_location),
+            None => block.push_at(instr, location),
         }
     }
 
@@ -115,97 +386,243 @@ impl CodeGenerator {
         match node {
             Node::Include(filename) => {
                 let old_cwd = self.cwd.clone();
-                self.cwd.push(filename);
+                let old_file = self.file.clone();
+                let old_line_no = self.line_no;
+                let Some(path) = self.find_include(filename) else {
+                    let message = format!("can't find include file \"{}\", add its directory with -I or TASM_INCLUDE.", filename);
+                    return Err(CodeGenError { file: self.file.clone(), line_no: self.line_no, message });
+                };
+                let old_macro_stack = std::mem::take(&mut self.macro_stack);
+                self.cwd = path;
 
                 let root = self.parse_file(&self.cwd)?;
+                self.program.includes.push((self.location(), self.cwd.display().to_string()));
 
                 // Set the new CWD
+                self.file = self.cwd.display().to_string();
                 self.cwd = Path::new(&self.cwd).parent().unwrap().to_path_buf();
                 self.process(&root)?;
 
                 // Revert back to the old cwd
                 self.cwd = old_cwd;
+                self.file = old_file;
+                self.line_no = old_line_no;
+                self.macro_stack = old_macro_stack;
+            }
+            Node::IncBin(filename, offset, length) => {
+                let Some(path) = self.find_include(filename) else {
+                    let message = format!("can't find INCBIN file \"{}\", add its directory with -I or TASM_INCLUDE.", filename);
+                    return Err(CodeGenError { file: self.file.clone(), line_no: self.line_no, message });
+                };
+                let bytes = std::fs::read(&path)
+                    .map_err(|e| self.error(format!("can't read INCBIN file \"{}\" ({}).", filename, e)))?;
+
+                let offset = match offset {
+                    Some(offset) => self.evaluate(offset)?,
+                    None => 0,
+                };
+                if !(0..=bytes.len() as i64).contains(&offset) {
+                    let message = format!("'INCBIN' offset {} is outside \"{}\", which is {} bytes.", offset, filename, bytes.len());
+                    return Err(self.error(message));
+                }
+                // A length running past the end of the file takes the rest of it.
+                let end = match length {
+                    Some(length) => match self.evaluate(length)? {
+                        length if length < 0 => return Err(self.error(format!("'INCBIN' length {} is negative.", length))),
+                        length => (offset as usize).saturating_add(length as usize).min(bytes.len()),
+                    },
+                    None => bytes.len(),
+                };
+                self.push_instr(Instruction::RawData(bytes[offset as usize..end].to_vec()));
             }
             Node::Program(stmts) => {
                 for stmt in stmts {
                     self.process(stmt)?;
                 }
             }
+            Node::Statement(line_no, stmt) => {
+                // Statements expanded from a macro keep the location of the call.
+                match self.macro_stack.last_mut() {
+                    Some((_, body_line)) => *body_line = *line_no,
+                    None => self.line_no = *line_no,
+                }
+                self.process(stmt)?;
+            }
+            Node::MacroDef { name, min_args, max_args, defaults, body } => {
+                let location = self.location();
+                self.macros.insert(name.clone(), Macro {
+                    name: name.clone(),
+                    min_args: *min_args,
+                    max_args: *max_args,
+                    defaults: defaults.clone(),
+                    body: body.clone(),
+                    location,
+                });
+            }
+            Node::MacroCall(name, args) => self.expand_macro(name, args)?,
+            Node::If { branches, otherwise } => {
+                // Only the first branch whose condition holds is assembled.
+                let mut block = otherwise;
+                for (condition, branch) in branches {
+                    if self.condition(condition)? {
+                        block = branch;
+                        break;
+                    }
+                }
+                for stmt in block {
+                    self.process(stmt)?;
+                }
+            }
+            Node::Times(count, stmt) => {
+                for _ in 0..self.repeat_count(count, "TIMES")? {
+                    self.process(stmt)?;
+                }
+            }
+            Node::Rep { count, counter, body } => {
+                let count = self.repeat_count(count, "%rep")?;
+                let old_value = counter.as_ref().and_then(|name| self.variables.get(name).copied());
+                for idx in 0..count {
+                    if let Some(name) = counter {
+                        self.variables.insert(name.clone(), idx as i64);
+                    }
+                    for stmt in body {
+                        self.process(stmt)?;
+                    }
+                }
+
+                // The counter is only defined inside the block.
+                if let Some(name) = counter {
+                    match old_value {
+                        Some(value) => self.variables.insert(name.clone(), value),
+                        None => self.variables.remove(name),
+                    };
+                }
+            }
+            Node::Invalid(message) => return Err(CodeGenError { file: self.file.clone(), line_no: self.line_no, message: message.clone() }),
             Node::Label(label) => {
-                self.program.new_block(&label);
-                self.block_addrs.insert(label.clone(), self.program.len() as u32);
+                let label = &self.define_label(label);
+                if self.block_addrs.contains_key(label) {
+                    let blocks = self.program.blocks();
+                    let message = match blocks.iter().find_map(|(block, _)| block.location().filter(|_| block.label() == label)) {
+                        Some(first) => format!("duplicate label '{}', first defined on line {} in \"{}\".", label, first.line_no, first.file),
+                        None => format!("duplicate label '{}'.", label),
+                    };
+                    return Err(self.error(message));
+                }
+                let location = self.location();
+                self.program.new_block_at(&label, location);
+                self.block_addrs.insert(label.clone(), self.program.current_len() as u32);
                 self.current_block += 1;
             } 
-            Node::Entry(label) => self.entry_point = label.clone(),
-            Node::DS(len) => self.push_instr(Instruction::RawData(vec![0; *len as usize])),
-            Node::Db(data) => self.push_instr(Instruction::RawData(data.to_vec())),
-            Node::DW(data) => {
-                let mut new_data = Vec::new();
-                for word in data {
-                    new_data.extend_from_slice(&utils::dump_word(*word, Endianness::Little));
+            Node::Entry(label) => self.entry_point = self.label_name(label),
+            Node::Org(addr) => self.program.base = Some(*addr as u64),
+            Node::Segment(name, align) => {
+                // Start an unlabeled block so the following instructions land in the segment.
+                self.program.switch_segment(name);
+                self.program.new_block("");
+                self.current_block += 1;
+                if let Some(align) = align {
+                    let align = self.check_align(*align as i64, "SEGMENT")?;
+                    self.program.align_segment(align);
+                }
+            }
+            Node::Align(align, fill) => {
+                let align = self.evaluate(align)?;
+                let align = self.check_align(align, "ALIGN")?;
+                let fill = match fill {
+                    Some(fill) => match self.evaluate(fill)? {
+                        fill @ 0..=0xFF => fill as u8,
+                        fill => {
+                            let message = format!("'ALIGN' fill 0x{:X} doesn't fit in a byte.", fill);
+                            return Err(CodeGenError { file: self.file.clone(), line_no: self.line_no, message });
+                        }
+                    },
+                    // Code is padded with NOPs so execution can run through the padding.
+                    None if self.program.current_segment().is_code() => 0x90,
+                    None => 0,
+                };
+
+                // The segment starts aligned, so padding its offset aligns the address.
+                self.program.align_segment(align);
+                let padding = (align as usize - self.program.current_len() % align as usize) % align as usize;
+                if padding > 0 {
+                    self.push_instr(Instruction::RawData(vec![fill; padding]));
+                }
+            }
+            Node::Extern(name) => {
+                if !self.program.externs.iter().any(|e| &e.name == name) {
+                    self.program.externs.push(Extern { name: name.clone(), location: self.location(), addr: Addr::default() });
                 }
-                self.push_instr(Instruction::RawData(new_data));
             }
-            Node::DL(data) => {
-                let mut new_data = Vec::new();
-                for word in data {
-                    new_data.extend_from_slice(&utils::dump_dword(*word, Endianness::Little));
+            Node::Needed(library) => {
+                if !self.program.needed.contains(library) {
+                    self.program.needed.push(library.clone());
                 }
-                self.push_instr(Instruction::RawData(new_data));
             }
-            Node::Int(x) => self.push_instr(Instruction::Int(*x)),
+            Node::Export(name) | Node::Global(name) => self.program.declare(name, self.location()).binding = SymbolBinding::Global,
+            Node::Weak(name) => self.program.declare(name, self.location()).binding = SymbolBinding::Weak,
+            Node::Hidden(name) => self.program.declare(name, self.location()).visibility = SymbolVisibility::Hidden,
+            Node::DS(len) => {
+                let len = self.repeat_count(len, "DS")?;
+                self.push_instr(Instruction::RawData(vec![0; len as usize]));
+            }
+            Node::Db(data) => self.push_data(data, 8)?,
+            Node::DW(data) => self.push_data(data, 16)?,
+            Node::DL(data) => self.push_data(data, 32)?,
+            Node::DQ(data) => self.push_data(data, 64)?,
+            Node::DT(data) => self.push_data(data, 80)?,
+            Node::Int(x) => match self.operand(x, 8, false)? {
+                Value::UByte(x) => self.push_instr(Instruction::Int(x)),
+                _ => panic!("unreachable code"),
+            }
             Node::Inc(reg) => self.push_instr(Instruction::Inc(*reg)),
             Node::Dec(reg) => self.push_instr(Instruction::Dec(*reg)),
-            Node::Jump { condition, label } => self.push_instr(Instruction::Jump { condition: *condition, addr: self.lookup_rel_pointer(label) }),
-            Node::JumpImm { condition, addr } => self.push_instr(Instruction::Jump { condition: *condition, addr: Value::UInt(*addr) }),
+            Node::Jump { condition, addr } => {
+                let addr = self.operand(addr, 32, true)?;
+                self.push_instr(Instruction::Jump { condition: *condition, addr });
+            }
             Node::Mov(reg1, reg2) => self.push_instr(Instruction::Mov(*reg1, *reg2)),
-            Node::MovImm(reg, x) => match reg.bits() {
-                8 => self.push_instr(Instruction::MovImmediate { register: *reg, value: Value::UByte(*x as u8) }),
-                16 => self.push_instr(Instruction::MovImmediate { register: *reg, value: Value::UShort(*x as u16) }),
-                32 => self.push_instr(Instruction::MovImmediate { register: *reg, value: Value::UInt(*x) }),
-                _ => panic!("unreachable code"),
+            Node::MovImm(reg, x) => {
+                let value = self.operand(x, reg.bits(), false)?;
+                self.push_instr(Instruction::MovImmediate { register: *reg, value });
+            }
+            Node::MovMemory(addr, reg) => {
+                let addr = self.operand(addr, 32, false)?;
+                self.push_instr(Instruction::MovMemory { addr, register: *reg });
             }
-            Node::MovImmPointer(reg, label) => self.push_instr(Instruction::MovImmediate { register: *reg, value: self.lookup_pointer(label) }),
-            Node::MovMemory(addr, reg) => self.push_instr(Instruction::MovMemory { addr: Value::UInt(*addr), register: *reg }),
-            Node::MovMemoryPointer(label, reg) => self.push_instr(Instruction::MovMemory { addr: self.lookup_pointer(label), register: *reg }),
             Node::MovMemoryRegister(dest, reg) => self.push_instr(Instruction::MovMemoryReg { dest: *dest, src: *reg  }),
-            Node::MovFromMemory(register, addr) => self.push_instr(Instruction::MovFromMemory(*register, Value::UInt(*addr))),
-            Node::MovFromMemoryPointer(register, label) => self.push_instr(Instruction::MovFromMemory(*register, self.lookup_pointer(label))),
+            Node::MovFromMemory(register, addr) => {
+                let addr = self.operand(addr, 32, false)?;
+                self.push_instr(Instruction::MovFromMemory(*register, addr));
+            }
             Node::MovFromMemoryRegister(dest, src) => self.push_instr(Instruction::MovFromMemoryReg(*dest, *src)),
             Node::Add(dest, src) => self.push_instr(Instruction::Add(*dest, *src)),
-            Node::AddImm(reg, x) => match reg.bits() {
-                8 => self.push_instr(Instruction::AddImmediate { register: *reg, value: Value::UByte(*x as u8) }),
-                16 => self.push_instr(Instruction::AddImmediate { register: *reg, value: Value::UShort(*x as u16) }),
-                32 => self.push_instr(Instruction::AddImmediate { register: *reg, value: Value::UInt(*x) }),
-                _ => panic!("unreachable code"),
+            Node::AddImm(reg, x) => {
+                let value = self.operand(x, reg.bits(), false)?;
+                self.push_instr(Instruction::AddImmediate { register: *reg, value });
             }
-            Node::AddImmPointer(reg, label) => self.push_instr(Instruction::AddImmediate { register: *reg, value: self.lookup_pointer(label) }),
             Node::Sub(dest, src) => self.push_instr(Instruction::Sub(*dest, *src)),
-            Node::SubImm(reg, x) => match reg.bits() {
-                8 => self.push_instr(Instruction::SubImmediate { register: *reg, value: Value::UByte(*x as u8) }),
-                16 => self.push_instr(Instruction::SubImmediate { register: *reg, value: Value::UShort(*x as u16) }),
-                32 => self.push_instr(Instruction::SubImmediate { register: *reg, value: Value::UInt(*x) }),
-                _ => panic!("unreachable code"),
+            Node::SubImm(reg, x) => {
+                let value = self.operand(x, reg.bits(), false)?;
+                self.push_instr(Instruction::SubImmediate { register: *reg, value });
             }
             Node::Mul(reg) => self.push_instr(Instruction::Multiply(*reg)),
             Node::Div(reg) => self.push_instr(Instruction::Divide(*reg)),
-            Node::SubImmPointer(reg, label) => self.push_instr(Instruction::SubImmediate { register: *reg, value: self.lookup_pointer(label) }),
             Node::And(a, b) => self.push_instr(Instruction::And(*a, *b)),
             Node::Or(a, b) => self.push_instr(Instruction::Or(*a, *b)),
             Node::XOr(a, b) => self.push_instr(Instruction::XOr(*a, *b)),
             Node::CMP(a, b) => self.push_instr(Instruction::Compare(*a, *b)),
-            Node::CMPImm(reg, x) => match reg.bits() {
-                8 => self.push_instr(Instruction::CompareImmediate(*reg, Value::UByte(*x as u8))),
-                16 => self.push_instr(Instruction::CompareImmediate(*reg, Value::UShort(*x as u16))),
-                32 => self.push_instr(Instruction::CompareImmediate(*reg, Value::UInt(*x))),
-                _ => panic!("unreachable code"),
+            Node::CMPImm(reg, x) => {
+                let value = self.operand(x, reg.bits(), false)?;
+                self.push_instr(Instruction::CompareImmediate(*reg, value));
             }
-            Node::CMPImmPointer(reg, label) => self.push_instr(Instruction::CompareImmediate(*reg, self.lookup_pointer(label))),
             Node::BSWAP(reg) => self.push_instr(Instruction::ByteSwap(*reg)),
             Node::Push(reg) => self.push_instr(Instruction::Push(*reg)),
             Node::Pop(reg) => self.push_instr(Instruction::Pop(*reg)),
-            Node::Call(addr) => self.push_instr(Instruction::Call(Value::UInt(*addr))),
-            Node::CallPointer(label) => self.push_instr(Instruction::Call(self.lookup_rel_pointer(label))),
+            Node::Call(addr) => {
+                let addr = self.operand(addr, 32, true)?;
+                self.push_instr(Instruction::Call(addr));
+            }
             Node::CallRegister(register) => self.push_instr(Instruction::CallRegister(*register)),
             Node::Return => self.push_instr(Instruction::Return),
             Node::Not(register) => self.push_instr(Instruction::Not(*register)),
@@ -213,37 +630,338 @@ impl CodeGenerator {
             Node::SHL(register) => self.push_instr(Instruction::ShiftLeft(*register)),
             Node::SHR(register) => self.push_instr(Instruction::ShiftRight(*register)),
             Node::EQU(ident, expr) => {
-                let expr = self.build_expr(expr);
-                let value = self.evaluate_expr(&expr);
+                // Redefining a constant in terms of its old value isn't circular.
+                let mut dependencies = Vec::new();
+                symbols_in(expr, &mut dependencies);
+                if self.variables.contains_key(ident) {
+                    dependencies.retain(|name| name != ident);
+                }
+                self.dependencies.insert(ident.clone(), (dependencies, self.location()));
+
+                let value = self.evaluate(expr)?;
                 self.variables.insert(ident.clone(), value);
+                self.program.constants.push(Constant { name: ident.clone(), value: value as u32, location: self.location() });
             }
             _ => (),
         }
         Ok(())
     }
 
-    fn evaluate_expr(&self, expr: &Expr) -> u32 {
+    /// Pushes the data of 'DB', 'DW' or 'DL', each value 'bits' wide. Strings have a value per
+    /// character and addresses of labels are filled in when the program is laid out.
+    fn push_data(&mut self, data: &[Node], bits: usize) -> Result<(), CodeGenError> {
+        // Every value is evaluated first so '$' is the start of the line throughout.
+        let mut values = Vec::new();
+        for node in data {
+            match node {
+                Node::Float(x) => values.extend(self.float(*x, bits)?),
+                _ if bits == 80 => return Err(self.error("'DT' can only be used with floats.".to_string())),
+                Node::String(s) => values.extend(s.iter().map(|&c| match bits {
+                    8 => Value::UByte(c),
+                    16 => Value::UShort(c as u16),
+                    32 => Value::UInt(c as u32),
+                    _ => Value::ULong(c as u64),
+                })),
+                expr => values.push(self.operand(expr, bits, false)?),
+            }
+        }
+
+        let mut bytes = Vec::new();
+        for value in values {
+            match value {
+                Value::UByte(x) => bytes.push(x),
+                Value::UShort(x) => bytes.extend_from_slice(&utils::dump_word(x, Endianness::Little)),
+                Value::UInt(x) => bytes.extend_from_slice(&utils::dump_dword(x, Endianness::Little)),
+                Value::ULong(x) => bytes.extend_from_slice(&utils::dump_qword(x, Endianness::Little)),
+                value => {
+                    if !bytes.is_empty() {
+                        self.push_instr(Instruction::RawData(std::mem::take(&mut bytes)));
+                    }
+                    self.push_instr(Instruction::Data(value));
+                    // Addresses are 32 bits, the top half of a 'DQ' is 0.
+                    if bits == 64 { bytes.extend_from_slice(&[0; 4]); }
+                }
+            }
+        }
+        if !bytes.is_empty() {
+            self.push_instr(Instruction::RawData(bytes));
+        }
+
+        Ok(())
+    }
+
+    /// Encodes a float as an IEEE single in 'DD', a double in 'DQ' or an x87 extended in 'DT'.
+    fn float(&self, x: f64, bits: usize) -> Result<Vec<Value>, CodeGenError> {
+        match bits {
+            32 if x.is_finite() && !(x as f32).is_finite() => Err(self.error(format!("{:e} doesn't fit in a 32 bit float.", x))),
+            32 => Ok(vec![Value::UInt((x as f32).to_bits())]),
+            64 => Ok(vec![Value::ULong(x.to_bits())]),
+            80 => {
+                let (mantissa, exponent) = utils::to_extended(x);
+                Ok(vec![Value::ULong(mantissa), Value::UShort(exponent)])
+            }
+            _ => Err(self.error(format!("floats can't be used in {} bit data, only in 'DD', 'DQ' and 'DT'.", bits))),
+        }
+    }
+
+    /// Checks an alignment is a power of two no larger than a page.
+    fn check_align(&self, align: i64, directive: &str) -> Result<u64, CodeGenError> {
+        if align <= 0 || !(align as u64).is_power_of_two() || align as u64 > elf::PAGE_SIZE {
+            let message = format!("'{}' alignment {} must be a power of two up to {}.", directive, align, elf::PAGE_SIZE);
+            return Err(CodeGenError { file: self.file.clone(), line_no: self.line_no, message });
+        }
+        Ok(align as u64)
+    }
+
+    /// Evaluates how many times 'TIMES' or '%rep' repeats.
+    fn repeat_count(&mut self, count: &Node, directive: &str) -> Result<u32, CodeGenError> {
+        let count = self.evaluate(count)?;
+        if !(0..=i32::MAX as i64).contains(&count) {
+            let message = format!("'{}' count {} is negative or too large.", directive, count);
+            return Err(CodeGenError { file: self.file.clone(), line_no: self.line_no, message });
+        }
+        Ok(count as u32)
+    }
+
+    /// Evaluates the condition of an '%if' branch, expressions hold when they aren't 0.
+    fn condition(&mut self, condition: &Node) -> Result<bool, CodeGenError> {
+        match condition {
+            Node::IfDef(name) => Ok(self.is_defined(name)),
+            Node::IfNDef(name) => Ok(!self.is_defined(name)),
+            expr => Ok(self.evaluate(expr)? != 0),
+        }
+    }
+
+    /// Checks whether 'name' is an `EQU` constant, a label defined so far, an extern or a macro.
+    fn is_defined(&self, name: &str) -> bool {
+        self.variables.contains_key(name)
+            || self.block_addrs.contains_key(name)
+            || self.program.externs.iter().any(|e| e.name == name)
+            || self.macros.contains_key(name)
+    }
+
+    /// Expands the macro 'name' and processes the expansion. Errors are reported at the call
+    /// with the line of the macro's body they came from.
+    fn expand_macro(&mut self, name: &str, args: &[String]) -> Result<(), CodeGenError> {
+        let error = |gen: &CodeGenerator, message: String| CodeGenError { file: gen.file.clone(), line_no: gen.line_no, message };
+        let Some(mac) = self.macros.get(name).cloned() else {
+            return Err(error(self, format!("unknown instruction or macro '{}'.", name)));
+        };
+        if self.macro_stack.len() >= MAX_MACRO_DEPTH {
+            return Err(error(self, format!("macros are nested more than {} deep expanding '{}', does it expand itself?", MAX_MACRO_DEPTH, name)));
+        }
+
+        self.macro_expansions += 1;
+        let expansion = mac.expand(args, self.macro_expansions).map_err(|message| error(self, message))?;
+        let note = |line_no: usize| MacroLocation { name: mac.name.clone(), location: mac.body_location(line_no) }.note();
+        let root = Parser::parse(&expansion).map_err(|e| error(self, e.message + &note(e.line_no)))?;
+
+        self.macro_stack.push((mac.clone(), 0));
+        let result = self.process(&root);
+        let (_, body_line) = self.macro_stack.pop().unwrap();
+        // Only the innermost macro is noted, so runaway recursion doesn't repeat it each level.
+        result.map_err(|mut e| {
+            if !e.message.contains(" (in macro '") {
+                e.message += &note(body_line);
+            }
+            e
+        })
+    }
+
+    /// Evaluates the expression 'node', reporting errors at the current line. Labels are their
+    /// offset into their segment.
+    fn evaluate(&mut self, node: &Node) -> Result<i64, CodeGenError> {
+        let expr = self.build_expr(node);
+        let term = self.evaluate_expr(&expr).map_err(|message| self.error(message))?;
+        self.unresolve(&term.unknown);
+        Ok(term.value)
+    }
+
+    /// Evaluates an immediate or memory address operand 'bits' wide. A label's address plus or
+    /// minus a constant is resolved when the program is laid out, relative to the end of the
+    /// instruction when 'relative'.
+    fn operand(&mut self, node: &Node, bits: usize, relative: bool) -> Result<Value, CodeGenError> {
+        let expr = self.build_expr(node);
+        let term = self.evaluate_expr(&expr).map_err(|message| self.error(message))?;
+        // Labels subtracted from each other cancel out leaving the distance between them, the
+        // address of an external can't be known until it's loaded though.
+        let count: i64 = term.labels.iter().map(|(_, count)| count).sum();
+        let external = term.labels.iter().find(|(label, _)| self.program.externs.iter().any(|e| &e.name == label));
+        let base = term.labels.iter().filter(|(_, count)| *count > 0).map(|(label, _)| label)
+            .find(|label| !label.is_empty()).or(term.labels.first().map(|(label, _)| label));
+        let result = match (count, base) {
+            _ if term.scaled => Err("an address can only have constants added to or subtracted from it.".to_string()),
+            (_, Some(_)) if term.labels.len() > 1 && external.is_some() => {
+                Err(format!("'{}' is EXTERN, its address can only have constants added to or subtracted from it.", external.unwrap().0))
+            }
+            (0, _) if bits < 64 && (term.value < -(1 << (bits - 1)) || term.value >= 1 << bits) => {
+                Err(format!("{} doesn't fit in {} bits.", term.value, bits))
+            }
+            (0, _) => match bits {
+                8 => Ok(Value::UByte(term.value as u8)),
+                16 => Ok(Value::UShort(term.value as u16)),
+                32 => Ok(Value::UInt(term.value as u32)),
+                _ => Ok(Value::ULong(term.value as u64)),
+            }
+            (1, Some(label)) if bits < 32 => Err(format!("the address of '{}' doesn't fit in {} bits.", label, bits)),
+            (1, Some(label)) if label.is_empty() => {
+                let segment = &self.program.current_segment().name;
+                Err(format!("'$' can only be used as an address after a label in segment '{}'.", segment))
+            }
+            (1, Some(label)) => {
+                let offset = self.block_addrs.get(label).or(self.forward_labels.get(label)).copied().unwrap_or(0);
+                match i32::try_from(term.value - offset as i64) {
+                    Ok(addend) if relative => Ok(Value::RelPointer(label.clone(), addend)),
+                    Ok(addend) => Ok(Value::Pointer(label.clone(), addend)),
+                    Err(_) => Err(format!("the offset from '{}' doesn't fit in 32 bits.", label)),
+                }
+            }
+            _ => Err("an expression can only use the address of one label, or the difference between two.".to_string()),
+        };
+
+        // A label used as the address is checked when the program is laid out, so it can be
+        // declared EXTERN.
+        let address = match &result {
+            Ok(Value::Pointer(label, _) | Value::RelPointer(label, _)) => Some(label),
+            _ => None,
+        };
+        let unknown: Vec<String> = term.unknown.iter().filter(|name| Some(*name) != address).cloned().collect();
+        self.unresolve(&unknown);
+
+        match result {
+            // The symbol may be a constant defined further on, the next pass will know.
+            Err(_) if !unknown.is_empty() => Ok(match bits { 8 => Value::UByte(0), 16 => Value::UShort(0), 32 => Value::UInt(0), _ => Value::ULong(0) }),
+            result => result.map_err(|message| self.error(message)),
+        }
+    }
+
+    /// Gets the full name of a label: '.loop' after 'PrintHex' is 'PrintHex.loop', and '1b' and
+    /// '1f' are the numeric label '1:' before and after this line.
+    fn label_name(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            return format!("{}{}", self.scope, name);
+        }
+        let Some(number) = name.strip_suffix(['b', 'f']).filter(|n| n.starts_with(|c: char| c.is_ascii_digit())) else {
+            return name.to_string();
+        };
+
+        let defined = self.numeric_labels.get(number).copied().unwrap_or(0);
+        match name.ends_with('b') {
+            // With no '1:' before it '1b' is left as it is, to be reported as undefined.
+            true if defined == 0 => name.to_string(),
+            true => numeric_label(number, defined),
+            false => numeric_label(number, defined + 1),
+        }
+    }
+
+    /// Gets the full name of a label being defined, and makes it the scope of the '.<name>'
+    /// labels after it unless it's local itself.
+    fn define_label(&mut self, name: &str) -> String {
+        if name.starts_with(|c: char| c.is_ascii_digit()) {
+            let defined = self.numeric_labels.entry(name.to_string()).or_insert(0);
+            *defined += 1;
+            return numeric_label(name, *defined);
+        }
+
+        let label = self.label_name(name);
+        if !name.starts_with('.') && !name.starts_with(LOCAL_LABEL_PREFIX) {
+            self.scope = label.clone();
+        }
+        label
+    }
+
+    /// Records symbols used by an expression that weren't found.
+    fn unresolve(&mut self, names: &[String]) {
+        let location = self.location();
+        let macro_location = self.macro_location();
+        self.unresolved.extend(names.iter().map(|name| (name.clone(), location.clone(), macro_location.clone())));
+    }
+
+    fn error(&self, message: String) -> CodeGenError {
+        CodeGenError { file: self.file.clone(), line_no: self.line_no, message }
+    }
+
+    /// Evaluates an expression with 64 bit arithmetic. Labels and constants defined later on take
+    /// their value from the previous pass and symbols not found at all are 0. '/', '%' and '>>'
+    /// treat their operands as unsigned, '//' and '%%' as signed, and comparisons are signed
+    /// giving 1 or 0.
+    fn evaluate_expr(&mut self, expr: &Expr) -> Result<Term, String> {
         match expr {
-            Expr::Number(v) => *v,
-            Expr::PC => self.program.len() as u32,
-            Expr::Pointer(label) => match self.block_addrs.get(label) {
-                Some(x) => *x,
-                None => {
-                    println!("Warning: unknown label '{}' in expression.", label);
-                    0
-                },
+            Expr::Number(v) => Ok(Term { value: *v, ..Term::default() }),
+            // '$' counts from the start of the segment, as an address it's relative to the
+            // label of the block it's in.
+            Expr::PC | Expr::SegmentStart => {
+                let value = if matches!(expr, Expr::PC) { self.program.current_len() as i64 } else { 0 };
+                let label = self.program.get_block_mut(self.current_block).unwrap().label().to_string();
+                Ok(Term { value, labels: vec![(label, 1)], ..Term::default() })
+            }
+            Expr::Pointer(name) => {
+                self.used.insert(name.clone());
+                let labels = vec![(name.clone(), 1)];
+                match self.block_addrs.get(name).or(self.forward_labels.get(name)) {
+                    Some(offset) => Ok(Term { value: *offset as i64, labels, ..Term::default() }),
+                    None => match self.forward_variables.get(name) {
+                        Some(value) => Ok(Term { value: *value, ..Term::default() }),
+                        None => Ok(Term { value: 0, labels, scaled: false, unknown: vec![name.clone()] }),
+                    }
+                }
+            }
+            Expr::UnaryOp { op, operand } => {
+                let term = self.evaluate_expr(operand)?;
+                let a = term.value;
+                let value = match op {
+                    Token::Minus => return Ok(Term::combine(a.checked_neg().ok_or(format!("-({}) overflows 64 bits.", a))?, Term::default(), term, -1)),
+                    Token::BitNot => !a,
+                    Token::LogicalNot => (a == 0) as i64,
+                    _ => panic!("not a unary operator!"),
+                };
+                let scaled = term.scaled || !term.labels.is_empty();
+                Ok(Term { value, labels: Vec::new(), scaled, unknown: term.unknown })
             }
             Expr::BinaryOp { op, lhs, rhs } => {
-                let a = self.evaluate_expr(lhs);
-                let b = self.evaluate_expr(rhs);
-                
+                let lhs = self.evaluate_expr(lhs)?;
+                let rhs = self.evaluate_expr(rhs)?;
+                let (a, b) = (lhs.value, rhs.value);
+                let overflow = |symbol: &str| format!("{} {} {} overflows 64 bits.", a, symbol, b);
+                let shift = |symbol: &str| match u32::try_from(b) {
+                    Ok(b) if b < 64 => Ok(b),
+                    _ => Err(format!("shift {} {} {} is out of range, shifts are from 0 to 63.", a, symbol, b)),
+                };
+                if b == 0 && matches!(op, Token::Divide | Token::SignedDivide | Token::Modulo | Token::SignedModulo) {
+                    return Err("division by zero.".to_string());
+                }
+
+                // Only adding and subtracting keeps track of labels.
                 match op {
-                    Token::Plus => a + b,
-                    Token::Minus => a - b,
-                    Token::Multiply => a * b,
-                    Token::Divide => a / b,
-                    _ => 0,
+                    Token::Plus => return Ok(Term::combine(a.checked_add(b).ok_or(overflow("+"))?, lhs, rhs, 1)),
+                    Token::Minus => return Ok(Term::combine(a.checked_sub(b).ok_or(overflow("-"))?, lhs, rhs, -1)),
+                    _ => (),
                 }
+                let scaled = !lhs.labels.is_empty() || !rhs.labels.is_empty();
+                let value = match op {
+                    Token::Multiply => a.checked_mul(b).ok_or(overflow("*")),
+                    Token::Divide => Ok(((a as u64) / (b as u64)) as i64),
+                    Token::Modulo => Ok(((a as u64) % (b as u64)) as i64),
+                    Token::SignedDivide => a.checked_div(b).ok_or(overflow("//")),
+                    Token::SignedModulo => a.checked_rem(b).ok_or(overflow("%%")),
+                    Token::BitAnd => Ok(a & b),
+                    Token::BitOr => Ok(a | b),
+                    Token::BitXor => Ok(a ^ b),
+                    Token::LeftShift => Ok(a << shift("<<")?),
+                    Token::RightShift => Ok(((a as u64) >> shift(">>")?) as i64),
+                    Token::LogicalAnd => Ok((a != 0 && b != 0) as i64),
+                    Token::LogicalOr => Ok((a != 0 || b != 0) as i64),
+                    Token::Equal => Ok((a == b) as i64),
+                    Token::NotEqual => Ok((a != b) as i64),
+                    Token::Less => Ok((a < b) as i64),
+                    Token::LessEqual => Ok((a <= b) as i64),
+                    Token::Greater => Ok((a > b) as i64),
+                    Token::GreaterEqual => Ok((a >= b) as i64),
+                    _ => panic!("not an operator!"),
+                }?;
+                let mut unknown = lhs.unknown;
+                unknown.extend(rhs.unknown);
+                Ok(Term { value, labels: Vec::new(), scaled: scaled || lhs.scaled || rhs.scaled, unknown })
             }
         }
     }
@@ -252,10 +970,12 @@ impl CodeGenerator {
         match node {
             Node::Pointer(label) => match self.variables.get(label) {
                 Some(v) => Expr::Number(*v),
-                None => Expr::Pointer(label.to_string())
+                None => Expr::Pointer(self.label_name(label))
             }
             Node::Integer(v) => Expr::Number(*v),
+            Node::Unary(op, node) => Expr::UnaryOp { op: op.clone(), operand: Box::new(self.build_expr(node)) },
             Node::Dollar => Expr::PC,
+            Node::DoubleDollar => Expr::SegmentStart,
             Node::ParenExpr(node) => self.build_expr(node),
             Node::Expr(nodes)  => {
                 let mut peekable = nodes.iter().peekable();
@@ -266,28 +986,19 @@ impl CodeGenerator {
         }
     }
 
+    // Operators of equal precedence group from the left, e.g. 1 - 2 - 3 is (1 - 2) - 3.
     fn build_expr_climber(&self, mut lhs: Expr, nodes: &mut core::iter::Peekable<std::slice::Iter<'_, Node>>, min_precedence: usize) -> Expr {
-        let mut peek = nodes.peek();
-        while peek.is_some() {
-            let operator = match peek.unwrap() {
-                Node::Operator(op) => op, 
-                _ => panic!("not an operator!"),
-            };
-            if precedence(&operator) < min_precedence { break; }
+        while let Some(Node::Operator(operator)) = nodes.peek() {
+            if precedence(operator) < min_precedence { break; }
 
             nodes.next();
             let lookahead = nodes.next();
             let mut rhs = self.build_expr(lookahead.unwrap());
 
-            peek = nodes.peek();
-            while peek.is_some() {
-                match peek.unwrap() {
-                    Node::Operator(op) => if precedence(&op) <= min_precedence { break }
-                    _ => break,
-                }               
-
-                rhs = self.build_expr_climber(rhs.clone(), nodes, precedence(&operator));
-                peek = nodes.peek();
+            // Operators binding tighter take the right hand side first.
+            while let Some(Node::Operator(op)) = nodes.peek() {
+                if precedence(op) <= precedence(operator) { break }
+                rhs = self.build_expr_climber(rhs, nodes, precedence(operator) + 1);
             }
 
             lhs = Expr::BinaryOp { 
@@ -299,3 +1010,253 @@ impl CodeGenerator {
         lhs
     }
 }
+
+/// Names the 'n'th definition of numeric label 'number'.
+fn numeric_label(number: &str, n: usize) -> String {
+    format!("{}@{}", number, n)
+}
+
+/// Gets the name of a symbol as written in the source, numeric labels referred to before
+/// they're defined are written '1f'.
+fn source_name(name: &str) -> String {
+    match name.split_once('@') {
+        Some((number, _)) if number.starts_with(|c: char| c.is_ascii_digit()) => format!("{}f", number),
+        _ => name.to_string(),
+    }
+}
+
+/// Collects the names of the symbols used by the expression 'node'.
+fn symbols_in(node: &Node, symbols: &mut Vec<String>) {
+    match node {
+        Node::Pointer(name) => symbols.push(name.clone()),
+        Node::ParenExpr(node) | Node::Unary(_, node) => symbols_in(node, symbols),
+        Node::Expr(nodes) => nodes.iter().for_each(|node| symbols_in(node, symbols)),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembles 'code' saved to a temporary file named after 'name'.
+    fn assemble(name: &str, code: &str) -> Result<Program, CodeGenError> {
+        let path = std::env::temp_dir().join(format!("tasm_code_gen_{}.s", name));
+        std::fs::write(&path, code).unwrap();
+        CodeGenerator::generate(&path, &[], &[])
+    }
+
+    #[test]
+    fn forward_references() {
+        let program = assemble("forward", "EQU size end - start\n_start:\nstart:\n    DB 1, 2, 3\nend:\n").ok().unwrap();
+        assert_eq!(program.constants[0].value, 3);
+
+        let error = assemble("undefined", "EQU size nothing + 1\n").err().unwrap();
+        assert_eq!((error.line_no, error.message.as_str()), (1, "undefined symbol 'nothing' in expression."));
+
+        let error = assemble("circular", "EQU a b\nEQU b a + 1\n").err().unwrap();
+        assert_eq!((error.line_no, error.message.as_str()), (1, "circular definition of 'a' (a -> b -> a)."));
+
+        // Redefining a constant in terms of itself is fine.
+        let program = assemble("redefined", "EQU a 1\nEQU a a + 1\n").ok().unwrap();
+        assert_eq!(program.constants[1].value, 2);
+
+        let error = assemble("unsettled", "_start:\n    TIMES end + 1 DB 0\nend:\n").err().unwrap();
+        assert_eq!(error.line_no, 3);
+        assert!(error.message.starts_with("the value of 'end' didn't settle"));
+    }
+
+    #[test]
+    fn expressions() {
+        let values = [
+            ("1 - 2 * 3 - 4", -9),
+            ("1 | 2 ^ 3 & 6", 1),
+            ("1 << 4 + 1", 32),
+            ("-8 >> 1 >> 32", 0x7FFF_FFFF),
+            ("-7 // 2", -3),
+            ("-7 %% 2", -1),
+            ("7 % 3", 1),
+            ("~0", -1),
+            ("!0 + !5", 1),
+            ("1 < 2 && 2 >= 2 || 0", 1),
+            ("3 == 3 != 0", 1),
+            ("-(2 + 3)", -5),
+        ];
+        for (idx, (expr, value)) in values.iter().enumerate() {
+            let program = assemble(&format!("expression{}", idx), &format!("EQU x {}\n", expr)).ok().unwrap();
+            assert_eq!(program.constants[0].value, *value as u32, "{}", expr);
+        }
+
+        let error = assemble("zero", "EQU a 0\n\nEQU b 1 // a\n").err().unwrap();
+        assert_eq!((error.line_no, error.message.as_str()), (3, "division by zero."));
+
+        let error = assemble("overflow", "EQU a 0x7FFFFFFF * 0x7FFFFFFF * 4\n").err().unwrap();
+        assert_eq!(error.message, "4611686014132420609 * 4 overflows 64 bits.");
+
+        let error = assemble("shift", "EQU a 1 << 64\n").err().unwrap();
+        assert_eq!(error.message, "shift 1 << 64 is out of range, shifts are from 0 to 63.");
+
+        let error = assemble("big_hex", "EQU a 0xFFFFFFFFFFFFFFFF + 1\n").err().unwrap();
+        assert_eq!(error.message, "invalid expression: 0xFFFFFFFFFFFFFFFF doesn't fit in 64 bits as a signed number");
+
+        let error = assemble("big_decimal", "EQU a -9223372036854775808\n").err().unwrap();
+        assert_eq!(error.message, "invalid expression: 9223372036854775808 doesn't fit in 64 bits");
+
+        // '%' and '%%' right before a number or name aren't macro parameters outside macros.
+        let program = assemble("modulo", "EQU k 4\n_start:\n    mov eax, 10%3\n    mov ebx, 10 %3\n    mov ecx, -6 %%k + 4\n").ok().unwrap();
+        assert_eq!(program.segment_as_vec(0), vec![0xB8, 1, 0, 0, 0, 0xBB, 1, 0, 0, 0, 0xB9, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn operands() {
+        // Not laid out, so labels are at their offset into the segment.
+        let code = "_start:\n    mov eax, _msg + 2\n    mov bl, end - _msg - 1\n    jmp $\n_msg:\n    DL _msg + 1, 3 * 4\n    DB -1, \"a\"\nend:\n";
+        let program = assemble("operands", code).ok().unwrap();
+        assert_eq!(program.segment_as_vec(0), vec![
+            0xB8, 0x0E, 0, 0, 0,
+            0xB3, 0x09,
+            0xE9, 0xFB, 0xFF, 0xFF, 0xFF,
+            0x0D, 0, 0, 0, 0x0C, 0, 0, 0,
+            0xFF, b'a',
+        ]);
+
+        let error = assemble("too_big", "_start:\n    DB 256\n").err().unwrap();
+        assert_eq!((error.line_no, error.message.as_str()), (2, "256 doesn't fit in 8 bits."));
+
+        let error = assemble("scaled", "_start:\n    mov eax, _start * 2\n").err().unwrap();
+        assert_eq!(error.message, "an address can only have constants added to or subtracted from it.");
+
+        let error = assemble("byte_address", "_start:\n    mov al, _start\n").err().unwrap();
+        assert_eq!(error.message, "the address of '_start' doesn't fit in 8 bits.");
+
+        // Negative values fit as long as they're in range as signed numbers.
+        let program = assemble("negative", "_start:\n    mov al, -1\n    DW -2, 'A'\n").ok().unwrap();
+        assert_eq!(program.segment_as_vec(0), vec![0xB0, 0xFF, 0xFE, 0xFF, 0x41, 0]);
+
+        // Constants defined further on fit in a byte once known.
+        let program = assemble("later", "_start:\n    mov al, size\nEQU size 4 + 1\n").ok().unwrap();
+        assert_eq!(program.segment_as_vec(0), vec![0xB0, 0x05]);
+    }
+
+    #[test]
+    fn wide_data() {
+        let code = "_start:\n    DD 1.5, _start + 2\n    DQ -2.5, _start + 1, 0x123456789ABCDEF0\n    DT 1.0, -2.\n";
+        let program = assemble("wide_data", code).ok().unwrap();
+        assert_eq!(program.segment_as_vec(0), vec![
+            0, 0, 0xC0, 0x3F, 2, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0x04, 0xC0,
+            1, 0, 0, 0, 0, 0, 0, 0,
+            0xF0, 0xDE, 0xBC, 0x9A, 0x78, 0x56, 0x34, 0x12,
+            0, 0, 0, 0, 0, 0, 0, 0x80, 0xFF, 0x3F,
+            0, 0, 0, 0, 0, 0, 0, 0x80, 0x00, 0xC0,
+        ]);
+
+        let error = assemble("byte_float", "_start:\n    DB 1.5\n").err().unwrap();
+        assert_eq!(error.message, "floats can't be used in 8 bit data, only in 'DD', 'DQ' and 'DT'.");
+
+        let error = assemble("integer_dt", "_start:\n    DT 1\n").err().unwrap();
+        assert_eq!(error.message, "'DT' can only be used with floats.");
+
+        let error = assemble("big_float", "_start:\n    DD 1e300\n").err().unwrap();
+        assert_eq!(error.message, "1e300 doesn't fit in a 32 bit float.");
+    }
+
+    #[test]
+    fn local_labels() {
+        let code = "_start:\n    jmp .done\n.done:\n    ret\nOther:\n.done:\n    jmp _start.done\n1:\n    jmp 1b\n    jmp 1f\n1:\n";
+        let program = assemble("local_labels", code).ok().unwrap();
+        assert_eq!(program.segment_as_vec(0), vec![
+            0xE9, 0, 0, 0, 0,
+            0xC3,
+            0xE9, 0xFA, 0xFF, 0xFF, 0xFF,
+            0xE9, 0xFB, 0xFF, 0xFF, 0xFF,
+            0xE9, 0, 0, 0, 0,
+        ]);
+        assert!(program.get_addr("Other.done").is_some());
+
+        let error = assemble("no_label_before", "_start:\n    jmp 1b\n1:\n").err().unwrap();
+        assert_eq!((error.line_no, error.message.as_str()), (2, "undefined symbol '1b', declare it with EXTERN if it's defined elsewhere."));
+
+        let error = assemble("no_label_after", "_start:\n1:\n    jmp 1f\n").err().unwrap();
+        assert_eq!((error.line_no, error.message.as_str()), (3, "undefined symbol '1f', declare it with EXTERN if it's defined elsewhere."));
+    }
+
+    #[test]
+    fn label_diagnostics() {
+        let error = assemble("duplicate", "_start:\n    ret\n_start:\n").err().unwrap();
+        let first = std::env::temp_dir().join("tasm_code_gen_duplicate.s").display().to_string();
+        assert_eq!((error.line_no, error.message), (3, format!("duplicate label '_start', first defined on line 1 in \"{}\".", first)));
+
+        let error = assemble("typo", "_start:\n_loop:\n    jmp _lopo\n").err().unwrap();
+        assert_eq!((error.line_no, error.message.as_str()), (3, "undefined symbol '_lopo', declare it with EXTERN if it's defined elsewhere."));
+
+        let code = "ENTRY _start\nGLOBAL exported\n_start:\n    jmp .used\n.used:\n.unused:\nexported:\n1:\nunused:\n";
+        let program = assemble("unused", code).ok().unwrap();
+        let unused: Vec<(&str, usize)> = program.unused_labels.iter().map(|(label, location)| (label.as_str(), location.line_no)).collect();
+        assert_eq!(unused, vec![("_start.unused", 6), ("unused", 9)]);
+    }
+
+    #[test]
+    fn macro_diagnostics() {
+        let path = |name: &str| std::env::temp_dir().join(format!("tasm_code_gen_{}.s", name)).display().to_string();
+
+        let error = assemble("macro_range", "%macro m 0\n    ret\n    mov al, 300\n%endmacro\n_start:\n    m\n").err().unwrap();
+        let note = format!(" (in macro 'm' at \"{}\" line 3)", path("macro_range"));
+        assert_eq!((error.line_no, error.message), (6, format!("300 doesn't fit in 8 bits.{}", note)));
+
+        let error = assemble("macro_jump", "%macro m 0\n    jmp nowhere\n%endmacro\n_start:\n    m\n").err().unwrap();
+        let note = format!(" (in macro 'm' at \"{}\" line 2)", path("macro_jump"));
+        assert_eq!((error.line_no, error.message), (5, format!("undefined symbol 'nowhere', declare it with EXTERN if it's defined elsewhere.{}", note)));
+
+        let error = assemble("macro_expression", "%macro m 0\n    DB nowhere - 1\n%endmacro\n_start:\n    m\n").err().unwrap();
+        let note = format!(" (in macro 'm' at \"{}\" line 2)", path("macro_expression"));
+        assert_eq!((error.line_no, error.message), (5, format!("undefined symbol 'nowhere' in expression.{}", note)));
+    }
+
+    #[test]
+    fn incbin() {
+        std::fs::write(std::env::temp_dir().join("tasm_code_gen_incbin.bin"), [1, 2, 3, 4, 5]).unwrap();
+        let code = "_start:\n    INCBIN \"tasm_code_gen_incbin.bin\"\n    INCBIN \"tasm_code_gen_incbin.bin\", 3\n    INCBIN \"tasm_code_gen_incbin.bin\", 1, 2\n";
+        let program = assemble("incbin", code).ok().unwrap();
+        assert_eq!(program.segment_as_vec(0), vec![1, 2, 3, 4, 5, 4, 5, 2, 3]);
+
+        let error = assemble("incbin_offset", "_start:\n    INCBIN \"tasm_code_gen_incbin.bin\", 6\n").err().unwrap();
+        assert_eq!(error.message, "'INCBIN' offset 6 is outside \"tasm_code_gen_incbin.bin\", which is 5 bytes.");
+
+        let error = assemble("incbin_missing", "_start:\n    INCBIN \"tasm_missing.bin\"\n").err().unwrap();
+        assert_eq!((error.line_no, error.message.as_str()), (2, "can't find INCBIN file \"tasm_missing.bin\", add its directory with -I or TASM_INCLUDE."));
+    }
+
+    #[test]
+    fn defines() {
+        assert_eq!(parse_define("DEBUG"), Ok(("DEBUG".to_string(), "1".to_string())));
+        assert_eq!(parse_define("_size=0x10"), Ok(("_size".to_string(), "0x10".to_string())));
+        assert_eq!(parse_define("x=a=b"), Ok(("x".to_string(), "a=b".to_string())));
+        assert_eq!(parse_define(""), Err("invalid symbol name ''".to_string()));
+        assert_eq!(parse_define("=3"), Err("invalid symbol name ''".to_string()));
+        assert_eq!(parse_define("1x=3"), Err("invalid symbol name '1x'".to_string()));
+        assert_eq!(parse_define("a-b"), Err("invalid symbol name 'a-b'".to_string()));
+    }
+
+    #[test]
+    fn include_order() {
+        // 'a' is in every directory, 'b' in the -I and TASM_INCLUDE directories, 'c' only in
+        // TASM_INCLUDE.
+        let root = std::env::temp_dir().join("tasm_code_gen_include_order");
+        let (src, dir, env) = (root.join("src"), root.join("dir"), root.join("env"));
+        for (path, files) in [(&src, &["a"][..]), (&dir, &["a", "b"]), (&env, &["a", "b", "c"])] {
+            std::fs::create_dir_all(path).unwrap();
+            for file in files {
+                std::fs::write(path.join(file), "").unwrap();
+            }
+        }
+
+        let tasm_include = std::env::join_paths([&env]).unwrap();
+        let include_dirs = include_dirs(std::slice::from_ref(&dir), Some(&tasm_include));
+        let gen = CodeGenerator::new(&src.join("main.s"), &include_dirs, Default::default());
+        assert_eq!(gen.find_include("a"), Some(src.join("a")));
+        assert_eq!(gen.find_include("b"), Some(dir.join("b")));
+        assert_eq!(gen.find_include("c"), Some(env.join("c")));
+        assert_eq!(gen.find_include("d"), None);
+    }
+}
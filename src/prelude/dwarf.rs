@@ -0,0 +1,277 @@
+use super::{Endianness, utils::*, Program};
+
+// DWARF constants, see the DWARF 3 standard section 7.
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_CHILDREN_NO: u8 = 0x00;
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_AT_LANGUAGE: u64 = 0x13;
+const DW_AT_COMP_DIR: u64 = 0x1b;
+const DW_AT_PRODUCER: u64 = 0x25;
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_LANG_MIPS_ASSEMBLER: u16 = 0x8001;
+
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_PC: u8 = 0x02;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNS_SET_FILE: u8 = 0x04;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+/// Line program header parameters. We only use the standard opcodes so the special opcode
+/// parameters just need to be valid.
+const LINE_BASE: i8 = -5;
+const LINE_RANGE: u8 = 14;
+const OPCODE_BASE: u8 = 13;
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+/// The contents of the .debug_abbrev, .debug_info and .debug_line sections describing a single
+/// compile unit for a 32-bit program.
+pub struct DebugInfo {
+    pub abbrev: Vec<u8>,
+    pub info: Vec<u8>,
+    pub line: Vec<u8>,
+}
+
+impl DebugInfo {
+    /// Generates the debug information for the program. `name` is the main source file and
+    /// `comp_dir` the directory relative file names are resolved against.
+    pub fn new(program: &Program, name: &str, comp_dir: &str) -> DebugInfo {
+        DebugInfo {
+            abbrev: Self::abbrev(),
+            info: Self::info(program, name, comp_dir),
+            line: Self::line(program),
+        }
+    }
+
+    /// Generates .debug_abbrev containing the single compile unit abbreviation.
+    fn abbrev() -> Vec<u8> {
+        let mut dump = Vec::new();
+        dump.extend_from_slice(&encode_uleb128(1)); // abbreviation code
+        dump.extend_from_slice(&encode_uleb128(DW_TAG_COMPILE_UNIT));
+        dump.push(DW_CHILDREN_NO);
+
+        for (attr, form) in [
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_COMP_DIR, DW_FORM_STRING),
+            (DW_AT_PRODUCER, DW_FORM_STRING),
+            (DW_AT_LANGUAGE, DW_FORM_DATA2),
+            (DW_AT_STMT_LIST, DW_FORM_DATA4),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_ADDR),
+        ] {
+            dump.extend_from_slice(&encode_uleb128(attr));
+            dump.extend_from_slice(&encode_uleb128(form));
+        }
+
+        // Terminate the attribute list and then the abbreviations.
+        dump.extend_from_slice(&[0, 0, 0]);
+        dump
+    }
+
+    /// Generates .debug_info containing the compile unit covering the whole program.
+    fn info(program: &Program, name: &str, comp_dir: &str) -> Vec<u8> {
+        let endianness = Endianness::Little;
+        let segments = program.segments().iter().enumerate()
+            .map(|(idx, s)| (s.start.vaddr, s.start.vaddr + program.segment_len(idx) as u64));
+        let low_pc = segments.clone().map(|(start, _)| start).min().unwrap_or(0) as u32;
+        let high_pc = segments.map(|(_, end)| end).max().unwrap_or(0) as u32;
+
+        let mut die = Vec::new();
+        die.extend_from_slice(&encode_uleb128(1)); // abbreviation code
+        push_string(&mut die, name);
+        push_string(&mut die, comp_dir);
+        push_string(&mut die, concat!("tasm ", env!("CARGO_PKG_VERSION")));
+        die.extend_from_slice(&dump_word(DW_LANG_MIPS_ASSEMBLER, endianness));
+        die.extend_from_slice(&dump_dword(0, endianness)); // offset into .debug_line
+        die.extend_from_slice(&dump_dword(low_pc, endianness));
+        die.extend_from_slice(&dump_dword(high_pc, endianness));
+
+        let mut dump = Vec::new();
+        // unit_length: length of the unit not including this field.
+        dump.extend_from_slice(&dump_dword(2 + 4 + 1 + die.len() as u32, endianness));
+        dump.extend_from_slice(&dump_word(3, endianness)); // version
+        dump.extend_from_slice(&dump_dword(0, endianness)); // offset into .debug_abbrev
+        dump.push(4); // address_size
+        dump.extend_from_slice(&die);
+        dump
+    }
+
+    /// Generates .debug_line mapping every instruction with a source location to its line.
+    fn line(program: &Program) -> Vec<u8> {
+        let endianness = Endianness::Little;
+        let table = program.line_table();
+
+        // Build the file table in order of appearance. File indices start at 1.
+        let mut files: Vec<&str> = Vec::new();
+        for (_, _, location) in &table {
+            if !files.contains(&location.file.as_str()) {
+                files.push(&location.file);
+            }
+        }
+
+        let mut header = Vec::new();
+        header.push(1); // minimum_instruction_length
+        header.push(1); // default_is_stmt
+        header.push(LINE_BASE as u8);
+        header.push(LINE_RANGE);
+        header.push(OPCODE_BASE);
+        header.extend_from_slice(&STANDARD_OPCODE_LENGTHS);
+        header.push(0); // include_directories: empty, everything is relative to comp_dir
+        for file in &files {
+            push_string(&mut header, file);
+            header.extend_from_slice(&encode_uleb128(0)); // directory index
+            header.extend_from_slice(&encode_uleb128(0)); // modification time
+            header.extend_from_slice(&encode_uleb128(0)); // file length
+        }
+        header.push(0);
+
+        // Addresses must increase within a sequence, so a new sequence is started whenever the
+        // table jumps backwards (e.g. into a segment placed at a lower address).
+        let mut program_data = Vec::new();
+        let mut sequence: Option<(usize, i64, u64)> = None; // (file, line, address)
+
+        for (instr_addr, len, location) in &table {
+            let (mut file, mut line, addr) = match sequence {
+                Some((file, line, addr)) if instr_addr.vaddr >= addr => (file, line, addr),
+                _ => {
+                    if sequence.is_some() {
+                        end_sequence(&mut program_data);
+                    }
+
+                    // The state machine starts at file 1, line 1.
+                    program_data.push(0); // extended opcode
+                    program_data.extend_from_slice(&encode_uleb128(5));
+                    program_data.push(DW_LNE_SET_ADDRESS);
+                    program_data.extend_from_slice(&dump_dword(instr_addr.vaddr as u32, endianness));
+                    (1, 1, instr_addr.vaddr)
+                }
+            };
+
+            if instr_addr.vaddr != addr {
+                program_data.push(DW_LNS_ADVANCE_PC);
+                program_data.extend_from_slice(&encode_uleb128(instr_addr.vaddr - addr));
+            }
+
+            let location_file = files.iter().position(|f| *f == location.file).unwrap() + 1;
+            if location_file != file {
+                program_data.push(DW_LNS_SET_FILE);
+                program_data.extend_from_slice(&encode_uleb128(location_file as u64));
+                file = location_file;
+            }
+
+            let location_line = location.line_no as i64;
+            if location_line != line {
+                program_data.push(DW_LNS_ADVANCE_LINE);
+                program_data.extend_from_slice(&encode_sleb128(location_line - line));
+                line = location_line;
+            }
+
+            program_data.push(DW_LNS_COPY);
+
+            // Advance past the instruction so the sequence ends one past its last byte.
+            program_data.push(DW_LNS_ADVANCE_PC);
+            program_data.extend_from_slice(&encode_uleb128(*len as u64));
+            sequence = Some((file, line, instr_addr.vaddr + *len as u64));
+        }
+
+        if sequence.is_some() {
+            end_sequence(&mut program_data);
+        }
+
+        let mut dump = Vec::new();
+        // unit_length: length of the unit not including this field.
+        let unit_length = 2 + 4 + header.len() + program_data.len();
+        dump.extend_from_slice(&dump_dword(unit_length as u32, endianness));
+        dump.extend_from_slice(&dump_word(3, endianness)); // version
+        dump.extend_from_slice(&dump_dword(header.len() as u32, endianness)); // header_length
+        dump.extend_from_slice(&header);
+        dump.extend_from_slice(&program_data);
+        dump
+    }
+}
+
+/// Ends the current line number sequence.
+fn end_sequence(program_data: &mut Vec<u8>) {
+    program_data.push(0); // extended opcode
+    program_data.extend_from_slice(&encode_uleb128(1));
+    program_data.push(DW_LNE_END_SEQUENCE);
+}
+
+/// Pushes a null terminated string.
+fn push_string(dump: &mut Vec<u8>, s: &str) {
+    dump.extend_from_slice(s.as_bytes());
+    dump.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_leb128(data: &[u8], offset: &mut usize) -> (u64, u32) {
+        let (mut value, mut shift) = (0, 0);
+        loop {
+            let byte = data[*offset];
+            *offset += 1;
+            value |= ((byte & 0x7F) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return (value, shift);
+            }
+        }
+    }
+
+    /// Runs the line program, returning the (address, file, line) rows it emits. Only the opcodes
+    /// `line` generates are understood.
+    fn rows(dump: &[u8]) -> Vec<(u64, u64, i64)> {
+        let header_length = read_dword(dump, 6, Endianness::Little) as usize;
+        let mut offset = 10 + header_length;
+        let (mut addr, mut file, mut line) = (0, 1, 1);
+        let mut rows = Vec::new();
+
+        while offset < dump.len() {
+            let opcode = dump[offset];
+            offset += 1;
+            match opcode {
+                0 => {
+                    let (len, _) = read_leb128(dump, &mut offset);
+                    match dump[offset] {
+                        DW_LNE_SET_ADDRESS => addr = read_dword(dump, offset + 1, Endianness::Little) as u64,
+                        DW_LNE_END_SEQUENCE => (file, line) = (1, 1),
+                        x => panic!("unexpected extended opcode {}", x),
+                    }
+                    offset += len as usize;
+                }
+                DW_LNS_COPY => rows.push((addr, file, line)),
+                DW_LNS_ADVANCE_PC => addr += read_leb128(dump, &mut offset).0,
+                DW_LNS_SET_FILE => file = read_leb128(dump, &mut offset).0,
+                DW_LNS_ADVANCE_LINE => {
+                    let (value, shift) = read_leb128(dump, &mut offset);
+                    line += ((value << (64 - shift)) as i64) >> (64 - shift);
+                }
+                x => panic!("unexpected opcode {}", x),
+            }
+        }
+
+        rows
+    }
+
+    #[test]
+    fn line() {
+        let path = std::env::temp_dir().join("tasm_dwarf_line.s");
+        std::fs::write(&path, "ENTRY _start\n_start:\n    mov eax, 1\n\n    mov ebx, 2\n    ret\n").unwrap();
+        let program = crate::prelude::CodeGenerator::generate(&path, &[], &[]).ok().unwrap();
+        let start = program.line_table()[0].0.vaddr;
+
+        assert_eq!(rows(&DebugInfo::line(&program)), vec![
+            (start, 1, 3),
+            (start + 5, 1, 5),
+            (start + 10, 1, 6),
+        ]);
+    }
+}
@@ -0,0 +1,443 @@
+use super::elf::*;
+use super::{utils::*, Addr, Endianness, Program};
+
+/// The i386 dynamic linker named by PT_INTERP.
+pub const INTERPRETER_X86: &str = "/lib/ld-linux.so.2";
+
+/// Length of the startup stub position independent executables run before the entry point when
+/// there's no dynamic linker. It finds the load address, applies every R_386_RELATIVE
+/// relocation and jumps to the entry point.
+pub const PIE_STUB_LEN: u64 = 42;
+
+/// Length of a PLT entry. The first entry is reserved for calling into the dynamic linker.
+pub const PLT_ENTRY_LEN: u64 = 16;
+
+/// The extra loadable segment the linker adds to position independent and dynamically linked
+/// executables and to shared libraries: the .interp path, startup stub, PLT, dynamic symbol
+/// table, relocations, .dynamic section and GOT. Its contents only depend on how many pointers,
+/// externals, exports and libraries the program has so its length is known before the program
+/// is laid out.
+pub struct DynamicSegment {
+    pie: bool,
+    shared: bool,
+    relocation_count: usize,
+    externs: Vec<String>,
+    exports: Vec<String>,
+    needed: Vec<String>,
+    soname: Option<String>,
+}
+
+impl DynamicSegment {
+    /// Describes the segment for a program being output as 'kind'.
+    pub fn new(program: &Program, kind: OutputKind) -> DynamicSegment {
+        let pie = kind == OutputKind::PositionIndependent;
+        let shared = kind == OutputKind::SharedLibrary;
+        DynamicSegment {
+            pie,
+            shared,
+            relocation_count: if pie { program.absolute_pointers().len() } else { 0 },
+            externs: program.externs.iter().map(|e| e.name.clone()).collect(),
+            exports: if shared { program.exported().into_iter().map(String::from).collect() } else { Vec::new() },
+            needed: program.needed.clone(),
+            soname: if shared { program.soname.clone() } else { None },
+        }
+    }
+
+    /// Whether the binary has dynamic symbols, i.e. it's a shared library or is linked against
+    /// one.
+    pub fn is_dynamic(&self) -> bool {
+        self.shared || !self.externs.is_empty() || !self.needed.is_empty()
+    }
+
+    /// Whether the executable is loaded by the dynamic linker.
+    pub fn has_interpreter(&self) -> bool {
+        !self.shared && self.is_dynamic()
+    }
+
+    /// Whether the binary needs the segment at all.
+    pub fn is_empty(&self) -> bool {
+        !self.pie && !self.is_dynamic()
+    }
+
+    /// Lists the names of the dynamic symbols after the null symbol: the externals followed by
+    /// the exports.
+    fn symbol_names(&self) -> Vec<&str> {
+        self.externs.iter().chain(&self.exports).map(|s| s.as_str()).collect()
+    }
+
+    /// Whether the executable relocates itself with the startup stub. The dynamic linker applies
+    /// the relocations when there is one.
+    fn has_stub(&self) -> bool {
+        self.pie && !self.is_dynamic()
+    }
+
+    /// Gets the number of R_386_RELATIVE relocations: every absolute pointer in the program and
+    /// the absolute GOT addresses in the PLT.
+    fn relative_count(&self) -> usize {
+        let plt = if self.externs.is_empty() { 0 } else { 2 + self.externs.len() };
+        self.relocation_count + plt
+    }
+
+    /// Lists the name and length of each section in layout order.
+    fn section_lens(&self) -> Vec<(&'static str, u64)> {
+        let symbol_count = 1 + self.symbol_names().len() as u64;
+        let relocation_len = ELFRelocation::len(ELFClass::X86) as u64;
+        let mut sections = Vec::new();
+
+        if self.has_interpreter() {
+            sections.push((".interp", INTERPRETER_X86.len() as u64 + 1));
+        }
+        if self.has_stub() {
+            sections.push((".text.pie", PIE_STUB_LEN));
+        }
+        if !self.externs.is_empty() {
+            sections.push((".plt", PLT_ENTRY_LEN * (1 + self.externs.len() as u64)));
+        }
+        if self.is_dynamic() {
+            sections.push((".hash", 4 * (2 + 2 * symbol_count)));
+            sections.push((".dynsym", ELFSymbol::len(ELFClass::X86) as u64 * symbol_count));
+            sections.push((".dynstr", self.dynstr().0.as_vec().len() as u64));
+        }
+        if self.pie {
+            sections.push((".rel.dyn", relocation_len * self.relative_count() as u64));
+        }
+        if !self.externs.is_empty() {
+            sections.push((".rel.plt", relocation_len * self.externs.len() as u64));
+        }
+        let dynamic_len = self.dynamic_entries(&[]).len() * ELFDynamicEntry::len(ELFClass::X86);
+        sections.push((".dynamic", dynamic_len as u64));
+        if !self.externs.is_empty() {
+            sections.push((".got.plt", 4 * (3 + self.externs.len() as u64)));
+        }
+
+        sections
+    }
+
+    /// Lays out the sections from 'start', returning each one's name, address and length.
+    /// Sections are aligned to 4 bytes.
+    pub fn layout(&self, start: Addr) -> Vec<(&'static str, Addr, u64)> {
+        let mut layout = Vec::new();
+        let mut addr = start;
+
+        for (name, len) in self.section_lens() {
+            layout.push((name, addr, len));
+            addr += len.next_multiple_of(4);
+        }
+
+        layout
+    }
+
+    /// Gets the length of the segment in bytes.
+    pub fn len(&self) -> u64 {
+        self.layout(Addr::default()).iter().map(|(_, _, len)| len.next_multiple_of(4)).sum()
+    }
+
+    /// Gets the address of the PLT entry calls to the external function 'idx' go through when
+    /// the segment is laid out from 'start'.
+    pub fn plt_entry(&self, start: Addr, idx: usize) -> Addr {
+        section_addr(&self.layout(start), ".plt") + PLT_ENTRY_LEN * (idx as u64 + 1)
+    }
+
+    /// Gets the address execution starts at: the startup stub if there is one, otherwise 'entry'.
+    pub fn entry_point(&self, start: Addr, entry: u64) -> u64 {
+        if self.has_stub() { section_addr(&self.layout(start), ".text.pie").vaddr } else { entry }
+    }
+
+    /// Builds the string table of the dynamic symbols, returning it along with the offset of
+    /// each library's name, each dynamic symbol's name and the soname.
+    fn dynstr(&self) -> (ELFStringTable, Vec<u32>, Vec<u32>, Option<u32>) {
+        let mut dynstr = ELFStringTable::new();
+        let needed = self.needed.iter().map(|library| dynstr.push(library)).collect();
+        let symbols = self.symbol_names().into_iter().map(|name| dynstr.push(name)).collect();
+        let soname = self.soname.as_ref().map(|soname| dynstr.push(soname));
+        (dynstr, needed, symbols, soname)
+    }
+
+    /// Builds the .dynamic entries from the layout. Only the number of entries is needed to
+    /// work out the segment's length so missing sections are treated as being at address 0.
+    fn dynamic_entries(&self, layout: &[(&str, Addr, u64)]) -> Vec<ELFDynamicEntry> {
+        let addr = |name| section_addr(layout, name).vaddr;
+        let len = |name| layout.iter().find(|(n, _, _)| *n == name).map(|(_, _, len)| *len).unwrap_or(0);
+        let mut entries = Vec::new();
+
+        if self.is_dynamic() {
+            let (_, needed, _, soname) = self.dynstr();
+            for offset in needed {
+                entries.push((DT_NEEDED, offset as u64));
+            }
+            if let Some(offset) = soname {
+                entries.push((DT_SONAME, offset as u64));
+            }
+            entries.push((DT_HASH, addr(".hash")));
+            entries.push((DT_STRTAB, addr(".dynstr")));
+            entries.push((DT_SYMTAB, addr(".dynsym")));
+            entries.push((DT_STRSZ, len(".dynstr")));
+            entries.push((DT_SYMENT, ELFSymbol::len(ELFClass::X86) as u64));
+        }
+
+        if !self.externs.is_empty() {
+            entries.push((DT_PLTGOT, addr(".got.plt")));
+            entries.push((DT_PLTRELSZ, len(".rel.plt")));
+            entries.push((DT_PLTREL, DT_REL));
+            entries.push((DT_JMPREL, addr(".rel.plt")));
+        }
+
+        if self.pie {
+            let relent = ELFRelocation::len(ELFClass::X86) as u64;
+            entries.push((DT_REL, addr(".rel.dyn")));
+            entries.push((DT_RELSZ, self.relative_count() as u64 * relent));
+            entries.push((DT_RELENT, relent));
+            entries.push((DT_RELCOUNT, self.relative_count() as u64));
+
+            // The relocations patch the program's own code.
+            if self.relative_count() > 0 {
+                entries.push((DT_TEXTREL, 0));
+            }
+        }
+
+        // Filled in by the dynamic linker for debuggers.
+        if self.has_interpreter() {
+            entries.push((DT_DEBUG, 0));
+        }
+        if self.pie {
+            entries.push((DT_FLAGS_1, DF_1_PIE));
+        }
+        entries.push((DT_NULL, 0));
+
+        entries.into_iter().map(|(d_tag, d_val)| ELFDynamicEntry { class: ELFClass::X86, d_tag, d_val }).collect()
+    }
+
+    /// Builds the sections laid out from 'start'. The startup stub jumps to 'entry' once the
+    /// relocations are applied.
+    pub fn build(&self, program: &Program, start: Addr, entry: u64) -> Vec<(String, ELFSection)> {
+        let endianness = Endianness::Little;
+        let layout = self.layout(start);
+        let addr = |name| section_addr(&layout, name);
+        let got = addr(".got.plt").vaddr;
+        let plt = addr(".plt").vaddr;
+
+        // Absolute addresses in the PLT, relocated along with the program's pointers.
+        let mut relative = program.absolute_pointers();
+        let mut sections = Vec::new();
+
+        for (name, addr, len) in &layout {
+            let (sh_type, sh_flags, sh_entsize, data) = match *name {
+                ".interp" => {
+                    let mut data = INTERPRETER_X86.as_bytes().to_vec();
+                    data.push(0);
+                    (ELFSectionType::ProgramBits, SHF_ALLOC, 0, data)
+                }
+                ".text.pie" => {
+                    let count = self.relative_count() as u32;
+                    (ELFSectionType::ProgramBits, SHF_ALLOC | SHF_EXECINSTR, 0, stub(*addr, section_addr(&layout, ".rel.dyn"), count, entry))
+                }
+                ".plt" => {
+                    // plt0: push dword [got + 4] ; jmp dword [got + 8]
+                    let mut data = vec![0xFF, 0x35];
+                    data.extend_from_slice(&dump_dword(got as u32 + 4, endianness));
+                    data.extend_from_slice(&[0xFF, 0x25]);
+                    data.extend_from_slice(&dump_dword(got as u32 + 8, endianness));
+                    data.extend_from_slice(&[0x90; 4]);
+                    relative.push(*addr + 2u64);
+                    relative.push(*addr + 8u64);
+
+                    // pltN: jmp dword [got + 12 + 4N] ; push N * 8 ; jmp plt0
+                    for idx in 0..self.externs.len() {
+                        let entry = *addr + PLT_ENTRY_LEN * (idx as u64 + 1);
+                        data.extend_from_slice(&[0xFF, 0x25]);
+                        data.extend_from_slice(&dump_dword((got + 12 + 4 * idx as u64) as u32, endianness));
+                        data.push(0x68);
+                        data.extend_from_slice(&dump_dword((idx * ELFRelocation::len(ELFClass::X86)) as u32, endianness));
+                        data.push(0xE9);
+                        data.extend_from_slice(&dump_dword(plt.wrapping_sub(entry.vaddr + PLT_ENTRY_LEN) as u32, endianness));
+                        relative.push(entry + 2u64);
+                    }
+                    (ELFSectionType::ProgramBits, SHF_ALLOC | SHF_EXECINSTR, 0, data)
+                }
+                ".hash" => (ELFSectionType::Hash, SHF_ALLOC, 4, hash_table(&self.symbol_names(), endianness)),
+                ".dynsym" => {
+                    let (_, _, names, _) = self.dynstr();
+                    let mut data = ELFSymbol::null(ELFClass::X86).as_vec(endianness);
+                    for (st_name, name) in names.into_iter().zip(self.symbol_names()) {
+                        // Externals are undefined, exports are defined in the section covering
+                        // their segment. Segment sections follow the null section. Both are typed
+                        // as functions so linkers call them through the PLT rather than copying
+                        // them into the executable.
+                        let symbol = match program.labels().into_iter().find(|(label, _, _)| *label == name) {
+                            Some((_, addr, len)) if self.exports.iter().any(|e| e == name) => ELFSymbol {
+                                class: ELFClass::X86,
+                                st_name,
+                                st_value: addr.vaddr,
+                                st_size: len as u64,
+                                binding: program.binding(name).into(),
+                                st_type: ELFSymbolType::Function,
+                                visibility: ELFSymbolVisibility::Default,
+                                st_shndx: 1 + program.segment_of(name).unwrap_or(0) as u16,
+                            },
+                            _ => ELFSymbol {
+                                class: ELFClass::X86,
+                                st_name,
+                                st_value: 0,
+                                st_size: 0,
+                                binding: program.binding(name).into(),
+                                st_type: ELFSymbolType::Function,
+                                visibility: ELFSymbolVisibility::Default,
+                                st_shndx: 0,
+                            },
+                        };
+                        data.extend_from_slice(&symbol.as_vec(endianness));
+                    }
+                    (ELFSectionType::DynamicSymbolTable, SHF_ALLOC, ELFSymbol::len(ELFClass::X86) as u64, data)
+                }
+                ".dynstr" => (ELFSectionType::StringTable, SHF_ALLOC, 0, self.dynstr().0.as_vec()),
+                ".rel.dyn" => {
+                    let mut data = Vec::new();
+                    for pointer in &relative {
+                        let relocation = ELFRelocation { class: ELFClass::X86, r_offset: pointer.vaddr, r_sym: 0, r_type: R_386_RELATIVE };
+                        data.extend_from_slice(&relocation.as_vec(endianness));
+                    }
+                    (ELFSectionType::Rel, SHF_ALLOC, ELFRelocation::len(ELFClass::X86) as u64, data)
+                }
+                ".rel.plt" => {
+                    let mut data = Vec::new();
+                    for idx in 0..self.externs.len() {
+                        let relocation = ELFRelocation {
+                            class: ELFClass::X86,
+                            r_offset: got + 12 + 4 * idx as u64,
+                            r_sym: idx as u32 + 1,
+                            r_type: R_386_JMP_SLOT,
+                        };
+                        data.extend_from_slice(&relocation.as_vec(endianness));
+                    }
+                    (ELFSectionType::Rel, SHF_ALLOC, ELFRelocation::len(ELFClass::X86) as u64, data)
+                }
+                ".dynamic" => {
+                    let mut data = Vec::new();
+                    for entry in self.dynamic_entries(&layout) {
+                        data.extend_from_slice(&entry.as_vec(endianness));
+                    }
+                    (ELFSectionType::Dynamic, SHF_ALLOC | SHF_WRITE, ELFDynamicEntry::len(ELFClass::X86) as u64, data)
+                }
+                ".got.plt" => {
+                    // The first entries are the address of .dynamic and two reserved for the
+                    // dynamic linker. Each function's entry starts out pointing back into its PLT
+                    // entry so the first call goes through the dynamic linker.
+                    let mut data = dump_dword(section_addr(&layout, ".dynamic").vaddr as u32, endianness).to_vec();
+                    data.extend_from_slice(&[0; 8]);
+                    for idx in 0..self.externs.len() {
+                        let entry = plt + PLT_ENTRY_LEN * (idx as u64 + 1);
+                        data.extend_from_slice(&dump_dword(entry as u32 + 6, endianness));
+                    }
+                    (ELFSectionType::ProgramBits, SHF_ALLOC | SHF_WRITE, 4, data)
+                }
+                _ => unreachable!(),
+            };
+            debug_assert_eq!(data.len() as u64, *len);
+
+            // The name is filled in when the section header table is built.
+            let mut header = ELFSectionHeader::new(ELFClass::X86, 0, sh_type);
+            header.sh_flags = sh_flags;
+            header.sh_addr = addr.vaddr;
+            header.sh_offset = addr.addr;
+            header.sh_size = data.len() as u64;
+            header.sh_addralign = 4;
+            header.sh_entsize = sh_entsize;
+            sections.push((name.to_string(), ELFSection { header, data }));
+        }
+
+        sections
+    }
+}
+
+/// Looks up the address of a section in a layout.
+fn section_addr(layout: &[(&str, Addr, u64)], name: &str) -> Addr {
+    layout.iter().find(|(n, _, _)| *n == name).map(|(_, addr, _)| *addr).unwrap_or_default()
+}
+
+/// Builds the startup stub laid out at 'start'. It applies the 'count' relocations at 'rel' and
+/// then jumps to 'entry'.
+fn stub(start: Addr, rel: Addr, count: u32, entry: u64) -> Vec<u8> {
+    let endianness = Endianness::Little;
+
+    // The address after the call, popped into ebx to find how far the binary was moved.
+    let anchor = start.vaddr + 6;
+    let mut stub = Vec::new();
+    stub.push(0x60);                                                              // pushad
+    stub.extend_from_slice(&[0xE8, 0x00, 0x00, 0x00, 0x00]);                      // call anchor
+    stub.push(0x5B);                                                              // anchor: pop ebx
+    stub.extend_from_slice(&[0x81, 0xEB]);                                        // sub ebx, anchor
+    stub.extend_from_slice(&dump_dword(anchor as u32, endianness));
+    stub.extend_from_slice(&[0x8D, 0xB3]);                                        // lea esi, [ebx + rel]
+    stub.extend_from_slice(&dump_dword(rel.vaddr as u32, endianness));
+    stub.push(0xB9);                                                              // mov ecx, count
+    stub.extend_from_slice(&dump_dword(count, endianness));
+    stub.extend_from_slice(&[0xE3, 0x0A]);                                        // jecxz done
+    stub.extend_from_slice(&[0x8B, 0x06]);                                        // next: mov eax, [esi]
+    stub.extend_from_slice(&[0x01, 0x1C, 0x18]);                                  // add [eax + ebx], ebx
+    stub.extend_from_slice(&[0x83, 0xC6, 0x08]);                                  // add esi, 8
+    stub.extend_from_slice(&[0xE2, 0xF6]);                                        // loop next
+    stub.push(0x61);                                                              // done: popad
+    stub.push(0xE9);                                                              // jmp entry
+    let delta = entry.wrapping_sub(start.vaddr + PIE_STUB_LEN) as u32;
+    stub.extend_from_slice(&dump_dword(delta, endianness));
+    debug_assert_eq!(stub.len() as u64, PIE_STUB_LEN);
+
+    stub
+}
+
+/// The System V ELF hash function used by .hash.
+pub fn elf_hash(name: &str) -> u32 {
+    let mut h: u32 = 0;
+    for b in name.bytes() {
+        h = (h << 4).wrapping_add(b as u32);
+        let g = h & 0xF0000000;
+        if g != 0 { h ^= g >> 24; }
+        h &= !g;
+    }
+    h
+}
+
+/// Builds a System V hash table (.hash) for the dynamic symbols named 'names', which follow the
+/// null symbol. Every bucket holds the most recent symbol hashed to it, the chain links back to
+/// the others.
+pub fn hash_table(names: &[&str], endianness: Endianness) -> Vec<u8> {
+    let symbol_count = names.len() + 1;
+    let bucket_count = symbol_count;
+    let mut buckets = vec![0u32; bucket_count];
+    let mut chains = vec![0u32; symbol_count];
+
+    for (idx, name) in names.iter().enumerate() {
+        let bucket = elf_hash(name) as usize % bucket_count;
+        chains[idx + 1] = buckets[bucket];
+        buckets[bucket] = idx as u32 + 1;
+    }
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&dump_dword(bucket_count as u32, endianness));
+    data.extend_from_slice(&dump_dword(symbol_count as u32, endianness));
+    for x in buckets.into_iter().chain(chains) {
+        data.extend_from_slice(&dump_dword(x, endianness));
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash() {
+        assert_eq!(elf_hash(""), 0);
+        assert_eq!(elf_hash("printf"), 0x077905A6);
+        assert_eq!(elf_hash("exit"), 0x0006CF04);
+
+        assert_eq!(hash_table(&["printf"], Endianness::Little), vec![
+            0x02, 0x00, 0x00, 0x00, // nbucket
+            0x02, 0x00, 0x00, 0x00, // nchain
+            0x01, 0x00, 0x00, 0x00, // bucket[0]: printf
+            0x00, 0x00, 0x00, 0x00, // bucket[1]
+            0x00, 0x00, 0x00, 0x00, // chain[0]
+            0x00, 0x00, 0x00, 0x00, // chain[1]
+        ]);
+    }
+}
@@ -1,4 +1,5 @@
-use super::{Endianness, utils::*, Program, Addr};
+use super::{Endianness, utils::*, Program, Addr, SymbolBinding, SymbolVisibility, dwarf::DebugInfo, dynamic::DynamicSegment};
+use std::collections::HashMap;
 use std::fs::File;
 use std::path::Path;
 use std::io::Write;
@@ -18,6 +19,17 @@ pub enum ELFType {
      Core
 }
 
+/// The kind of binary to produce.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OutputKind {
+    /// An executable loaded at a fixed address.
+    Executable,
+    /// A position independent executable (ET_DYN) that relocates itself wherever it is loaded.
+    PositionIndependent,
+    /// A shared library (ET_DYN) exporting labels through its dynamic symbol table.
+    SharedLibrary,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ELFProgramHeaderType {
     Null,
@@ -29,6 +41,60 @@ pub enum ELFProgramHeaderType {
     TLS,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ELFSectionType {
+    Null,
+    ProgramBits,
+    SymbolTable,
+    StringTable,
+    Hash,
+    Dynamic,
+    NoBits,
+    Rel,
+    DynamicSymbolTable,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ELFSymbolBinding {
+    Local,
+    Global,
+    Weak,
+}
+
+impl From<SymbolBinding> for ELFSymbolBinding {
+    fn from(binding: SymbolBinding) -> ELFSymbolBinding {
+        match binding {
+            SymbolBinding::Local => ELFSymbolBinding::Local,
+            SymbolBinding::Global => ELFSymbolBinding::Global,
+            SymbolBinding::Weak => ELFSymbolBinding::Weak,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ELFSymbolVisibility {
+    Default,
+    Hidden,
+}
+
+impl From<SymbolVisibility> for ELFSymbolVisibility {
+    fn from(visibility: SymbolVisibility) -> ELFSymbolVisibility {
+        match visibility {
+            SymbolVisibility::Default => ELFSymbolVisibility::Default,
+            SymbolVisibility::Hidden => ELFSymbolVisibility::Hidden,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ELFSymbolType {
+    NoType,
+    Object,
+    Function,
+    Section,
+    File,
+}
+
 pub struct ELFHeader {
     pub class: ELFClass, 
     pub endianness: Endianness,
@@ -36,6 +102,10 @@ pub struct ELFHeader {
     pub instruction_set: u16,
     pub entry_point: u64,
     pub program_table: u64,
+    pub program_count: u16,
+    pub section_table: u64,
+    pub section_count: u16,
+    pub section_names: u16,
 }
 
 pub struct ELFProgramHeader {
@@ -44,15 +114,146 @@ pub struct ELFProgramHeader {
     pub p_offset: u64,
     pub p_vaddr: u64,
     pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_flags: u32,
+    pub p_align: u64,
+}
+
+#[derive(Clone)]
+pub struct ELFSectionHeader {
+    pub class: ELFClass,
+    pub sh_name: u32,
+    pub sh_type: ELFSectionType,
+    pub sh_flags: u64,
+    pub sh_addr: u64,
+    pub sh_offset: u64,
+    pub sh_size: u64,
+    pub sh_link: u32,
+    pub sh_info: u32,
+    pub sh_addralign: u64,
+    pub sh_entsize: u64,
+}
+
+/// A section header along with the bytes it describes. Sections that cover the program itself
+/// (e.g. .text) leave `data` empty since the program is written separately.
+#[derive(Clone)]
+pub struct ELFSection {
+    pub header: ELFSectionHeader,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ELFSymbol {
+    pub class: ELFClass,
+    pub st_name: u32,
+    pub st_value: u64,
+    pub st_size: u64,
+    pub binding: ELFSymbolBinding,
+    pub st_type: ELFSymbolType,
+    pub visibility: ELFSymbolVisibility,
+    pub st_shndx: u16,
+}
+
+/// An entry in a relocation table without explicit addends (SHT_REL). The addend is the value
+/// already stored at `r_offset`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ELFRelocation {
+    pub class: ELFClass,
+    pub r_offset: u64,
+    pub r_sym: u32,
+    pub r_type: u32,
+}
+
+/// An entry in the .dynamic section.
+pub struct ELFDynamicEntry {
+    pub class: ELFClass,
+    pub d_tag: u64,
+    pub d_val: u64,
+}
+
+/// A string table (.strtab, .shstrtab) of null terminated strings referenced by offset.
+pub struct ELFStringTable {
+    data: Vec<u8>,
 }
 
 pub struct ELF {
     pub class: ELFClass,
     pub header: ELFHeader,
-    pub program_header: ELFProgramHeader,
+    pub program_headers: Vec<ELFProgramHeader>,
+    pub sections: Vec<ELFSection>,
+    /// Loaded sections made by the linker rather than assembled from the program, e.g. the
+    /// relocation table of a position independent executable.
+    pub loaded_sections: Vec<(String, ELFSection)>,
+    pub debug_info: Option<DebugInfo>,
     pub program: Program,
 }
 
+/// Loadable segments are aligned to pages, a segment's address must be congruent to its file
+/// offset modulo the page size.
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// The traditional System V load address for i386 executables.
+pub const DEFAULT_BASE_X86: u64 = 0x08048000;
+
+/// Memory reserved past the end of the highest segment. Programs use it as scratch space, e.g.
+/// buffers at fixed addresses.
+pub const SCRATCH_MEMORY: u64 = 0x40000000;
+
+/// Section header flags
+pub const SHF_WRITE: u64 = 0x1;
+pub const SHF_ALLOC: u64 = 0x2;
+pub const SHF_EXECINSTR: u64 = 0x4;
+
+/// Program header flags
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+/// Special section indices
+pub const SHN_UNDEF: u16 = 0;
+pub const SHN_ABS: u16 = 0xFFF1;
+pub const SHN_COMMON: u16 = 0xFFF2;
+
+/// Relocation types
+pub const R_386_32: u32 = 1;
+pub const R_386_PC32: u32 = 2;
+pub const R_386_PLT32: u32 = 4;
+pub const R_386_JMP_SLOT: u32 = 7;
+pub const R_386_RELATIVE: u32 = 8;
+
+/// Dynamic section tags
+pub const DT_NULL: u64 = 0;
+pub const DT_NEEDED: u64 = 1;
+pub const DT_PLTRELSZ: u64 = 2;
+pub const DT_PLTGOT: u64 = 3;
+pub const DT_HASH: u64 = 4;
+pub const DT_STRTAB: u64 = 5;
+pub const DT_SYMTAB: u64 = 6;
+pub const DT_STRSZ: u64 = 10;
+pub const DT_SYMENT: u64 = 11;
+pub const DT_SONAME: u64 = 14;
+pub const DT_REL: u64 = 17;
+pub const DT_RELSZ: u64 = 18;
+pub const DT_RELENT: u64 = 19;
+pub const DT_PLTREL: u64 = 20;
+pub const DT_DEBUG: u64 = 21;
+pub const DT_TEXTREL: u64 = 22;
+pub const DT_JMPREL: u64 = 23;
+pub const DT_RELCOUNT: u64 = 0x6FFFFFFA;
+pub const DT_FLAGS_1: u64 = 0x6FFFFFFB;
+
+/// DT_FLAGS_1 flag marking a position independent executable.
+pub const DF_1_PIE: u64 = 0x08000000;
+
+/// Dumps an address, offset or size field, which is either 32 or 64 bits long depending on the
+/// class.
+fn dump_addr(class: ELFClass, x: u64, endianness: Endianness) -> Vec<u8> {
+    match class {
+        ELFClass::X86 => dump_dword(x as u32, endianness).to_vec(),
+        ELFClass::X86_64 => dump_qword(x, endianness).to_vec(),
+    }
+}
+
 impl ELFHeader {
     pub fn new_x86(entry_point: u32) -> ELFHeader {
         ELFHeader {
@@ -62,6 +263,10 @@ impl ELFHeader {
             instruction_set: 0x03, // x86
             entry_point: entry_point as u64,
             program_table: 0x34,
+            program_count: 1,
+            section_table: 0,
+            section_count: 0,
+            section_names: 0,
         }
     }
 
@@ -153,15 +358,7 @@ impl ELFHeader {
         }
 
         // e_shoff: Memory address to the start of the section header table. 
-        // TODO: Something other than 0.
-        match self.class {
-            ELFClass::X86 => {
-                for _ in 0..4 { dump.push(0); }
-            }
-            ELFClass::X86_64 => {
-                for _ in 0..8 { dump.push(0); }
-            }
-        }
+        dump.extend_from_slice(&dump_addr(self.class, self.section_table, self.endianness));
 
         // e_flags: Unsure of this field, it depends on target architcture. TODO: Research this.
         for _ in 0..4 { dump.push(0); }
@@ -177,8 +374,8 @@ impl ELFHeader {
         }, self.endianness);
         dump.push(bytes[0]); dump.push(bytes[1]);
 
-        // e_phnum: Number of entries in the program header table. We set this to 1.
-        let bytes = dump_word(1, self.endianness);
+        // e_phnum: Number of entries in the program header table.
+        let bytes = dump_word(self.program_count, self.endianness);
         dump.push(bytes[0]); dump.push(bytes[1]);
 
         // e_shentsize: Contains the size of the section header table entry.
@@ -188,13 +385,13 @@ impl ELFHeader {
         }, self.endianness);
         dump.push(bytes[0]); dump.push(bytes[1]);
 
-        // e_shnum: Number of entries in the section header table. We set this to 0.
-        let bytes = dump_word(0, self.endianness);
+        // e_shnum: Number of entries in the section header table.
+        let bytes = dump_word(self.section_count, self.endianness);
         dump.push(bytes[0]); dump.push(bytes[1]);
 
-        // e_shstrndx: Contains the index of the section header table etnry that contains the
-        // section names. We temporarily set this to 0.
-        let bytes = dump_word(0, self.endianness);
+        // e_shstrndx: Contains the index of the section header table entry that contains the
+        // section names.
+        let bytes = dump_word(self.section_names, self.endianness);
         dump.push(bytes[0]); dump.push(bytes[1]);
 
         dump
@@ -202,6 +399,20 @@ impl ELFHeader {
 }
 
 impl ELFProgramHeader {
+    /// Constructs an empty program header of the given type.
+    pub fn new(class: ELFClass, p_type: ELFProgramHeaderType) -> ELFProgramHeader {
+        ELFProgramHeader {
+            class,
+            p_type,
+            p_offset: 0,
+            p_vaddr: 0,
+            p_filesz: 0,
+            p_memsz: 0,
+            p_flags: PF_R | PF_W | PF_X,
+            p_align: PAGE_SIZE,
+        }
+    }
+
     pub fn len(&self) -> usize {
         match self.class {
             ELFClass::X86 => 0x20,
@@ -224,9 +435,9 @@ impl ELFProgramHeader {
         }, endianness);
         for i in 0..4 { dump.push(bytes[i]); }
 
-        // p_flags: 0x07 = exectuable + readable + readable. (64-bit only).
+        // p_flags: PF_R, PF_W and PF_X permissions of the segment. (64-bit only).
         if self.class == ELFClass::X86_64 {
-            let bytes = dump_dword(0x07, endianness);
+            let bytes = dump_dword(self.p_flags, endianness);
             for i in 0..4 { dump.push(bytes[i]); }
         }
 
@@ -272,33 +483,154 @@ impl ELFProgramHeader {
             }
         }
 
-        // p_memsz: size in bytes of the segment in memory. we set p_memsz = p_filesz
-        match self.class {
-            ELFClass::X86 => {
-                let bytes = dump_dword(0x40000000, endianness);
-                for i in 0..4 { dump.push(bytes[i]); }
-            }
-            ELFClass::X86_64 => {
-                let bytes = dump_qword(self.p_filesz, endianness);
-                for i in 0..8 { dump.push(bytes[i]); }
-            }
-        }
+        // p_memsz: size in bytes of the segment in memory.
+        dump.extend_from_slice(&dump_addr(self.class, self.p_memsz, endianness));
 
-        // p_flags: 0x05 = exectuable + writeable + readable. (32-bit only).
+        // p_flags: PF_R, PF_W and PF_X permissions of the segment. (32-bit only).
         if self.class == ELFClass::X86 {
-            let bytes = dump_dword(0x07, endianness);
+            let bytes = dump_dword(self.p_flags, endianness);
             for i in 0..4 { dump.push(bytes[i]); }
         }
 
-        // p_align: Unsure of this, source sets it to 0x1000. (TODO: figure this out)
+        // p_align: loadable segments are aligned to the page size, p_vaddr = p_offset mod p_align.
+        dump.extend_from_slice(&dump_addr(self.class, self.p_align, endianness));
+
+        dump
+    }
+}
+
+impl ELFSectionHeader {
+    /// Constructs an empty section header of the given type.
+    pub fn new(class: ELFClass, sh_name: u32, sh_type: ELFSectionType) -> ELFSectionHeader {
+        ELFSectionHeader {
+            class,
+            sh_name,
+            sh_type,
+            sh_flags: 0,
+            sh_addr: 0,
+            sh_offset: 0,
+            sh_size: 0,
+            sh_link: 0,
+            sh_info: 0,
+            sh_addralign: 0,
+            sh_entsize: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.class {
+            ELFClass::X86 => 0x28,
+            ELFClass::X86_64 => 0x40,
+        }
+    }
+
+    pub fn as_vec(&self, endianness: Endianness) -> Vec<u8> {
+        let mut dump = Vec::new();
+
+        // sh_name: offset to the section's name in .shstrtab.
+        dump.extend_from_slice(&dump_dword(self.sh_name, endianness));
+
+        // sh_type: Identifies the type of the section.
+        dump.extend_from_slice(&dump_dword(match self.sh_type {
+            ELFSectionType::Null => 0,
+            ELFSectionType::ProgramBits => 1,
+            ELFSectionType::SymbolTable => 2,
+            ELFSectionType::StringTable => 3,
+            ELFSectionType::Hash => 5,
+            ELFSectionType::Dynamic => 6,
+            ELFSectionType::NoBits => 8,
+            ELFSectionType::Rel => 9,
+            ELFSectionType::DynamicSymbolTable => 11,
+        }, endianness));
+
+        // sh_flags: attributes of the section (SHF_WRITE, SHF_ALLOC, SHF_EXECINSTR).
+        dump.extend_from_slice(&dump_addr(self.class, self.sh_flags, endianness));
+
+        // sh_addr: virtual address of the section in memory, for sections that are loaded.
+        dump.extend_from_slice(&dump_addr(self.class, self.sh_addr, endianness));
+
+        // sh_offset: offset of the section in the file image.
+        dump.extend_from_slice(&dump_addr(self.class, self.sh_offset, endianness));
+
+        // sh_size: size in bytes of the section in the file image.
+        dump.extend_from_slice(&dump_addr(self.class, self.sh_size, endianness));
+
+        // sh_link: section index of an associated section (e.g. .strtab for .symtab).
+        dump.extend_from_slice(&dump_dword(self.sh_link, endianness));
+
+        // sh_info: extra information, for .symtab this is one past the last local symbol.
+        dump.extend_from_slice(&dump_dword(self.sh_info, endianness));
+
+        // sh_addralign: required alignment of the section.
+        dump.extend_from_slice(&dump_addr(self.class, self.sh_addralign, endianness));
+
+        // sh_entsize: size of each entry for sections with fixed-size entries.
+        dump.extend_from_slice(&dump_addr(self.class, self.sh_entsize, endianness));
+
+        dump
+    }
+}
+
+impl ELFSymbol {
+    pub fn len(class: ELFClass) -> usize {
+        match class {
+            ELFClass::X86 => 0x10,
+            ELFClass::X86_64 => 0x18,
+        }
+    }
+
+    /// The null symbol every symbol table starts with.
+    pub fn null(class: ELFClass) -> ELFSymbol {
+        ELFSymbol {
+            class,
+            st_name: 0,
+            st_value: 0,
+            st_size: 0,
+            binding: ELFSymbolBinding::Local,
+            st_type: ELFSymbolType::NoType,
+            visibility: ELFSymbolVisibility::Default,
+            st_shndx: 0,
+        }
+    }
+
+    pub fn as_vec(&self, endianness: Endianness) -> Vec<u8> {
+        let mut dump = Vec::new();
+
+        // st_info: binding in the high nibble, type in the low nibble.
+        let binding = match self.binding {
+            ELFSymbolBinding::Local => 0,
+            ELFSymbolBinding::Global => 1,
+            ELFSymbolBinding::Weak => 2,
+        };
+        let st_type = match self.st_type {
+            ELFSymbolType::NoType => 0,
+            ELFSymbolType::Object => 1,
+            ELFSymbolType::Function => 2,
+            ELFSymbolType::Section => 3,
+            ELFSymbolType::File => 4,
+        };
+        let info = (binding << 4) | st_type;
+        let other = match self.visibility {
+            ELFSymbolVisibility::Default => 0,
+            ELFSymbolVisibility::Hidden => 2,
+        };
+
+        // The fields are ordered differently between the 32 and 64 bit formats.
+        dump.extend_from_slice(&dump_dword(self.st_name, endianness));
         match self.class {
             ELFClass::X86 => {
-                let bytes = dump_dword(0x1000, endianness);
-                for i in 0..4 { dump.push(bytes[i]); }
+                dump.extend_from_slice(&dump_dword(self.st_value as u32, endianness));
+                dump.extend_from_slice(&dump_dword(self.st_size as u32, endianness));
+                dump.push(info);
+                dump.push(other); // st_other
+                dump.extend_from_slice(&dump_word(self.st_shndx, endianness));
             }
             ELFClass::X86_64 => {
-                let bytes = dump_qword(0x1000, endianness);
-                for i in 0..8 { dump.push(bytes[i]); }
+                dump.push(info);
+                dump.push(other); // st_other
+                dump.extend_from_slice(&dump_word(self.st_shndx, endianness));
+                dump.extend_from_slice(&dump_qword(self.st_value, endianness));
+                dump.extend_from_slice(&dump_qword(self.st_size, endianness));
             }
         }
 
@@ -306,40 +638,422 @@ impl ELFProgramHeader {
     }
 }
 
+impl ELFRelocation {
+    pub fn len(class: ELFClass) -> usize {
+        match class {
+            ELFClass::X86 => 0x08,
+            ELFClass::X86_64 => 0x10,
+        }
+    }
+
+    pub fn as_vec(&self, endianness: Endianness) -> Vec<u8> {
+        let mut dump = Vec::new();
+
+        // r_offset: address of the field to relocate.
+        dump.extend_from_slice(&dump_addr(self.class, self.r_offset, endianness));
+
+        // r_info: symbol index and relocation type.
+        let info = match self.class {
+            ELFClass::X86 => ((self.r_sym as u64) << 8) | (self.r_type as u64 & 0xFF),
+            ELFClass::X86_64 => ((self.r_sym as u64) << 32) | self.r_type as u64,
+        };
+        dump.extend_from_slice(&dump_addr(self.class, info, endianness));
+
+        dump
+    }
+}
+
+impl ELFDynamicEntry {
+    pub fn len(class: ELFClass) -> usize {
+        match class {
+            ELFClass::X86 => 0x08,
+            ELFClass::X86_64 => 0x10,
+        }
+    }
+
+    pub fn as_vec(&self, endianness: Endianness) -> Vec<u8> {
+        let mut dump = Vec::new();
+        dump.extend_from_slice(&dump_addr(self.class, self.d_tag, endianness));
+        dump.extend_from_slice(&dump_addr(self.class, self.d_val, endianness));
+        dump
+    }
+}
+
+impl ELFStringTable {
+    /// Constructs a string table containing only the leading empty string.
+    pub fn new() -> ELFStringTable {
+        ELFStringTable { data: vec![0] }
+    }
+
+    /// Adds a string to the table returning its offset.
+    pub fn push(&mut self, s: &str) -> u32 {
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(s.as_bytes());
+        self.data.push(0);
+        offset
+    }
+
+    pub fn as_vec(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+}
+
 impl ELF {
-    pub fn new_x86(program: Program) -> ELF {
-        let mut offset = Addr { addr: 0, vaddr: 0x08048000 }; // TODO: Figure out what this address is.
+    /// Lays out the program as an i386 executable. Each segment becomes a loadable segment,
+    /// either at the address requested by the layout or on the page after the previous segment.
+    /// The file is padded so that every segment's file offset is congruent to its address modulo
+    /// the page size.
+    ///
+    /// Position independent and dynamically linked executables and shared libraries get an
+    /// extra segment made by the linker (see `DynamicSegment`). Position independent executables
+    /// are laid out from address 0 and, unless the dynamic linker is loaded, relocate themselves
+    /// with a startup stub. Shared libraries are also laid out from address 0 but must be
+    /// position independent code since they have no relocations.
+    pub fn new_x86(mut program: Program, kind: OutputKind) -> Result<ELF, String> {
+        let base = program.base.unwrap_or(if kind == OutputKind::Executable { DEFAULT_BASE_X86 } else { 0 });
+        if base % PAGE_SIZE != 0 {
+            return Err(format!("load address 0x{:08X} is not aligned to the page size (0x{:X}).", base, PAGE_SIZE));
+        }
+        if kind == OutputKind::SharedLibrary {
+            Self::check_shared_library(&program)?;
+        }
+
+        // Each segment's name, length, requested address and the alignment of its contents.
+        let mut segments: Vec<(String, u64, Option<u64>, u64)> = program.segments().iter().enumerate()
+            .map(|(idx, segment)| (segment.name.clone(), program.segment_len(idx) as u64, segment.vaddr, segment.align))
+            .collect();
+        let dynamic = DynamicSegment::new(&program, kind);
+        if !dynamic.is_empty() {
+            segments.push(("dynamic".to_string(), dynamic.len(), None, 4));
+        }
+
+        // Besides the loadable segments there's PT_DYNAMIC, and PT_PHDR and PT_INTERP when the
+        // dynamic linker is used.
         let mut header = ELFHeader::new_x86(0);
-        let mut program_header = ELFProgramHeader {
-            class: ELFClass::X86,
-            p_type: ELFProgramHeaderType::Loadable,
-            p_offset: 0,
-            p_vaddr: offset.vaddr,
-            p_filesz: program.len() as u64,
+        if kind != OutputKind::Executable { header.elftype = ELFType::SharedObject; }
+        let extra_headers = if dynamic.has_interpreter() { 3 } else if dynamic.is_empty() { 0 } else { 1 };
+        let segment_count = segments.iter().filter(|(_, len, _, _)| *len > 0).count() + extra_headers;
+        let program_header_len = ELFProgramHeader::new(ELFClass::X86, ELFProgramHeaderType::Loadable).len() as u64;
+        let mut offset = header.len() as u64 + segment_count as u64 * program_header_len;
+
+        // Empty segments are given an address for any labels in them but aren't loaded.
+        let mut loaded: Vec<(&str, ELFProgramHeader)> = Vec::new();
+        let mut starts = Vec::new();
+        for (name, len, requested, align) in &segments {
+            let len = *len;
+            offset = offset.next_multiple_of(*align);
+            let vaddr = match (requested, loaded.last()) {
+                (Some(vaddr), _) if vaddr % align != 0 => {
+                    return Err(format!("segment '{}' is placed at 0x{:08X} which isn't aligned to {} bytes.", name, vaddr, align));
+                }
+                (Some(vaddr), _) => {
+                    // Pad the file until the offset is congruent with the requested address.
+                    offset += ((vaddr % PAGE_SIZE) + PAGE_SIZE - (offset % PAGE_SIZE)) % PAGE_SIZE;
+                    *vaddr
+                }
+                (None, None) => base + offset,
+                (None, Some((_, prev))) => (prev.p_vaddr + prev.p_memsz).next_multiple_of(PAGE_SIZE) + offset % PAGE_SIZE,
+            };
+            starts.push(Addr { addr: offset, vaddr });
+            if len == 0 { continue; }
+
+            for (prev_name, prev) in &loaded {
+                if vaddr < prev.p_vaddr + prev.p_memsz && prev.p_vaddr < vaddr + len {
+                    return Err(format!("segment '{}' (0x{:08X}-0x{:08X}) overlaps segment '{}' (0x{:08X}-0x{:08X}).",
+                        name, vaddr, vaddr + len, prev_name, prev.p_vaddr, prev.p_vaddr + prev.p_memsz));
+                }
+            }
+
+            let mut program_header = ELFProgramHeader::new(ELFClass::X86, ELFProgramHeaderType::Loadable);
+            program_header.p_offset = offset;
+            program_header.p_vaddr = vaddr;
+            program_header.p_filesz = len;
+            program_header.p_memsz = len;
+            debug_assert_eq!(program_header.p_offset % PAGE_SIZE, program_header.p_vaddr % PAGE_SIZE);
+            loaded.push((name, program_header));
+            offset += len;
+        }
+
+        let mut program_headers: Vec<ELFProgramHeader> = loaded.into_iter().map(|(_, p)| p).collect();
+        for (idx, start) in starts.iter().take(program.segments().len()).enumerate() {
+            program.set_segment_start(idx, *start);
+        }
+
+        // Shared libraries only take the memory they need.
+        if kind != OutputKind::SharedLibrary {
+            if let Some(last) = program_headers.iter_mut().max_by_key(|p| p.p_vaddr) {
+                last.p_memsz = last.p_memsz.max(SCRATCH_MEMORY);
+            }
+        }
+
+        // Shared libraries have no entry point unless one was asked for.
+        header.entry_point = match program.entry_point() {
+            _ if kind == OutputKind::SharedLibrary && program.entry == "__entry_point__" => 0,
+            Some(addr) => addr.vaddr,
+            None => return Err(format!("unknown entry point '{}'.", program.entry)),
         };
 
-        offset += header.len() as u64 + program_header.len() as u64;
-        header.entry_point = offset.vaddr + program.entry_point.addr; 
-        program_header.p_offset = header.len() as u64 + program_header.len() as u64;
-        program_header.p_vaddr += header.len() as u64 + program_header.len() as u64;
+        let mut loaded_sections = Vec::new();
+        if !dynamic.is_empty() {
+            let start = *starts.last().unwrap();
+            for (idx, external) in program.externs.iter_mut().enumerate() {
+                external.addr = dynamic.plt_entry(start, idx);
+            }
+
+            loaded_sections = dynamic.build(&program, start, header.entry_point);
+            header.entry_point = dynamic.entry_point(start, header.entry_point);
+
+            let (_, section) = loaded_sections.iter().find(|(name, _)| name == ".dynamic").unwrap();
+            let mut program_header = ELFProgramHeader::new(ELFClass::X86, ELFProgramHeaderType::Dynamic);
+            program_header.p_offset = section.header.sh_offset;
+            program_header.p_vaddr = section.header.sh_addr;
+            program_header.p_filesz = section.header.sh_size;
+            program_header.p_memsz = section.header.sh_size;
+            program_header.p_flags = PF_R | PF_W;
+            program_header.p_align = 4;
+            program_headers.push(program_header);
+        }
+
+        if dynamic.is_dynamic() {
+            // The dynamic linker finds the program headers through PT_PHDR, so they must be
+            // loaded. Extend the first segment back to the start of the file to cover them.
+            let first = &mut program_headers[0];
+            if first.p_vaddr < first.p_offset {
+                return Err(format!("segment at 0x{:08X} is too low to load the program headers.", first.p_vaddr));
+            }
+            first.p_vaddr -= first.p_offset;
+            first.p_filesz += first.p_offset;
+            first.p_memsz += first.p_offset;
+            first.p_offset = 0;
+        }
+
+        if dynamic.has_interpreter() {
+            let first = &program_headers[0];
+            let mut phdr = ELFProgramHeader::new(ELFClass::X86, ELFProgramHeaderType::ProgramHeaderTable);
+            phdr.p_offset = header.program_table;
+            phdr.p_vaddr = first.p_vaddr + header.program_table;
+            phdr.p_filesz = segment_count as u64 * program_header_len;
+            phdr.p_memsz = phdr.p_filesz;
+            phdr.p_flags = PF_R;
+            phdr.p_align = 4;
+
+            let (_, section) = loaded_sections.iter().find(|(name, _)| name == ".interp").unwrap();
+            let mut interp = ELFProgramHeader::new(ELFClass::X86, ELFProgramHeaderType::Interpereter);
+            interp.p_offset = section.header.sh_offset;
+            interp.p_vaddr = section.header.sh_addr;
+            interp.p_filesz = section.header.sh_size;
+            interp.p_memsz = section.header.sh_size;
+            interp.p_flags = PF_R;
+            interp.p_align = 1;
+
+            program_headers.insert(0, phdr);
+            program_headers.insert(1, interp);
+        }
+        header.program_count = program_headers.len() as u16;
 
         let mut elf = ELF {
             class: ELFClass::X86,
             header,
-            program_header,           
+            program_headers,
+            sections: Vec::new(),
+            loaded_sections,
+            debug_info: None,
             program,
         };
 
-        elf.program.offset = offset;
-        elf
+        elf.build_sections();
+        Ok(elf)
+    }
+
+    /// Checks a program can be output as a shared library: the code must be position independent.
+    /// Shared libraries have no relocations so absolute addresses and calls through the PLT,
+    /// which uses absolute addresses, can't be used.
+    fn check_shared_library(program: &Program) -> Result<(), String> {
+        if let Some((_, label, location)) = program.absolute_references().first() {
+            let location = location.map(|l| format!("\"{}\" line {}: ", l.file, l.line_no)).unwrap_or_default();
+            return Err(format!("{}absolute address of '{}' isn't position independent, shared libraries must find \
+                addresses relative to the program counter (e.g. with call/pop).", location, label));
+        }
+
+        if let Some(external) = program.externs.first() {
+            return Err(format!("\"{}\" line {}: shared libraries can't call external function '{}'.",
+                external.location.file, external.location.line_no, external.name));
+        }
+
+        Ok(())
+    }
+
+    /// Attaches DWARF debug information (.debug_abbrev, .debug_info and .debug_line) describing
+    /// the program's source lines. `name` is the main source file.
+    pub fn add_debug_info(&mut self, name: &str) {
+        let comp_dir = std::env::current_dir().unwrap_or_default();
+        self.debug_info = Some(DebugInfo::new(&self.program, name, &comp_dir.display().to_string()));
+        self.build_sections();
+    }
+
+    /// Builds the section header table: .text covering the program, any loaded sections made by
+    /// the linker, any debug sections, a .symtab/.strtab listing every label and the .shstrtab
+    /// naming the sections. Sections that aren't loaded are laid out after the program with the
+    /// section header table at the end of the file.
+    fn build_sections(&mut self) {
+        let endianness = self.header.endianness;
+        let mut shstrtab = ELFStringTable::new();
+        let mut strtab = ELFStringTable::new();
+        let align = match self.class {
+            ELFClass::X86 => 4,
+            ELFClass::X86_64 => 8,
+        };
+
+        // Index 0 is reserved for the null section.
+        let mut sections = vec![ELFSection {
+            header: ELFSectionHeader::new(self.class, 0, ELFSectionType::Null),
+            data: Vec::new(),
+        }];
+
+        // Each segment gets a section of the same name.
+        let first_segment_section = sections.len() as u16;
+        for (idx, segment) in self.program.segments().iter().enumerate() {
+            let mut header = ELFSectionHeader::new(self.class, shstrtab.push(&segment.name), ELFSectionType::ProgramBits);
+            header.sh_flags = SHF_ALLOC | SHF_WRITE | SHF_EXECINSTR;
+            header.sh_addr = segment.start.vaddr;
+            header.sh_offset = segment.start.addr;
+            header.sh_size = self.program.segment_len(idx) as u64;
+            header.sh_addralign = 1;
+            sections.push(ELFSection { header, data: Vec::new() });
+        }
+
+        let first_loaded_section = sections.len();
+        for (name, section) in &self.loaded_sections {
+            let mut section = section.clone();
+            section.header.sh_name = shstrtab.push(name);
+            sections.push(section);
+        }
+
+        // Link the dynamic sections to the symbol and string tables they refer to.
+        let index_of = |name: &str| self.loaded_sections.iter().position(|(n, _)| n == name).map(|i| (first_loaded_section + i) as u32);
+        for section in &mut sections[first_loaded_section..] {
+            let link = match section.header.sh_type {
+                ELFSectionType::Hash | ELFSectionType::Rel => index_of(".dynsym"),
+                ELFSectionType::DynamicSymbolTable | ELFSectionType::Dynamic => index_of(".dynstr"),
+                _ => None,
+            };
+            section.header.sh_link = link.unwrap_or(0);
+            if section.header.sh_type == ELFSectionType::DynamicSymbolTable {
+                section.header.sh_info = 1; // every symbol after the null symbol is global
+            }
+        }
+
+        if let Some(debug_info) = &self.debug_info {
+            for (name, data) in [
+                (".debug_abbrev", &debug_info.abbrev),
+                (".debug_info", &debug_info.info),
+                (".debug_line", &debug_info.line),
+            ] {
+                let mut header = ELFSectionHeader::new(self.class, shstrtab.push(name), ELFSectionType::ProgramBits);
+                header.sh_addralign = 1;
+                sections.push(ELFSection { header, data: data.clone() });
+            }
+        }
+
+        // Every label becomes a symbol in its segment's section and every external an undefined
+        // symbol. Local symbols must come before the rest.
+        let segments: HashMap<&str, usize> = self.program.blocks().into_iter()
+            .map(|(block, _)| (block.label(), block.segment()))
+            .collect();
+        let mut symbols = vec![ELFSymbol::null(self.class)];
+        for (label, addr, len) in self.program.labels() {
+            symbols.push(ELFSymbol {
+                class: self.class,
+                st_name: strtab.push(label),
+                st_value: addr.vaddr,
+                st_size: len as u64,
+                binding: self.program.binding(label).into(),
+                st_type: ELFSymbolType::NoType,
+                visibility: self.program.visibility(label).into(),
+                st_shndx: first_segment_section + segments[label] as u16,
+            });
+        }
+        for external in &self.program.externs {
+            symbols.push(ELFSymbol {
+                class: self.class,
+                st_name: strtab.push(&external.name),
+                st_value: 0,
+                st_size: 0,
+                binding: self.program.binding(&external.name).into(),
+                st_type: ELFSymbolType::NoType,
+                visibility: self.program.visibility(&external.name).into(),
+                st_shndx: 0,
+            });
+        }
+        symbols.sort_by_key(|s| s.binding != ELFSymbolBinding::Local);
+        let first_global = symbols.iter().position(|s| s.binding != ELFSymbolBinding::Local).unwrap_or(symbols.len());
+        let symtab: Vec<u8> = symbols.iter().flat_map(|s| s.as_vec(endianness)).collect();
+
+        let mut header = ELFSectionHeader::new(self.class, shstrtab.push(".symtab"), ELFSectionType::SymbolTable);
+        header.sh_link = sections.len() as u32 + 1; // .strtab follows .symtab
+        header.sh_info = first_global as u32;
+        header.sh_addralign = align;
+        header.sh_entsize = ELFSymbol::len(self.class) as u64;
+        sections.push(ELFSection { header, data: symtab });
+
+        let mut header = ELFSectionHeader::new(self.class, shstrtab.push(".strtab"), ELFSectionType::StringTable);
+        header.sh_addralign = 1;
+        sections.push(ELFSection { header, data: strtab.as_vec() });
+
+        let mut header = ELFSectionHeader::new(self.class, shstrtab.push(".shstrtab"), ELFSectionType::StringTable);
+        header.sh_addralign = 1;
+        let section_names = sections.len() as u16;
+        sections.push(ELFSection { header, data: shstrtab.as_vec() });
+
+        // Lay out the contents of the sections that aren't loaded after the program.
+        let mut offset = self.program_headers.iter().map(|p| p.p_offset + p.p_filesz).max().unwrap_or(0);
+        for section in sections.iter_mut().filter(|s| !s.data.is_empty() && s.header.sh_flags & SHF_ALLOC == 0) {
+            offset = offset.next_multiple_of(section.header.sh_addralign.max(1));
+            section.header.sh_offset = offset;
+            section.header.sh_size = section.data.len() as u64;
+            offset += section.data.len() as u64;
+        }
+
+        self.header.section_table = offset.next_multiple_of(align);
+        self.header.section_count = sections.len() as u16;
+        self.header.section_names = section_names;
+        self.sections = sections;
+    }
+
+    /// Converts the ELF binary into a vector of bytes.
+    pub fn as_vec(&self) -> Vec<u8> {
+        let endianness = self.header.endianness;
+        let mut dump = Vec::new();
+        dump.extend_from_slice(&self.header.as_vec());
+        for program_header in &self.program_headers {
+            dump.extend_from_slice(&program_header.as_vec(endianness));
+        }
+
+        for (idx, segment) in self.program.segments().iter().enumerate() {
+            dump.resize(segment.start.addr as usize, 0);
+            dump.extend_from_slice(&self.program.segment_as_vec(idx));
+        }
+
+        for section in &self.sections {
+            if section.data.is_empty() { continue; }
+            dump.resize(section.header.sh_offset as usize, 0);
+            dump.extend_from_slice(&section.data);
+        }
+
+        if !self.sections.is_empty() {
+            dump.resize(self.header.section_table as usize, 0);
+            for section in &self.sections {
+                dump.extend_from_slice(&section.header.as_vec(endianness));
+            }
+        }
+
+        dump
     }
     
     /// Saves the ELF binary to disk.
     pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
         let mut file = File::create(path)?;
-        file.write_all(&self.header.as_vec())?;
-        file.write_all(&self.program_header.as_vec(self.header.endianness))?;
-        file.write_all(&self.program.as_vec())?;
+        file.write_all(&self.as_vec())?;
         Ok(())
     }
 }
@@ -399,9 +1113,277 @@ mod tests {
             p_offset: 0x54,
             p_vaddr: 0x08048054,
             p_filesz: 0x0C,
+            p_memsz: 0x0C,
+            p_flags: PF_R | PF_X,
+            p_align: PAGE_SIZE,
+        };
+
+        assert_eq!(ph.len(), 0x20);
+        assert_eq!(ph.as_vec(Endianness::Little), result);
+    }
+
+    #[test]
+    fn elf_section_header() {
+        let result = vec![
+            0x01, 0x00, 0x00, 0x00,  // sh_name
+            0x01, 0x00, 0x00, 0x00,  // sh_type
+            0x07, 0x00, 0x00, 0x00,  // sh_flags
+            0x54, 0x80, 0x04, 0x08,  // sh_addr
+            0x54, 0x00, 0x00, 0x00,  // sh_offset
+            0x0C, 0x00, 0x00, 0x00,  // sh_size
+            0x00, 0x00, 0x00, 0x00,  // sh_link
+            0x00, 0x00, 0x00, 0x00,  // sh_info
+            0x01, 0x00, 0x00, 0x00,  // sh_addralign
+            0x00, 0x00, 0x00, 0x00,  // sh_entsize
+        ];
+
+        let sh = ELFSectionHeader {
+            class: ELFClass::X86,
+            sh_name: 1,
+            sh_type: ELFSectionType::ProgramBits,
+            sh_flags: SHF_ALLOC | SHF_WRITE | SHF_EXECINSTR,
+            sh_addr: 0x08048054,
+            sh_offset: 0x54,
+            sh_size: 0x0C,
+            sh_link: 0,
+            sh_info: 0,
+            sh_addralign: 1,
+            sh_entsize: 0,
+        };
+
+        assert_eq!(sh.len(), 0x28);
+        assert_eq!(sh.as_vec(Endianness::Little), result);
+    }
+
+    #[test]
+    fn elf_symbol() {
+        let result = vec![
+            0x07, 0x00, 0x00, 0x00,  // st_name
+            0x54, 0x80, 0x04, 0x08,  // st_value
+            0x0C, 0x00, 0x00, 0x00,  // st_size
+            0x10,                    // st_info
+            0x00,                    // st_other
+            0x01, 0x00,              // st_shndx
+        ];
+
+        let sym = ELFSymbol {
+            class: ELFClass::X86,
+            st_name: 7,
+            st_value: 0x08048054,
+            st_size: 0x0C,
+            binding: ELFSymbolBinding::Global,
+            st_type: ELFSymbolType::NoType,
+            visibility: ELFSymbolVisibility::Default,
+            st_shndx: 1,
+        };
+
+        assert_eq!(ELFSymbol::len(ELFClass::X86), 0x10);
+        assert_eq!(sym.as_vec(Endianness::Little), result);
+    }
+
+    #[test]
+    fn elf_string_table() {
+        let mut strtab = ELFStringTable::new();
+        assert_eq!(strtab.push(".text"), 1);
+        assert_eq!(strtab.push("_start"), 7);
+        assert_eq!(strtab.as_vec(), b"\0.text\0_start\0".to_vec());
+    }
+
+    #[test]
+    fn elf_layout() {
+        use crate::prelude::Instruction;
+
+        let mut program = Program::new();
+        program.entry = "_start".to_string();
+        program.new_block("_start").push(Instruction::RawData(vec![0x90; 4]));
+        program.switch_segment("data");
+        program.new_block("_msg").push(Instruction::RawData(vec![0x41; 3]));
+
+        // Segments follow on from each other on separate pages.
+        let elf = ELF::new_x86(program, OutputKind::Executable).unwrap();
+        assert_eq!(elf.program_headers.len(), 2);
+        assert_eq!(elf.program_headers[0].p_offset, 0x74);
+        assert_eq!(elf.program_headers[0].p_vaddr, 0x08048074);
+        assert_eq!(elf.program_headers[1].p_offset, 0x78);
+        assert_eq!(elf.program_headers[1].p_vaddr, 0x08049078);
+        assert_eq!(elf.header.entry_point, 0x08048074);
+
+        // Requested addresses are kept congruent with their file offsets.
+        let mut program = elf.program;
+        program.apply_layout("data 0x20000123").unwrap();
+        let elf = ELF::new_x86(program, OutputKind::Executable).unwrap();
+        assert_eq!(elf.program_headers[1].p_offset, 0x123);
+        assert_eq!(elf.program_headers[1].p_vaddr, 0x20000123);
+
+        let mut program = elf.program;
+        program.apply_layout("data 0x08048076").unwrap();
+        assert!(ELF::new_x86(program, OutputKind::Executable).is_err());
+    }
+
+    #[test]
+    fn elf_alignment() {
+        use crate::prelude::Instruction;
+
+        let mut program = Program::new();
+        program.entry = "_start".to_string();
+        program.align_segment(16);
+        program.new_block("_start").push(Instruction::RawData(vec![0x90; 4]));
+        program.switch_segment("data");
+        program.align_segment(64);
+        program.new_block("_msg").push(Instruction::RawData(vec![0x41; 3]));
+
+        // The file is padded so aligned segments start at aligned addresses.
+        let elf = ELF::new_x86(program, OutputKind::Executable).unwrap();
+        assert_eq!(elf.program_headers[0].p_offset, 0x80);
+        assert_eq!(elf.program_headers[0].p_vaddr, 0x08048080);
+        assert_eq!(elf.program_headers[1].p_offset, 0xC0);
+        assert_eq!(elf.program_headers[1].p_vaddr, 0x080490C0);
+
+        let mut program = elf.program;
+        program.apply_layout("data 0x20000120").unwrap();
+        assert_eq!(ELF::new_x86(program, OutputKind::Executable).err().unwrap(),
+            "segment 'data' is placed at 0x20000120 which isn't aligned to 64 bytes.");
+    }
+
+    #[test]
+    fn elf_pie() {
+        use crate::prelude::{Instruction, Register, Value};
+
+        let mut program = Program::new();
+        program.entry = "_start".to_string();
+        let block = program.new_block("_start");
+        block.push(Instruction::MovImmediate { register: Register::ECX, value: Value::Pointer("_msg".to_string(), 0) });
+        block.push(Instruction::RawData(vec![0x90; 2]));
+        program.new_block("_msg").push(Instruction::RawData(vec![0x41; 3]));
+
+        // The program starts at 0 and the stub runs first, then jumps to _start.
+        let elf = ELF::new_x86(program, OutputKind::PositionIndependent).unwrap();
+        assert_eq!(elf.header.elftype, ELFType::SharedObject);
+        assert_eq!(elf.program_headers.len(), 3);
+        assert_eq!(elf.program_headers[0].p_vaddr, 0x94);
+        assert_eq!(elf.program_headers[2].p_type, ELFProgramHeaderType::Dynamic);
+
+        let (name, stub) = &elf.loaded_sections[0];
+        assert_eq!(name, ".text.pie");
+        assert_eq!(elf.header.entry_point, stub.header.sh_addr);
+        assert_eq!(stub.header.sh_offset % 4, 0);
+        let jump = i32::from_le_bytes(stub.data[38..42].try_into().unwrap());
+        assert_eq!((stub.header.sh_addr + stub.header.sh_size).wrapping_add_signed(jump as i64), 0x94);
+
+        // The pointer to _msg in 'mov ecx, _msg' is relocated.
+        let (name, rel) = &elf.loaded_sections[1];
+        assert_eq!(name, ".rel.dyn");
+        assert_eq!(rel.data, vec![0x95, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn elf_dynamic() {
+        use crate::prelude::{Extern, Instruction, SourceLocation, Value};
+
+        let mut program = Program::new();
+        program.entry = "_start".to_string();
+        program.needed.push("libc.so.6".to_string());
+        program.externs.push(Extern {
+            name: "exit".to_string(),
+            location: SourceLocation { file: "test.s".to_string(), line_no: 1 },
+            addr: Addr::default(),
+        });
+        program.new_block("_start").push(Instruction::Call(Value::RelPointer("exit".to_string(), 0)));
+
+        // The program headers are loaded by extending the first segment to the start of the file.
+        let elf = ELF::new_x86(program, OutputKind::Executable).unwrap();
+        let types: Vec<ELFProgramHeaderType> = elf.program_headers.iter().map(|p| p.p_type).collect();
+        assert_eq!(types, vec![
+            ELFProgramHeaderType::ProgramHeaderTable,
+            ELFProgramHeaderType::Interpereter,
+            ELFProgramHeaderType::Loadable,
+            ELFProgramHeaderType::Loadable,
+            ELFProgramHeaderType::Dynamic,
+        ]);
+        assert_eq!(elf.program_headers[0].p_vaddr, DEFAULT_BASE_X86 + 0x34);
+        assert_eq!(elf.program_headers[2].p_offset, 0);
+        assert_eq!(elf.program_headers[2].p_vaddr, DEFAULT_BASE_X86);
+
+        // Calls go through the PLT entry after the reserved first entry.
+        let names: Vec<&str> = elf.loaded_sections.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec![".interp", ".plt", ".hash", ".dynsym", ".dynstr", ".rel.plt", ".dynamic", ".got.plt"]);
+        let plt = &elf.loaded_sections[1].1.header;
+        assert_eq!(elf.program.get_addr("exit").unwrap().vaddr, plt.sh_addr + 16);
+
+        // The GOT entry starts out pointing at the push in the PLT entry and is relocated by
+        // R_386_JMP_SLOT against the first dynamic symbol.
+        let got = &elf.loaded_sections[7].1;
+        assert_eq!(got.data[12..16], dump_dword(plt.sh_addr as u32 + 16 + 6, Endianness::Little));
+        let rel = &elf.loaded_sections[5].1;
+        let mut expected = dump_dword(got.header.sh_addr as u32 + 12, Endianness::Little).to_vec();
+        expected.extend_from_slice(&[0x07, 0x01, 0x00, 0x00]);
+        assert_eq!(rel.data, expected);
+    }
+
+    #[test]
+    fn elf_shared_library() {
+        use crate::prelude::{Instruction, SourceLocation, Value};
+
+        let location = SourceLocation { file: "test.s".to_string(), line_no: 1 };
+        let mut program = Program::new();
+        program.soname = Some("libtest.so".to_string());
+        program.declare("answer", location.clone());
+        program.new_block("answer").push(Instruction::RawData(vec![0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]));
+
+        let elf = ELF::new_x86(program, OutputKind::SharedLibrary).unwrap();
+        assert_eq!(elf.header.elftype, ELFType::SharedObject);
+        assert_eq!(elf.header.entry_point, 0);
+        let names: Vec<&str> = elf.loaded_sections.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec![".hash", ".dynsym", ".dynstr", ".dynamic"]);
+        assert_eq!(elf.loaded_sections[2].1.data, b"\0answer\0libtest.so\0");
+
+        // The export is a defined function in .text.
+        let answer = elf.program.get_addr("answer").unwrap();
+        let symbol = ELFSymbol {
+            class: ELFClass::X86,
+            st_name: 1,
+            st_value: answer.vaddr,
+            st_size: 6,
+            binding: ELFSymbolBinding::Global,
+            st_type: ELFSymbolType::Function,
+            visibility: ELFSymbolVisibility::Default,
+            st_shndx: 1,
         };
+        assert_eq!(elf.loaded_sections[1].1.data[16..], symbol.as_vec(Endianness::Little));
+
+        // Absolute addresses aren't position independent.
+        let mut program = elf.program;
+        program.get_block_mut(0).unwrap().push_at(Instruction::MovImmediate {
+            register: crate::prelude::Register::EAX,
+            value: Value::Pointer("answer".to_string(), 0),
+        }, location);
+        assert!(ELF::new_x86(program, OutputKind::SharedLibrary).is_err());
+    }
+    #[test]
+    fn elf_symbol_binding() {
+        use crate::prelude::{Extern, Instruction, SourceLocation};
+
+        let location = SourceLocation { file: "test.s".to_string(), line_no: 1 };
+        let mut program = Program::new();
+        program.new_block("local").push(Instruction::Return);
+        program.new_block("_start").push(Instruction::Return);
+        program.new_block("helper").push(Instruction::Return);
+        program.declare("_start", location.clone());
+        program.declare("helper", location.clone()).binding = SymbolBinding::Weak;
+        program.declare("helper", location.clone()).visibility = SymbolVisibility::Hidden;
+        program.entry = "_start".to_string();
+        program.externs.push(Extern { name: "puts".to_string(), location, addr: Addr::default() });
+        assert_eq!(program.exported(), vec!["_start"]);
+
+        let elf = ELF::new_x86(program, OutputKind::Executable).unwrap();
+        let symtab = elf.sections.iter().find(|s| s.header.sh_type == ELFSectionType::SymbolTable).unwrap();
 
-        //assert_eq!(ph.len(), 0x20);
-        //assert_eq!(ph.as_vec(Endianness::Little), result);
+        // The null symbol and 'local' are local, then _start, helper and puts.
+        assert_eq!(symtab.header.sh_info, 2);
+        let info_and_other: Vec<(u8, u8)> = symtab.data.chunks(ELFSymbol::len(ELFClass::X86))
+            .map(|symbol| (symbol[12], symbol[13]))
+            .collect();
+        assert_eq!(info_and_other, vec![(0x00, 0), (0x00, 0), (0x10, 0), (0x20, 2), (0x10, 0)]);
+        assert_eq!(symtab.data[4 * 16 + 14..], [0, 0]);
     }
 }
@@ -26,6 +26,8 @@ pub enum JumpCondition {
 
 pub enum Instruction {
     RawData(Vec<u8>),
+    /// A value in data, e.g. the address of a label in a table.
+    Data(Value),
     Int(u8),
     Mov(Register, Register),
     MovImmediate { register: Register, value: Value },
@@ -60,10 +62,28 @@ pub enum Instruction {
 }
 
 impl Instruction {
+    /// Gets the immediate or memory address operand. It is always encoded as the trailing bytes
+    /// of the instruction.
+    pub fn value(&self) -> Option<&Value> {
+        match self {
+            Self::MovImmediate { value, .. } => Some(value),
+            Self::MovMemory { addr, .. } => Some(addr),
+            Self::MovFromMemory(_, value) => Some(value),
+            Self::Jump { addr, .. } => Some(addr),
+            Self::AddImmediate { value, .. } => Some(value),
+            Self::SubImmediate { value, .. } => Some(value),
+            Self::CompareImmediate(_, value) => Some(value),
+            Self::Call(value) => Some(value),
+            Self::Data(value) => Some(value),
+            _ => None,
+        }
+    }
+
     /// Get the length of the instruction in bytes.
     pub fn len(&self) -> usize {
         match self {
             Self::RawData(x) => x.len(),
+            Self::Data(value) => value.len(),
             Self::Int(_) => 2,
             Self::Mov(dest, _) => if dest.bits() == 16 { 3 } else { 2 },
             Self::MovImmediate { register, value } => match register.bits() {
@@ -130,6 +150,7 @@ impl Program {
 
         match instr {
             Instruction::RawData(x) => data.extend_from_slice(x),
+            Instruction::Data(value) => data.extend_from_slice(&value.as_vec(&self, cur_addr)),
             Instruction::Int(x) => {
                 data.push(0xCD);
                 data.push(*x);
@@ -19,12 +19,56 @@ pub enum Token {
     Multiply,
     #[token("/")]
     Divide,
+    #[token("//")]
+    SignedDivide,
+    #[token("%")]
+    Modulo,
+    #[token("%%")]
+    SignedModulo,
+    #[token("&")]
+    BitAnd,
+    #[token("|")]
+    BitOr,
+    #[token("^")]
+    BitXor,
+    #[token("~")]
+    BitNot,
+    #[token("<<")]
+    LeftShift,
+    #[token(">>")]
+    RightShift,
+    #[token("!")]
+    LogicalNot,
+    #[token("&&")]
+    LogicalAnd,
+    #[token("||")]
+    LogicalOr,
+    #[token("==")]
+    Equal,
+    #[token("!=")]
+    NotEqual,
+    #[token("<")]
+    Less,
+    #[token("<=")]
+    LessEqual,
+    #[token(">")]
+    Greater,
+    #[token(">=")]
+    GreaterEqual,
     #[token("(")]
     LeftParen,
     #[token(")")]
     RightParen,
     #[token("$")]
     Dollar,
+    #[token("$$")]
+    DoubleDollar,
+    #[token("=")]
+    Equals,
+    #[token("{")]
+    LeftBrace,
+    #[token("}")]
+    RightBrace,
 
     // Pseudo-instructions
     #[token("ENTRY")]
@@ -36,11 +80,68 @@ pub enum Token {
     #[token("DW")]
     DW,
     #[token("DL")]
+    #[token("DD")]
     DLPseudo,
+    #[token("DQ")]
+    DQ,
+    #[token("DT")]
+    DT,
     #[token("EQU")]
     EQU,
     #[token("INCLUDE")]
     Include,
+    #[token("INCBIN")]
+    IncBin,
+    #[token("ORG")]
+    Org,
+    #[token("SEGMENT")]
+    Segment,
+    #[token("EXTERN")]
+    Extern,
+    #[token("NEEDED")]
+    Needed,
+    #[token("EXPORT")]
+    Export,
+    #[token("GLOBAL")]
+    Global,
+    #[token("WEAK")]
+    Weak,
+    #[token("HIDDEN")]
+    Hidden,
+    #[token("TIMES")]
+    Times,
+    #[token("ALIGN")]
+    Align,
+    #[token("ASCII")]
+    Ascii,
+    #[token("ASCIZ")]
+    Asciz,
+    #[token("PASCAL")]
+    Pascal,
+
+    // Preprocessor
+    #[token("%macro")]
+    Macro,
+    // Macro parameters (%1, %*, %%label) are replaced in the body's text before it's parsed,
+    // they lex as operators so they don't hide '10%3' or 'a %%b' outside macros.
+    #[token("%endmacro")]
+    EndMacro,
+    #[token("%if")]
+    If,
+    #[token("%ifdef")]
+    IfDef,
+    #[token("%ifndef")]
+    IfNDef,
+    #[token("%elif")]
+    Elif,
+    #[token("%else")]
+    Else,
+    #[token("%endif")]
+    EndIf,
+    #[token("%rep")]
+    Rep,
+    #[token("%endrep")]
+    EndRep,
 
     // Instructions
     #[token("mov")]
@@ -207,14 +308,71 @@ pub enum Token {
     Whitespace,
 
     // Values
-    #[regex("[_a-zA-Z][_a-zA-Z0-9]*", |lex| lex.slice().to_owned())]
+    // Names starting with '.' are local to the label before them, e.g. '.loop' after 'PrintHex'
+    // is 'PrintHex.loop'.
+    #[regex(r"\.?[_a-zA-Z][_a-zA-Z0-9]*(\.[_a-zA-Z0-9]+)*", |lex| lex.slice().to_owned())]
     Identifier(String),
-    #[regex(r#""([^"\\\x00-\x1F]|\\(["\\bnfrt/]|u[a-fA-F0-9]{4}))*""#, |lex| let s = lex.slice().to_owned(); s[1..s.len()-1].to_string())]
-    String(String),
-    #[regex("0x[0-9A-Fa-f]+", |lex| let s = lex.slice().to_owned(); u64::from_str_radix(&s[2..], 16).unwrap())]
+    // The numeric label before ('1b') or after ('1f') this line.
+    #[regex("[0-9]+[bf]", |lex| lex.slice().to_owned())]
+    NumericLabel(String),
+    #[regex(r#""([^"\\\n]|\\.)*""#, |lex| unescape(lex.slice()))]
+    #[regex(r#"'([^'\\\n]|\\.)*'"#, |lex| unescape(lex.slice()))]
+    #[regex(r#"`([^`\\\n]|\\.)*`"#, |lex| unescape(lex.slice()))]
+    String(Vec<u8>),
+    // Numbers in hex (0x1F, 1Fh), binary (0b101) or octal (0o17, 17q).
+    #[regex("0[xX][0-9A-Fa-f_]+", |lex| parse_radix(&lex.slice()[2..], 16))]
+    #[regex("[0-9][0-9A-Fa-f_]*[hH]", |lex| parse_radix(&lex.slice()[..lex.slice().len() - 1], 16))]
+    #[regex("0[bB][01_]+", |lex| parse_radix(&lex.slice()[2..], 2))]
+    #[regex("0[oO][0-7_]+", |lex| parse_radix(&lex.slice()[2..], 8))]
+    #[regex("[0-7][0-7_]*[qQ]", |lex| parse_radix(&lex.slice()[..lex.slice().len() - 1], 8))]
     HexNumber(u64),
-    #[regex("-?[0-9]+", |lex| lex.slice().parse::<i64>().unwrap())]
+    // Floats have a '.' or an exponent, e.g. 1.5, 3. or 1e-3.
+    #[regex(r"[0-9][0-9_]*\.[0-9_]*([eE][+-]?[0-9]+)?", |lex| lex.slice().replace('_', "").parse::<f64>().ok())]
+    #[regex(r"[0-9][0-9_]*[eE][+-]?[0-9]+", |lex| lex.slice().replace('_', "").parse::<f64>().ok())]
+    Float(f64),
+    #[regex("[0-9][0-9_]*", |lex| lex.slice().replace('_', "").parse::<i64>().ok())]
     Number(i64),
+
+    // Text that isn't a token, e.g. a number too big for 64 bits, given by the parser rather
+    // than the lexer.
+    Invalid(String),
+}
+
+/// Parses a number in base 'radix', ignoring '_' separating the digits.
+fn parse_radix(digits: &str, radix: u32) -> Option<u64> {
+    u64::from_str_radix(&digits.replace('_', ""), radix).ok()
+}
+
+/// Gets the bytes of a string quoted by ", ' or `, decoding the escapes \n, \r, \t, \0 and
+/// \xNN. Any other escaped character stands for itself, e.g. \\ or \".
+fn unescape(quoted: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some('x') => {
+                    let digits: String = chars.clone().take(2).take_while(|c| c.is_ascii_hexdigit()).collect();
+                    if let Ok(byte) = u8::from_str_radix(&digits, 16) {
+                        chars.nth(digits.len() - 1);
+                        bytes.push(byte);
+                        continue;
+                    }
+                    'x'
+                }
+                Some(c) => c,
+                None => break,
+            },
+            c => c,
+        };
+        let mut buffer = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+    bytes
 }
 
 #[cfg(test)]
@@ -235,7 +393,7 @@ _start:
         assert_eq!(lex.next(), Some(Ok(Token::Whitespace)));
         assert_eq!(lex.next(), Some(Ok(Token::Db)));
         assert_eq!(lex.next(), Some(Ok(Token::Whitespace)));
-        assert_eq!(lex.next(), Some(Ok(Token::String("Hello World!".to_string()))));
+        assert_eq!(lex.next(), Some(Ok(Token::String(b"Hello World!".to_vec()))));
         assert_eq!(lex.next(), Some(Ok(Token::Comma)));
         assert_eq!(lex.next(), Some(Ok(Token::Whitespace)));
         assert_eq!(lex.next(), Some(Ok(Token::HexNumber(0xA))));
@@ -258,4 +416,67 @@ _start:
         assert_eq!(lex.next(), Some(Ok(Token::HexNumber(0x80))));
         assert_eq!(lex.next(), None);
     }
+
+    #[test]
+    fn operators() {
+        let tokens: Vec<Token> = Token::lexer("10-2 // 3 %% 4 % 5 << >> <= < == != ! && || & | ^ ~")
+            .map(|token| token.unwrap())
+            .filter(|token| *token != Token::Whitespace)
+            .collect();
+        assert_eq!(tokens, vec![
+            Token::Number(10), Token::Minus, Token::Number(2), Token::SignedDivide, Token::Number(3),
+            Token::SignedModulo, Token::Number(4), Token::Modulo, Token::Number(5), Token::LeftShift,
+            Token::RightShift, Token::LessEqual, Token::Less, Token::Equal, Token::NotEqual,
+            Token::LogicalNot, Token::LogicalAnd, Token::LogicalOr, Token::BitAnd, Token::BitOr,
+            Token::BitXor, Token::BitNot,
+        ]);
+    }
+
+    #[test]
+    fn literals() {
+        let tokens: Vec<Token> = Token::lexer("1_000_000 0x_FF 0FFh 0b1010 0o17 17q")
+            .map(|token| token.unwrap())
+            .filter(|token| *token != Token::Whitespace)
+            .collect();
+        assert_eq!(tokens, vec![
+            Token::Number(1_000_000), Token::HexNumber(0xFF), Token::HexNumber(0xFF), Token::HexNumber(10),
+            Token::HexNumber(15), Token::HexNumber(15),
+        ]);
+    }
+
+    #[test]
+    fn labels() {
+        let tokens: Vec<Token> = Token::lexer(".loop PrintHex.loop 1b 2f 0b1")
+            .map(|token| token.unwrap())
+            .filter(|token| *token != Token::Whitespace)
+            .collect();
+        assert_eq!(tokens, vec![
+            Token::Identifier(".loop".to_string()), Token::Identifier("PrintHex.loop".to_string()),
+            Token::NumericLabel("1b".to_string()), Token::NumericLabel("2f".to_string()), Token::HexNumber(1),
+        ]);
+    }
+
+    #[test]
+    fn floats() {
+        let tokens: Vec<Token> = Token::lexer("1.5 3. 1_000.25 1e3 2.5E-2 1E5h")
+            .map(|token| token.unwrap())
+            .filter(|token| *token != Token::Whitespace)
+            .collect();
+        assert_eq!(tokens, vec![
+            Token::Float(1.5), Token::Float(3.0), Token::Float(1000.25), Token::Float(1000.0), Token::Float(0.025),
+            Token::HexNumber(0x1E5),
+        ]);
+    }
+
+    #[test]
+    fn strings() {
+        let tokens: Vec<Token> = Token::lexer(r#""a\tb\n" 'it''s' 'don\'t' `\x41\x7\0\\\"` "é;""#)
+            .map(|token| token.unwrap())
+            .filter(|token| *token != Token::Whitespace)
+            .collect();
+        assert_eq!(tokens, vec![
+            Token::String(b"a\tb\n".to_vec()), Token::String(b"it".to_vec()), Token::String(b"s".to_vec()),
+            Token::String(b"don't".to_vec()), Token::String(b"A\x07\0\\\"".to_vec()), Token::String("é;".as_bytes().to_vec()),
+        ]);
+    }
 }
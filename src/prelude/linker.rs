@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+
+use super::elf::*;
+use super::archive::Archive;
+use super::object::ObjectFile;
+use super::{utils::*, Endianness, Instruction, Program, SourceLocation, SymbolBinding};
+
+/// Links relocatable objects into an executable. Sections of the same name are merged into one
+/// segment (.text.foo joins .text), global symbols are resolved across the objects and
+/// R_386_32 and R_386_PC32 relocations applied.
+pub struct Linker {
+    objects: Vec<ObjectFile>,
+    /// Where each section of each object is placed: the output segment and offset within it,
+    /// indexed by object then section.
+    placements: Vec<Vec<(usize, usize)>>,
+    /// The names of the output segments in order.
+    segments: Vec<String>,
+    /// The object and symbol index defining each global symbol.
+    globals: HashMap<String, (usize, usize)>,
+}
+
+/// Lists the names of the symbols the objects refer to without any of them defining it.
+fn undefined_symbols(objects: &[ObjectFile]) -> Vec<&str> {
+    let defined = |name: &str| objects.iter().any(|object| object.symbols.iter()
+        .any(|(n, s)| n == name && s.binding != ELFSymbolBinding::Local && s.st_shndx != SHN_UNDEF));
+
+    let mut undefined = Vec::new();
+    for object in objects {
+        for section in &object.sections {
+            for relocation in &section.relocations {
+                if let Some((name, symbol)) = object.symbols.get(relocation.r_sym as usize) {
+                    if symbol.st_shndx == SHN_UNDEF && !undefined.contains(&name.as_str()) && !defined(name) {
+                        undefined.push(name.as_str());
+                    }
+                }
+            }
+        }
+    }
+
+    undefined
+}
+
+/// Gets the segment a section is merged into, e.g. .text.startup joins .text.
+fn segment_name(section: &str) -> &str {
+    for name in [".text", ".rodata", ".data", ".bss"] {
+        if section.strip_prefix(name).is_some_and(|rest| rest.is_empty() || rest.starts_with('.')) {
+            return name;
+        }
+    }
+    section
+}
+
+impl Linker {
+    /// Resolves the global symbols of the objects and places their sections. Members of the
+    /// archives are linked when they define a symbol the objects refer to but don't define.
+    /// Every duplicate and undefined symbol is reported along with the file it's in.
+    pub fn new(mut objects: Vec<ObjectFile>, archives: &[(String, Archive)]) -> Result<Linker, String> {
+        // Linking a member can leave more symbols undefined, so keep searching until nothing
+        // new is linked.
+        let mut linked: Vec<String> = Vec::new();
+        loop {
+            let found = undefined_symbols(&objects).into_iter()
+                .filter_map(|name| archives.iter().find_map(|(path, archive)| {
+                    archive.find_symbol(name).map(|member| (format!("{}({})", path, member.name), member))
+                }))
+                .find(|(path, _)| !linked.contains(path));
+
+            let Some((path, member)) = found else { break };
+            let object = ObjectFile::parse(&path, &member.data)?;
+            linked.push(path);
+            objects.push(object);
+        }
+
+        let mut linker = Linker { objects, placements: Vec::new(), segments: vec![".text".to_string()], globals: HashMap::new() };
+        let mut errors = Vec::new();
+
+        // Global definitions override weak ones.
+        for (idx, object) in linker.objects.iter().enumerate() {
+            for (sym, (name, symbol)) in object.symbols.iter().enumerate() {
+                if symbol.binding == ELFSymbolBinding::Local || symbol.st_shndx == SHN_UNDEF { continue; }
+                if symbol.st_shndx == SHN_COMMON {
+                    errors.push(format!("\"{}\": common symbol '{}' isn't supported, compile with -fno-common.", object.path, name));
+                    continue;
+                }
+
+                match linker.globals.get(name) {
+                    Some((other, other_sym)) => {
+                        let (_, defined) = &linker.objects[*other].symbols[*other_sym];
+                        if defined.binding == ELFSymbolBinding::Weak && symbol.binding == ELFSymbolBinding::Global {
+                            linker.globals.insert(name.clone(), (idx, sym));
+                        } else if defined.binding == ELFSymbolBinding::Global && symbol.binding == ELFSymbolBinding::Global {
+                            errors.push(format!("\"{}\": duplicate symbol '{}', first defined in \"{}\".",
+                                object.path, name, linker.objects[*other].path));
+                        }
+                    }
+                    None => { linker.globals.insert(name.clone(), (idx, sym)); }
+                }
+            }
+        }
+
+        // Undefined symbols are only an error when something refers to them. Weak references
+        // resolve to 0.
+        for object in &linker.objects {
+            for section in &object.sections {
+                for relocation in &section.relocations {
+                    let Some((name, symbol)) = object.symbols.get(relocation.r_sym as usize) else {
+                        errors.push(format!("\"{}\": relocation in '{}' refers to unknown symbol {}.", object.path, section.name, relocation.r_sym));
+                        continue;
+                    };
+                    let error = format!("\"{}\": undefined symbol '{}'.", object.path, name);
+                    if symbol.st_shndx == SHN_UNDEF && symbol.binding != ELFSymbolBinding::Weak
+                        && !linker.globals.contains_key(name) && !errors.contains(&error) {
+                        errors.push(error);
+                    }
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+
+        // Sections are laid out in the order they're found, aligned within their segment.
+        let mut lens: Vec<usize> = vec![0];
+        for object in &linker.objects {
+            let mut placements = Vec::new();
+            for section in &object.sections {
+                let name = segment_name(&section.name);
+                let segment = match linker.segments.iter().position(|s| s == name) {
+                    Some(idx) => idx,
+                    None => {
+                        linker.segments.push(name.to_string());
+                        lens.push(0);
+                        linker.segments.len() - 1
+                    }
+                };
+                let offset = lens[segment].next_multiple_of(section.header.sh_addralign.max(1) as usize);
+                lens[segment] = offset + section.len();
+                placements.push((segment, offset));
+            }
+            linker.placements.push(placements);
+        }
+
+        Ok(linker)
+    }
+
+    /// Links the objects into an executable starting at the global symbol 'entry'. The base
+    /// address defaults to the usual one for executables.
+    pub fn link(&self, entry: &str, base: Option<u64>) -> Result<ELF, String> {
+        // Where segments end up only depends on their lengths, so lay out the unrelocated
+        // contents to find the addresses then lay out again once relocated.
+        let contents: Vec<Vec<Vec<u8>>> = self.objects.iter()
+            .map(|object| object.sections.iter().map(|s| s.contents()).collect())
+            .collect();
+        let elf = ELF::new_x86(self.build(&contents, entry, base), OutputKind::Executable)?;
+        let starts: Vec<u64> = self.segments.iter()
+            .map(|name| elf.program.segments().iter().find(|s| &s.name == name).unwrap().start.vaddr)
+            .collect();
+
+        let mut contents = contents;
+        for (idx, object) in self.objects.iter().enumerate() {
+            for (section_idx, section) in object.sections.iter().enumerate() {
+                let (segment, offset) = self.placements[idx][section_idx];
+                let section_addr = starts[segment] + offset as u64;
+                let data = &mut contents[idx][section_idx];
+
+                for relocation in &section.relocations {
+                    let at = relocation.r_offset as usize;
+                    if at + 4 > data.len() {
+                        return Err(format!("\"{}\": relocation at 0x{:X} is outside of '{}'.", object.path, at, section.name));
+                    }
+
+                    let s = self.symbol_addr(idx, relocation.r_sym as usize, &starts)? as u32;
+                    let a = read_dword(data, at, Endianness::Little);
+                    let p = (section_addr + at as u64) as u32;
+                    let value = match relocation.r_type {
+                        R_386_32 => s.wrapping_add(a),
+                        // Without shared libraries calls through the PLT go straight to the
+                        // function.
+                        R_386_PC32 | R_386_PLT32 => s.wrapping_add(a).wrapping_sub(p),
+                        r_type => return Err(format!("\"{}\": unsupported relocation type {} in '{}', only R_386_32 and \
+                            R_386_PC32 are supported (compile C with -fno-pic).", object.path, r_type, section.name)),
+                    };
+                    data[at..at + 4].copy_from_slice(&dump_dword(value, Endianness::Little));
+                }
+            }
+        }
+
+        ELF::new_x86(self.build(&contents, entry, base), OutputKind::Executable)
+    }
+
+    /// Looks up the address of symbol 'sym' of object 'idx' given the address of each segment.
+    fn symbol_addr(&self, idx: usize, sym: usize, starts: &[u64]) -> Result<u64, String> {
+        let object = &self.objects[idx];
+        let (name, symbol) = &object.symbols[sym];
+        let (idx, symbol) = match self.globals.get(name) {
+            Some((other, other_sym)) if symbol.binding != ELFSymbolBinding::Local => (*other, &self.objects[*other].symbols[*other_sym].1),
+            _ => (idx, symbol),
+        };
+
+        match symbol.st_shndx {
+            SHN_UNDEF => Ok(0), // an unresolved weak reference
+            SHN_ABS => Ok(symbol.st_value),
+            shndx => match self.objects[idx].sections.iter().position(|s| s.index == shndx) {
+                Some(section) => {
+                    let (segment, offset) = self.placements[idx][section];
+                    Ok(starts[segment] + offset as u64 + symbol.st_value)
+                }
+                None => Err(format!("\"{}\": symbol '{}' is in a section that isn't loaded.", object.path, name)),
+            },
+        }
+    }
+
+    /// Builds a program from the objects' section contents, with each global symbol labeling
+    /// the block starting at its address.
+    fn build(&self, contents: &[Vec<Vec<u8>>], entry: &str, base: Option<u64>) -> Program {
+        let mut program = Program::new();
+        program.base = base;
+        program.entry = entry.to_string();
+
+        // The globals defined in each section sorted by their offset.
+        let mut labels: HashMap<(usize, u16), Vec<(u64, &str)>> = HashMap::new();
+        for (name, (idx, sym)) in &self.globals {
+            let symbol = &self.objects[*idx].symbols[*sym].1;
+            labels.entry((*idx, symbol.st_shndx)).or_default().push((symbol.st_value, name));
+        }
+        for labels in labels.values_mut() {
+            labels.sort();
+        }
+
+        for (segment, name) in self.segments.iter().enumerate() {
+            program.switch_segment(name);
+            for (idx, object) in self.objects.iter().enumerate() {
+                for (section_idx, section) in object.sections.iter().enumerate() {
+                    let (placed, offset) = self.placements[idx][section_idx];
+                    if placed != segment { continue; }
+                    program.align_segment(section.header.sh_addralign.max(1));
+
+                    // Split the section into blocks at each label.
+                    let data = &contents[idx][section_idx];
+                    let mut pending = vec![0; offset - program.current_len()];
+                    let mut label = ("", None);
+                    let mut start = 0;
+                    for (value, name) in labels.get(&(idx, section.index)).into_iter().flatten() {
+                        let end = (*value as usize).clamp(start, data.len());
+                        pending.extend_from_slice(&data[start..end]);
+                        start = end;
+                        push_block(&mut program, label, std::mem::take(&mut pending));
+
+                        let location = SourceLocation { file: object.path.clone(), line_no: 0 };
+                        let (_, symbol) = &object.symbols[self.globals[*name].1];
+                        let binding = if symbol.binding == ELFSymbolBinding::Weak { SymbolBinding::Weak } else { SymbolBinding::Global };
+                        program.declare(name, location.clone()).binding = binding;
+                        label = (name, Some(location));
+                    }
+                    pending.extend_from_slice(&data[start..]);
+                    push_block(&mut program, label, pending);
+                }
+            }
+        }
+
+        program
+    }
+}
+
+/// Pushes a block of raw data labeled by 'label' to the program.
+fn push_block(program: &mut Program, (label, location): (&str, Option<SourceLocation>), data: Vec<u8>) {
+    let block = match location {
+        Some(location) => program.new_block_at(label, location),
+        None => program.new_block(label),
+    };
+    block.push(Instruction::RawData(data));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::{Addr, Extern, Value};
+
+    /// Assembles a program calling 'callee' from '_start' into an object.
+    fn caller(callee: &str) -> ObjectFile {
+        let location = SourceLocation { file: "main.s".to_string(), line_no: 1 };
+        let mut program = Program::new();
+        program.new_block("_start").push(Instruction::Call(Value::RelPointer(callee.to_string(), 0)));
+        program.declare("_start", location.clone());
+        program.externs.push(Extern { name: callee.to_string(), location, addr: Addr::default() });
+        ObjectFile::from_program(program, "main.o").unwrap()
+    }
+
+    /// Assembles a program defining the global 'name' followed by data pointing to it.
+    fn callee(name: &str, path: &str) -> ObjectFile {
+        let mut program = Program::new();
+        program.new_block(name).push(Instruction::Return);
+        program.switch_segment("data");
+        program.new_block("_ptr").push(Instruction::MovImmediate { register: crate::prelude::Register::EAX, value: Value::Pointer(name.to_string(), 0) });
+        program.declare(name, SourceLocation { file: path.to_string(), line_no: 1 });
+        ObjectFile::from_program(program, path).unwrap()
+    }
+
+    #[test]
+    fn link() {
+        let elf = Linker::new(vec![caller("f"), callee("f", "f.o")], &[]).unwrap().link("_start", None).unwrap();
+        let start = elf.program.get_addr("_start").unwrap();
+        let f = elf.program.get_addr("f").unwrap();
+        assert_eq!(elf.header.entry_point, start.vaddr);
+
+        // The call is relative to the end of the instruction, the pointer absolute. Sections are
+        // aligned to 4 bytes within their segment.
+        assert_eq!(f.vaddr, start.vaddr + 8);
+        let text = elf.program.segment_as_vec(0);
+        assert_eq!(text[0..5], [0xE8, 0x03, 0x00, 0x00, 0x00]);
+        let data = elf.program.segment_as_vec(1);
+        assert_eq!(data[1..5], dump_dword(f.vaddr as u32, Endianness::Little));
+    }
+
+    #[test]
+    fn link_errors() {
+        let error = Linker::new(vec![caller("g"), callee("f", "f.o")], &[]).err().unwrap();
+        assert_eq!(error, "\"main.o\": undefined symbol 'g'.");
+
+        let error = Linker::new(vec![caller("f"), callee("f", "a.o"), callee("f", "b.o")], &[]).err().unwrap();
+        assert_eq!(error, "\"b.o\": duplicate symbol 'f', first defined in \"a.o\".");
+
+        // Global definitions override weak ones.
+        let mut weak = callee("f", "weak.o");
+        weak.symbols.iter_mut().find(|(name, _)| name == "f").unwrap().1.binding = ELFSymbolBinding::Weak;
+        let linker = Linker::new(vec![caller("f"), weak, callee("f", "b.o")], &[]).unwrap();
+        assert_eq!(linker.globals["f"].0, 2);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use super::{Addr, Program};
+
+/// Number of encoded bytes shown per listing row before continuing on the next row.
+const BYTES_PER_ROW: usize = 8;
+
+/// Generates an assembly listing: every source line alongside the file offset, virtual address
+/// and encoded bytes it produced. Included files are expanded in place.
+pub struct Listing {
+    instrs: HashMap<(String, usize), Vec<(Addr, Vec<u8>)>>,
+    labels: HashMap<(String, usize), Addr>,
+    constants: HashMap<(String, usize), u32>,
+    includes: HashMap<(String, usize), String>,
+    output: String,
+}
+
+impl Listing {
+    /// Generates the listing for the program assembled from the source file at 'path'.
+    pub fn generate(program: &Program, path: &str) -> std::io::Result<String> {
+        let mut listing = Listing {
+            instrs: HashMap::new(),
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            includes: HashMap::new(),
+            output: String::new(),
+        };
+
+        // Instructions following on from the last one of their line are listed together, so a
+        // line repeated by TIMES is one run of bytes.
+        for (addr, bytes, location) in program.encode_with_locations() {
+            if let Some(location) = location {
+                let key = (location.file.clone(), location.line_no);
+                let runs = listing.instrs.entry(key).or_default();
+                match runs.last_mut() {
+                    Some((start, run)) if *start + run.len() as u64 == addr => run.extend(bytes),
+                    _ => runs.push((addr, bytes)),
+                }
+            }
+        }
+
+        for (block, addr) in program.blocks() {
+            if let Some(location) = block.location() {
+                listing.labels.insert((location.file.clone(), location.line_no), addr);
+            }
+        }
+
+        for constant in &program.constants {
+            let key = (constant.location.file.clone(), constant.location.line_no);
+            listing.constants.insert(key, constant.value);
+        }
+
+        for (location, included) in &program.includes {
+            listing.includes.insert((location.file.clone(), location.line_no), included.clone());
+        }
+
+        writeln!(listing.output, " line      offset   address  {:<w$} source", "bytes", w = BYTES_PER_ROW * 2 + 1).unwrap();
+        listing.list_file(path, 0)?;
+        Ok(listing.output)
+    }
+
+    /// Lists every line in a file, recursing into included files.
+    fn list_file(&mut self, path: &str, depth: usize) -> std::io::Result<()> {
+        let code = std::fs::read_to_string(path)?;
+        let marker = if depth == 0 { String::new() } else { format!("<{}>", depth) };
+
+        for (i, text) in code.lines().enumerate() {
+            let key = (path.to_string(), i + 1);
+            let prefix = format!("{:>5} {:<4}", i + 1, marker);
+
+            match (self.instrs.get(&key), self.labels.get(&key), self.constants.get(&key)) {
+                // A line assembled more than once, e.g. in a %rep block, only lists the first time.
+                (Some(runs), _, _) => {
+                    let (addr, bytes) = &runs[0];
+                    let chunks: Vec<&[u8]> = if bytes.is_empty() { vec![&[]] } else { bytes.chunks(BYTES_PER_ROW).collect() };
+                    for (j, chunk) in chunks.iter().enumerate() {
+                        let mut hex: String = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+                        if j + 1 < chunks.len() { hex.push('-'); }
+                        let row_addr = *addr + (j * BYTES_PER_ROW) as u64;
+                        let source = if j == 0 { text } else { "" };
+                        Self::row(&mut self.output, &prefix, Some(row_addr), &hex, source);
+                    }
+                    if runs.len() > 1 {
+                        Self::row(&mut self.output, &prefix, None, &format!("<rep {}>", runs.len()), "");
+                    }
+                }
+                (None, Some(addr), _) => Self::row(&mut self.output, &prefix, Some(*addr), "", text),
+                (None, None, Some(value)) => Self::row(&mut self.output, &prefix, None, &format!("= {:08X}", value), text),
+                (None, None, None) => Self::row(&mut self.output, &prefix, None, "", text),
+            }
+
+            if let Some(included) = self.includes.get(&key).cloned() {
+                self.list_file(&included, depth + 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn row(output: &mut String, prefix: &str, addr: Option<Addr>, bytes: &str, text: &str) {
+        let addr = match addr {
+            Some(addr) => format!("{:08X} {:08X}", addr.addr, addr.vaddr),
+            None => " ".repeat(17),
+        };
+        let line = format!("{} {} {:<w$} {}", prefix, addr, bytes, text, w = BYTES_PER_ROW * 2 + 1);
+        writeln!(output, "{}", line.trim_end()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::CodeGenerator;
+
+    #[test]
+    fn repeated_lines() {
+        let path = std::env::temp_dir().join("tasm_listing_repeated.s");
+        std::fs::write(&path, "_start:\n%rep 3\n    inc eax\n    dec ebx\n%endrep\n    TIMES 10 DB 0\n").unwrap();
+        let program = CodeGenerator::generate(&path, &[], &[]).ok().unwrap();
+        let listing = Listing::generate(&program, &path.display().to_string()).unwrap();
+
+        // Not laid out, so addresses are offsets into the segment.
+        let rows: Vec<&str> = listing.lines().skip(1).collect();
+        assert_eq!(rows, vec![
+            "    1      00000000 00000000                   _start:",
+            "    2                                          %rep 3",
+            "    3      00000000 00000000 40                    inc eax",
+            "    3                        <rep 3>",
+            "    4      00000001 00000001 4B                    dec ebx",
+            "    4                        <rep 3>",
+            "    5                                          %endrep",
+            "    6      00000006 00000006 0000000000000000-     TIMES 10 DB 0",
+            "    6      0000000E 0000000E 0000",
+        ]);
+    }
+}
@@ -0,0 +1,194 @@
+use super::SourceLocation;
+
+/// How deeply macros can expand inside each other before assuming the expansion never ends.
+pub const MAX_MACRO_DEPTH: usize = 64;
+
+/// What `%%<label>` labels start with, followed by the number of the expansion.
+pub const LOCAL_LABEL_PREFIX: &str = "__macro";
+
+/// A macro defined by `%macro <name> <params> [defaults] ... %endmacro`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Macro {
+    pub name: String,
+    /// The fewest arguments the macro takes.
+    pub min_args: usize,
+    /// The most arguments the macro takes, `None` when it takes any number (`1-*`).
+    pub max_args: Option<usize>,
+    /// The values of the optional parameters when they're left out, in order.
+    pub defaults: Vec<String>,
+    /// The lines between `%macro` and `%endmacro`.
+    pub body: Vec<String>,
+    /// Where the `%macro` line is.
+    pub location: SourceLocation,
+}
+
+impl Macro {
+    /// Gets the location of line 'line_no' (from 1) of the macro's body.
+    pub fn body_location(&self, line_no: usize) -> SourceLocation {
+        SourceLocation { file: self.location.file.clone(), line_no: self.location.line_no + line_no }
+    }
+
+    /// Expands the macro with the given arguments. `%1`, `%2`... are replaced by the arguments,
+    /// `%0` by the number of arguments, `%*` by all of them separated by commas and `%%<label>`
+    /// by a label unique to expansion 'id'.
+    pub fn expand(&self, args: &[String], id: usize) -> Result<String, String> {
+        if args.len() < self.min_args || self.max_args.is_some_and(|max| args.len() > max) {
+            let expected = match self.max_args {
+                Some(max) if max == self.min_args => format!("{}", max),
+                Some(max) => format!("{} to {}", self.min_args, max),
+                None => format!("at least {}", self.min_args),
+            };
+            return Err(format!("macro '{}' takes {} arguments, got {}.", self.name, expected, args.len()));
+        }
+
+        // Left out optional parameters take their default, or are empty without one.
+        let mut args = args.to_vec();
+        for idx in args.len()..self.max_args.unwrap_or(0) {
+            args.push(self.defaults.get(idx - self.min_args).cloned().unwrap_or_default());
+        }
+
+        let mut expansion = String::new();
+        for line in &self.body {
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                if c != '%' {
+                    expansion.push(c);
+                    continue;
+                }
+
+                match chars.peek() {
+                    // '%%' followed by a name is a local label, otherwise it's the signed modulo
+                    // operator.
+                    Some('%') => {
+                        chars.next();
+                        if chars.peek().is_some_and(|c| *c == '_' || c.is_ascii_alphabetic()) {
+                            expansion.push_str(&format!("{}{}_", LOCAL_LABEL_PREFIX, id));
+                        } else {
+                            expansion.push_str("%%");
+                        }
+                    }
+                    Some('*') => {
+                        chars.next();
+                        expansion.push_str(&args.join(", "));
+                    }
+                    Some(c) if c.is_ascii_digit() => {
+                        let mut digits = String::new();
+                        while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                            digits.push(*c);
+                            chars.next();
+                        }
+                        match digits.parse::<usize>().unwrap() {
+                            0 => expansion.push_str(&args.len().to_string()),
+                            n if n <= args.len() => expansion.push_str(&args[n - 1]),
+                            n => return Err(format!("macro '{}' was given {} arguments, there's no parameter %{}.", self.name, args.len(), n)),
+                        }
+                    }
+                    _ => expansion.push(c),
+                }
+            }
+            expansion.push('\n');
+        }
+
+        Ok(expansion)
+    }
+}
+
+/// Splits the arguments of a macro call at the commas outside of strings and brackets,
+/// trimming each one. Arguments wrapped in braces (`{a, b}`) can contain commas.
+pub fn split_args(args: &str) -> Vec<String> {
+    let mut split = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+
+    for c in args.chars() {
+        let in_string = quote.is_some();
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' | '\'' | '`' if quote.is_none() => quote = Some(c),
+            c if quote == Some(c) => quote = None,
+            '(' | '[' | '{' if !in_string => depth += 1,
+            ')' | ']' | '}' if !in_string => depth -= 1,
+            ',' if !in_string && depth == 0 => {
+                split.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => (),
+        }
+        current.push(c);
+    }
+
+    if !current.trim().is_empty() || !split.is_empty() {
+        split.push(current.trim().to_string());
+    }
+
+    // Braces only group the argument.
+    split.into_iter()
+        .map(|arg| match arg.strip_prefix('{').and_then(|arg| arg.strip_suffix('}')) {
+            Some(inner) => inner.trim().to_string(),
+            None => arg,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn print_macro(min_args: usize, max_args: Option<usize>) -> Macro {
+        Macro {
+            name: "print".to_string(),
+            min_args,
+            max_args,
+            defaults: vec!["1".to_string()],
+            body: vec![
+                "%%loop:".to_string(),
+                "    mov ecx, %1".to_string(),
+                "    mov ebx, %2 ; %0 args".to_string(),
+                "    DB %*".to_string(),
+                "    jnz %%loop".to_string(),
+            ],
+            location: SourceLocation { file: "test.s".to_string(), line_no: 3 },
+        }
+    }
+
+    #[test]
+    fn expand() {
+        let args = split_args("_msg, 2");
+        assert_eq!(print_macro(2, Some(2)).expand(&args, 7).unwrap(),
+            "__macro7_loop:\n    mov ecx, _msg\n    mov ebx, 2 ; 2 args\n    DB _msg, 2\n    jnz __macro7_loop\n");
+
+        // The second parameter is optional.
+        let expansion = print_macro(1, Some(2)).expand(&split_args("_msg"), 8).unwrap();
+        assert!(expansion.contains("mov ebx, 1 ; 2 args"));
+
+        // Any number of arguments.
+        let expansion = print_macro(2, None).expand(&split_args("\"a, b\", 2, 3"), 9).unwrap();
+        assert!(expansion.contains("DB \"a, b\", 2, 3"));
+        assert!(expansion.contains("; 3 args"));
+
+        assert_eq!(print_macro(2, Some(2)).expand(&split_args("_msg"), 10).err().unwrap(),
+            "macro 'print' takes 2 arguments, got 1.");
+        assert_eq!(print_macro(2, None).expand(&[], 10).err().unwrap(),
+            "macro 'print' takes at least 2 arguments, got 0.");
+        assert_eq!(print_macro(0, None).expand(&split_args("_msg"), 11).err().unwrap(),
+            "macro 'print' was given 1 arguments, there's no parameter %2.");
+        assert_eq!(print_macro(1, None).body_location(2).line_no, 5);
+
+        // '%%' not followed by a name is the signed modulo operator.
+        let body = vec!["    mov eax, %1 %% 3".to_string(), "    mov ebx, %1%%%%done".to_string()];
+        let modulo = Macro { body, ..print_macro(1, Some(1)) };
+        assert_eq!(modulo.expand(&split_args("x"), 12).unwrap(), "    mov eax, x %% 3\n    mov ebx, x%%__macro12_done\n");
+    }
+
+    #[test]
+    fn args() {
+        assert_eq!(split_args(""), Vec::<String>::new());
+        assert_eq!(split_args(" eax , [ebx + 4], (1, 2)"), vec!["eax", "[ebx + 4]", "(1, 2)"]);
+        assert_eq!(split_args("{1, 2}, \"x,y\""), vec!["1, 2", "\"x,y\""]);
+        assert_eq!(split_args("'a,\"', `\\`,`, ','"), vec!["'a,\"'", "`\\`,`", "','"]);
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt::Write;
+use super::elf::ELF;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum MapFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+pub enum MapEntryKind {
+    Segment,
+    Label,
+    Constant,
+}
+
+/// A single line in the symbol map. Constants have no file offset or size and use their value
+/// as the address.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MapEntry {
+    pub kind: MapEntryKind,
+    pub name: String,
+    pub offset: Option<u64>,
+    pub addr: u64,
+    pub size: Option<u64>,
+}
+
+/// A listing of every segment, label and EQU constant in an ELF binary sorted by address.
+pub struct SymbolMap {
+    pub entries: Vec<MapEntry>,
+}
+
+impl MapEntryKind {
+    fn name(&self) -> &'static str {
+        match self {
+            MapEntryKind::Segment => "segment",
+            MapEntryKind::Label => "label",
+            MapEntryKind::Constant => "constant",
+        }
+    }
+}
+
+impl SymbolMap {
+    /// Constructs the symbol map for an ELF binary.
+    pub fn new(elf: &ELF) -> SymbolMap {
+        let mut entries = Vec::new();
+
+        for (idx, segment) in elf.program.segments().iter().enumerate() {
+            entries.push(MapEntry {
+                kind: MapEntryKind::Segment,
+                name: segment.name.clone(),
+                offset: Some(segment.start.addr),
+                addr: segment.start.vaddr,
+                size: Some(elf.program.segment_len(idx) as u64),
+            });
+        }
+
+        for (label, addr, len) in elf.program.labels() {
+            entries.push(MapEntry {
+                kind: MapEntryKind::Label,
+                name: label.to_string(),
+                offset: Some(addr.addr),
+                addr: addr.vaddr,
+                size: Some(len as u64),
+            });
+        }
+
+        for constant in &elf.program.constants {
+            entries.push(MapEntry {
+                kind: MapEntryKind::Constant,
+                name: constant.name.clone(),
+                offset: None,
+                addr: constant.value as u64,
+                size: None,
+            });
+        }
+
+        // Sort by address, keeping the order stable between runs for entries at the same address.
+        entries.sort_by(|a, b| (a.addr, a.kind, &a.name).cmp(&(b.addr, b.kind, &b.name)));
+        SymbolMap { entries }
+    }
+
+    /// Formats the map as text or JSON.
+    pub fn format(&self, format: MapFormat) -> String {
+        match format {
+            MapFormat::Text => self.as_text(),
+            MapFormat::Json => self.as_json(),
+        }
+    }
+
+    /// Formats the map as a table with one entry per line.
+    pub fn as_text(&self) -> String {
+        let mut output = String::new();
+        writeln!(output, "{:<9} {:<8} {:<8} {:<8} name", "kind", "offset", "address", "size").unwrap();
+
+        for entry in &self.entries {
+            let offset = entry.offset.map(|x| format!("{:08X}", x)).unwrap_or("-".to_string());
+            let size = entry.size.map(|x| format!("{:08X}", x)).unwrap_or("-".to_string());
+            writeln!(output, "{:<9} {:<8} {:08X} {:<8} {}", entry.kind.name(), offset, entry.addr, size, entry.name).unwrap();
+        }
+
+        output
+    }
+
+    /// Formats the map as a JSON object containing an array of entries.
+    pub fn as_json(&self) -> String {
+        let mut output = String::new();
+        writeln!(output, "{{").unwrap();
+        writeln!(output, "  \"entries\": [").unwrap();
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            let offset = entry.offset.map(|x| x.to_string()).unwrap_or("null".to_string());
+            let size = entry.size.map(|x| x.to_string()).unwrap_or("null".to_string());
+            let separator = if i + 1 < self.entries.len() { "," } else { "" };
+            writeln!(output, "    {{ \"kind\": \"{}\", \"name\": \"{}\", \"offset\": {}, \"address\": {}, \"size\": {} }}{}",
+                entry.kind.name(), escape_json(&entry.name), offset, entry.addr, size, separator).unwrap();
+        }
+
+        writeln!(output, "  ]").unwrap();
+        writeln!(output, "}}").unwrap();
+        output
+    }
+}
+
+/// Escapes a string for use inside a JSON string literal.
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_map() {
+        let map = SymbolMap {
+            entries: vec![
+                MapEntry { kind: MapEntryKind::Constant, name: "msg_len".to_string(), offset: None, addr: 0x0D, size: None },
+                MapEntry { kind: MapEntryKind::Label, name: "_msg".to_string(), offset: Some(0x54), addr: 0x08048054, size: Some(0x0D) },
+            ],
+        };
+
+        assert_eq!(map.as_text(), "\
+kind      offset   address  size     name
+constant  -        0000000D -        msg_len
+label     00000054 08048054 0000000D _msg
+");
+        assert_eq!(map.as_json(), r#"{
+  "entries": [
+    { "kind": "constant", "name": "msg_len", "offset": null, "address": 13, "size": null },
+    { "kind": "label", "name": "_msg", "offset": 84, "address": 134512724, "size": 13 }
+  ]
+}
+"#);
+    }
+
+    #[test]
+    fn internal_labels() {
+        let path = std::env::temp_dir().join("tasm_map_internal.s");
+        std::fs::write(&path, "%macro m 0\n%%skip:\n%endmacro\nENTRY _start\n_start:\n1:\n    jmp 1b\n    m\n").unwrap();
+        let program = crate::prelude::CodeGenerator::generate(&path, &[], &[]).ok().unwrap();
+        let elf = ELF::new_x86(program, crate::prelude::elf::OutputKind::Executable).unwrap();
+
+        // Numeric and macro labels are named by the assembler, so they're left out.
+        let map = SymbolMap::new(&elf);
+        let names: Vec<&str> = map.entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec![".text", "_start"]);
+    }
+}
@@ -1,11 +1,19 @@
 pub mod addr;
 pub mod elf;
+pub mod dwarf;
+pub mod dynamic;
 pub mod instruction;
 pub mod program;
 pub mod lexer;
 pub mod ast;
 pub mod parser;
+pub mod macros;
 pub mod code_gen;
+pub mod listing;
+pub mod object;
+pub mod archive;
+pub mod linker;
+pub mod map;
 mod utils;
 
 pub use addr::*;
@@ -84,8 +92,10 @@ pub enum Value {
     UShort(u16),
     UInt(u32),
     ULong(u64),
-    Pointer(String),
-    RelPointer(String),
+    /// The address of a label plus an addend.
+    Pointer(String, i32),
+    /// The address of a label plus an addend, relative to the end of the instruction.
+    RelPointer(String, i32),
 }
 
 impl Value {
@@ -95,8 +105,8 @@ impl Value {
             Value::UShort(_) => 2,
             Value::UInt(_) => 4,
             Value::ULong(_) => 8,
-            Value::Pointer(_) => 4,
-            Value::RelPointer(_) => 4,
+            Value::Pointer(..) => 4,
+            Value::RelPointer(..) => 4,
         }
     }
 
@@ -106,13 +116,13 @@ impl Value {
             Value::UShort(x) => utils::dump_word(*x, Endianness::Little).to_vec(),
             Value::UInt(x) => utils::dump_dword(*x, Endianness::Little).to_vec(),
             Value::ULong(x) => utils::dump_qword(*x, Endianness::Little).to_vec(),
-            Value::Pointer(label) => {
-                let x = program.get_addr(label).unwrap_or_default().vaddr as u32;
+            Value::Pointer(label, addend) => {
+                let x = (program.get_addr(label).unwrap_or_default().vaddr as u32).wrapping_add(*addend as u32);
                 utils::dump_dword(x, Endianness::Little).to_vec()
             }
-            Value::RelPointer(label) => {
-                let x = program.get_addr(label).unwrap_or_default().addr as i32;
-                let delta = x - (addr.addr as i32);
+            Value::RelPointer(label, addend) => {
+                let x = (program.get_addr(label).unwrap_or_default().vaddr as i32).wrapping_add(*addend);
+                let delta = x.wrapping_sub(addr.vaddr as i32);
                 utils::dump_dword(delta as u32, Endianness::Little).to_vec()
             }
         }
@@ -0,0 +1,495 @@
+use super::elf::*;
+use super::{utils::*, Addr, Endianness, Program, Value};
+
+/// A section of a relocatable object holding code or data along with the relocations applied
+/// to it.
+#[derive(Clone)]
+pub struct ObjectSection {
+    pub name: String,
+    /// The index of the section's header in the object, referenced by symbols.
+    pub index: u16,
+    pub header: ELFSectionHeader,
+    /// The section's contents. Empty for sections that take no space in the file (.bss).
+    pub data: Vec<u8>,
+    pub relocations: Vec<ELFRelocation>,
+}
+
+/// An ELF32 i386 relocatable object (ET_REL), as produced by `tasm -c`, `nasm -f elf32` or
+/// `gcc -m32 -c`. Only the sections loaded into memory are kept, debug information and notes
+/// are dropped.
+#[derive(Clone)]
+pub struct ObjectFile {
+    /// Where the object was read from, used when reporting errors.
+    pub path: String,
+    pub sections: Vec<ObjectSection>,
+    /// Every symbol along with its name, starting with the null symbol so relocations can index
+    /// it directly. Local symbols come first.
+    pub symbols: Vec<(String, ELFSymbol)>,
+}
+
+/// Section types read from relocatable objects.
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+
+/// The fields of a section header read from a relocatable object.
+struct RawSectionHeader {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: u64,
+    offset: usize,
+    size: usize,
+    link: usize,
+    info: u32,
+    align: u64,
+}
+
+impl ObjectSection {
+    /// Gets the section's length in memory.
+    pub fn len(&self) -> usize {
+        self.header.sh_size as usize
+    }
+
+    /// Gets the section's contents, zero filling sections that take no space in the file.
+    pub fn contents(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        data.resize(self.len(), 0);
+        data
+    }
+}
+
+impl ObjectFile {
+    /// Parses a relocatable object read from 'path'.
+    pub fn parse(path: &str, data: &[u8]) -> Result<ObjectFile, String> {
+        let endianness = Endianness::Little;
+        let error = |message: &str| Err(format!("\"{}\": {}", path, message));
+
+        if data.len() < 0x34 || data[0..4] != [0x7F, 0x45, 0x4C, 0x46] {
+            return error("not an ELF file.");
+        }
+        if data[4] != 1 || data[5] != 1 || read_word(data, 0x12, endianness) != 3 {
+            return error("not a 32 bit little endian i386 object.");
+        }
+        if read_word(data, 0x10, endianness) != 1 {
+            return error("not a relocatable object, expected one made by 'tasm -c', 'nasm -f elf32' or 'gcc -m32 -c'.");
+        }
+
+        let shoff = read_dword(data, 0x20, endianness) as usize;
+        let shnum = read_word(data, 0x30, endianness) as usize;
+        let shstrndx = read_word(data, 0x32, endianness) as usize;
+        let header_len = ELFSectionHeader::new(ELFClass::X86, 0, ELFSectionType::Null).len();
+        if shoff + shnum * header_len > data.len() {
+            return error("section header table is truncated.");
+        }
+
+        let headers: Vec<RawSectionHeader> = (0..shnum)
+            .map(|idx| {
+                let at = |field: usize| read_dword(data, shoff + idx * header_len + field, endianness);
+                RawSectionHeader {
+                    sh_name: at(0x00),
+                    sh_type: at(0x04),
+                    sh_flags: at(0x08) as u64,
+                    offset: at(0x10) as usize,
+                    size: at(0x14) as usize,
+                    link: at(0x18) as usize,
+                    info: at(0x1C),
+                    align: at(0x20) as u64,
+                }
+            })
+            .collect();
+        if headers.iter().any(|h| h.sh_type != SHT_NOBITS && h.offset + h.size > data.len()) {
+            return error("section contents are truncated.");
+        }
+
+        for header in &headers {
+            let (table, entry_len) = match header.sh_type {
+                SHT_SYMTAB => ("symbol", ELFSymbol::len(ELFClass::X86)),
+                SHT_REL => ("relocation", ELFRelocation::len(ELFClass::X86)),
+                _ => continue,
+            };
+            if header.size % entry_len != 0 {
+                return error(&format!("{} table size isn't a multiple of its entry size.", table));
+            }
+        }
+
+        // Section and symbol names are read from the string tables they link to.
+        let is_strtab = |idx: usize| headers.get(idx).is_some_and(|h| h.sh_type == SHT_STRTAB);
+        if !is_strtab(shstrndx) {
+            return error("section names aren't in a string table.");
+        }
+        if headers.iter().any(|h| h.sh_type == SHT_SYMTAB && !is_strtab(h.link)) {
+            return error("symbol table isn't linked to a string table.");
+        }
+        let name = |strtab: usize, offset: u32| -> String {
+            let strings = &data[headers[strtab].offset..headers[strtab].offset + headers[strtab].size];
+            // A name starting past the end of the table is empty.
+            let start = (offset as usize).min(strings.len());
+            let end = strings[start..].iter().position(|b| *b == 0).map_or(strings.len(), |len| start + len);
+            String::from_utf8_lossy(&strings[start..end]).to_string()
+        };
+
+        let mut object = ObjectFile { path: path.to_string(), sections: Vec::new(), symbols: Vec::new() };
+        for (idx, raw) in headers.iter().enumerate() {
+            if raw.sh_flags & SHF_ALLOC == 0 { continue; }
+            let (section_type, contents) = match raw.sh_type {
+                SHT_PROGBITS => (ELFSectionType::ProgramBits, data[raw.offset..raw.offset + raw.size].to_vec()),
+                SHT_NOBITS => (ELFSectionType::NoBits, Vec::new()),
+                _ => continue,
+            };
+
+            let mut header = ELFSectionHeader::new(ELFClass::X86, 0, section_type);
+            header.sh_flags = raw.sh_flags;
+            header.sh_size = raw.size as u64;
+            header.sh_addralign = raw.align;
+            object.sections.push(ObjectSection {
+                name: name(shstrndx, raw.sh_name),
+                index: idx as u16,
+                header,
+                data: contents,
+                relocations: Vec::new(),
+            });
+        }
+
+        for &RawSectionHeader { sh_type, offset, size, link, info, .. } in &headers {
+            match sh_type {
+                SHT_SYMTAB => {
+                    for at in (offset..offset + size).step_by(ELFSymbol::len(ELFClass::X86)) {
+                        let st_info = data[at + 12];
+                        let binding = match st_info >> 4 {
+                            0 => ELFSymbolBinding::Local,
+                            1 => ELFSymbolBinding::Global,
+                            2 => ELFSymbolBinding::Weak,
+                            x => return error(&format!("unsupported symbol binding {}.", x)),
+                        };
+                        let st_type = match st_info & 0xF {
+                            1 => ELFSymbolType::Object,
+                            2 => ELFSymbolType::Function,
+                            3 => ELFSymbolType::Section,
+                            4 => ELFSymbolType::File,
+                            _ => ELFSymbolType::NoType,
+                        };
+                        let visibility = match data[at + 13] & 0x3 {
+                            1 | 2 => ELFSymbolVisibility::Hidden,
+                            _ => ELFSymbolVisibility::Default,
+                        };
+                        let st_name = read_dword(data, at, endianness);
+                        let symbol = ELFSymbol {
+                            class: ELFClass::X86,
+                            st_name,
+                            st_value: read_dword(data, at + 4, endianness) as u64,
+                            st_size: read_dword(data, at + 8, endianness) as u64,
+                            binding,
+                            st_type,
+                            visibility,
+                            st_shndx: read_word(data, at + 14, endianness),
+                        };
+                        object.symbols.push((name(link, st_name), symbol));
+                    }
+                }
+                SHT_REL => {
+                    // Relocations of sections that aren't loaded (e.g. debug information) are
+                    // dropped along with the section.
+                    let Some(section) = object.sections.iter_mut().find(|s| s.index as u32 == info) else { continue };
+                    for at in (offset..offset + size).step_by(ELFRelocation::len(ELFClass::X86)) {
+                        let r_info = read_dword(data, at + 4, endianness);
+                        section.relocations.push(ELFRelocation {
+                            class: ELFClass::X86,
+                            r_offset: read_dword(data, at, endianness) as u64,
+                            r_sym: r_info >> 8,
+                            r_type: r_info & 0xFF,
+                        });
+                    }
+                }
+                SHT_RELA => return error("relocations with explicit addends (SHT_RELA) aren't supported on i386."),
+                _ => (),
+            }
+        }
+
+        // Sections are named by the symbols that refer to them.
+        for (name, symbol) in &mut object.symbols {
+            if symbol.st_type == ELFSymbolType::Section && name.is_empty() {
+                if let Some(section) = object.sections.iter().find(|s| s.index == symbol.st_shndx) {
+                    *name = section.name.clone();
+                }
+            }
+        }
+
+        Ok(object)
+    }
+
+    /// Converts an assembled program into a relocatable object. Each segment becomes a section
+    /// starting at address 0, named with a leading '.' (SEGMENT data becomes .data) so it's
+    /// merged with the sections of other assemblers and compilers. Labels become symbols and
+    /// externals undefined symbols. Pointers to labels get relocations against the section
+    /// symbol of the label's segment, relative pointers only when the label is in a different
+    /// segment.
+    pub fn from_program(mut program: Program, path: &str) -> Result<ObjectFile, String> {
+        if let Some(library) = program.needed.first() {
+            return Err(format!("relocatable objects can't depend on shared libraries, remove NEEDED \"{}\".", library));
+        }
+        for idx in 0..program.segments().len() {
+            program.set_segment_start(idx, Addr::default());
+        }
+
+        let mut object = ObjectFile { path: path.to_string(), sections: Vec::new(), symbols: Vec::new() };
+        object.symbols.push((String::new(), ELFSymbol::null(ELFClass::X86)));
+        for (idx, segment) in program.segments().iter().enumerate() {
+            let name = if segment.name.starts_with('.') { segment.name.clone() } else { format!(".{}", segment.name) };
+            let mut header = ELFSectionHeader::new(ELFClass::X86, 0, ELFSectionType::ProgramBits);
+            header.sh_flags = section_flags(&name);
+            header.sh_size = program.segment_len(idx) as u64;
+            header.sh_addralign = segment.align.max(4);
+            object.sections.push(ObjectSection {
+                name: name.clone(),
+                index: 1 + idx as u16,
+                header,
+                data: program.segment_as_vec(idx),
+                relocations: Vec::new(),
+            });
+
+            let mut symbol = ELFSymbol::null(ELFClass::X86);
+            symbol.st_type = ELFSymbolType::Section;
+            symbol.st_shndx = 1 + idx as u16;
+            object.symbols.push((name, symbol));
+        }
+
+        // Local symbols must come before the rest.
+        let mut labels: Vec<(String, ELFSymbol)> = program.labels().into_iter()
+            .map(|(label, addr, len)| (label.to_string(), ELFSymbol {
+                class: ELFClass::X86,
+                st_name: 0,
+                st_value: addr.vaddr,
+                st_size: len as u64,
+                binding: program.binding(label).into(),
+                st_type: ELFSymbolType::NoType,
+                visibility: program.visibility(label).into(),
+                st_shndx: 1 + program.segment_of(label).unwrap() as u16,
+            }))
+            .collect();
+        labels.extend(program.externs.iter().map(|external| (external.name.clone(), ELFSymbol {
+            class: ELFClass::X86,
+            st_name: 0,
+            st_value: 0,
+            st_size: 0,
+            binding: program.binding(&external.name).into(),
+            st_type: ELFSymbolType::NoType,
+            visibility: program.visibility(&external.name).into(),
+            st_shndx: SHN_UNDEF,
+        })));
+        labels.sort_by_key(|(_, symbol)| symbol.binding != ELFSymbolBinding::Local);
+        object.symbols.extend(labels);
+
+        for (segment, field, value, _) in program.pointers() {
+            let (r_type, label, offset) = match value {
+                Value::Pointer(label, addend) => (R_386_32, label, *addend as u32),
+                Value::RelPointer(label, addend) => (R_386_PC32, label, *addend as u32),
+                _ => continue,
+            };
+
+            // Labels are referred to by their section's symbol with the label's offset as the
+            // addend, externals by their own symbol.
+            let (r_sym, target) = match program.segment_of(label) {
+                Some(idx) if r_type == R_386_PC32 && idx == segment => continue,
+                Some(idx) => (1 + idx as u32, program.get_addr(label).unwrap().vaddr as u32),
+                None => match object.symbols.iter().position(|(name, s)| name == label && s.st_shndx == SHN_UNDEF) {
+                    Some(idx) => (idx as u32, 0),
+                    None => return Err(format!("undefined symbol '{}'.", label)),
+                },
+            };
+
+            // The addend of a relative pointer is taken from the start of the field rather than
+            // the end of the instruction.
+            let target = target.wrapping_add(offset);
+            let addend = if r_type == R_386_PC32 { target.wrapping_sub(4) } else { target };
+            let section = &mut object.sections[segment];
+            let at = field.vaddr as usize;
+            section.data[at..at + 4].copy_from_slice(&dump_dword(addend, Endianness::Little));
+            section.relocations.push(ELFRelocation { class: ELFClass::X86, r_offset: field.vaddr, r_sym, r_type });
+        }
+
+        Ok(object)
+    }
+
+    /// Converts the object into a vector of bytes: the ELF header, the contents of each section
+    /// followed by its .rel section, then .symtab, .strtab, .shstrtab and the section header
+    /// table. Sections are renumbered in order.
+    pub fn as_vec(&self) -> Vec<u8> {
+        let endianness = Endianness::Little;
+        let mut shstrtab = ELFStringTable::new();
+        let mut strtab = ELFStringTable::new();
+        let mut header = ELFHeader::new_x86(0);
+        header.elftype = ELFType::Relocatable;
+        header.program_table = 0;
+        header.program_count = 0;
+
+        let renumber = |index: u16| match self.sections.iter().position(|s| s.index == index) {
+            Some(idx) => 1 + idx as u16,
+            None => index,
+        };
+        let symtab_index = 1 + self.sections.len() + self.sections.iter().filter(|s| !s.relocations.is_empty()).count();
+
+        let mut sections = vec![ELFSection { header: ELFSectionHeader::new(ELFClass::X86, 0, ELFSectionType::Null), data: Vec::new() }];
+        let mut rel_sections = Vec::new();
+        for (idx, section) in self.sections.iter().enumerate() {
+            let mut header = section.header.clone();
+            header.sh_name = shstrtab.push(&section.name);
+            sections.push(ELFSection { header, data: section.data.clone() });
+
+            if section.relocations.is_empty() { continue; }
+            let mut header = ELFSectionHeader::new(ELFClass::X86, shstrtab.push(&format!(".rel{}", section.name)), ELFSectionType::Rel);
+            header.sh_link = symtab_index as u32;
+            header.sh_info = 1 + idx as u32;
+            header.sh_addralign = 4;
+            header.sh_entsize = ELFRelocation::len(ELFClass::X86) as u64;
+            let data = section.relocations.iter().flat_map(|r| r.as_vec(endianness)).collect();
+            rel_sections.push(ELFSection { header, data });
+        }
+        sections.extend(rel_sections);
+
+        let mut symtab = Vec::new();
+        for (name, symbol) in &self.symbols {
+            let mut symbol = symbol.clone();
+            symbol.st_name = if name.is_empty() || symbol.st_type == ELFSymbolType::Section { 0 } else { strtab.push(name) };
+            if symbol.st_shndx != SHN_UNDEF && symbol.st_shndx < SHN_ABS {
+                symbol.st_shndx = renumber(symbol.st_shndx);
+            }
+            symtab.extend_from_slice(&symbol.as_vec(endianness));
+        }
+        let mut symtab_header = ELFSectionHeader::new(ELFClass::X86, shstrtab.push(".symtab"), ELFSectionType::SymbolTable);
+        symtab_header.sh_link = symtab_index as u32 + 1;
+        symtab_header.sh_info = self.symbols.iter().position(|(_, s)| s.binding != ELFSymbolBinding::Local).unwrap_or(self.symbols.len()) as u32;
+        symtab_header.sh_addralign = 4;
+        symtab_header.sh_entsize = ELFSymbol::len(ELFClass::X86) as u64;
+        sections.push(ELFSection { header: symtab_header, data: symtab });
+
+        let mut strtab_header = ELFSectionHeader::new(ELFClass::X86, shstrtab.push(".strtab"), ELFSectionType::StringTable);
+        strtab_header.sh_addralign = 1;
+        sections.push(ELFSection { header: strtab_header, data: strtab.as_vec() });
+
+        let mut shstrtab_header = ELFSectionHeader::new(ELFClass::X86, shstrtab.push(".shstrtab"), ELFSectionType::StringTable);
+        shstrtab_header.sh_addralign = 1;
+        header.section_names = sections.len() as u16;
+        sections.push(ELFSection { header: shstrtab_header, data: shstrtab.as_vec() });
+
+        // Lay out the contents of the sections after the ELF header.
+        let mut offset = header.len() as u64;
+        for section in sections.iter_mut().skip(1) {
+            offset = offset.next_multiple_of(section.header.sh_addralign.max(1));
+            section.header.sh_offset = offset;
+            if section.header.sh_type != ELFSectionType::NoBits {
+                section.header.sh_size = section.data.len() as u64;
+                offset += section.data.len() as u64;
+            }
+        }
+        header.section_table = offset.next_multiple_of(4);
+        header.section_count = sections.len() as u16;
+
+        let mut dump = header.as_vec();
+        for section in &sections {
+            if section.data.is_empty() { continue; }
+            dump.resize(section.header.sh_offset as usize, 0);
+            dump.extend_from_slice(&section.data);
+        }
+        dump.resize(header.section_table as usize, 0);
+        for section in &sections {
+            dump.extend_from_slice(&section.header.as_vec(endianness));
+        }
+
+        dump
+    }
+}
+
+/// Gets the flags of the section holding the segment named 'name'. Code is executable,
+/// read-only data isn't writable and everything else is writable data.
+fn section_flags(name: &str) -> u64 {
+    if name == ".text" || name.starts_with(".text.") {
+        SHF_ALLOC | SHF_EXECINSTR
+    } else if name == ".rodata" || name.starts_with(".rodata.") {
+        SHF_ALLOC
+    } else {
+        SHF_ALLOC | SHF_WRITE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::{Extern, Instruction, SourceLocation};
+
+    #[test]
+    fn object_round_trip() {
+        let location = SourceLocation { file: "test.s".to_string(), line_no: 1 };
+        let mut program = Program::new();
+        let block = program.new_block("_start");
+        block.push(Instruction::Call(Value::RelPointer("puts".to_string(), 0)));
+        block.push(Instruction::Call(Value::RelPointer("_start".to_string(), 0)));
+        block.push(Instruction::Jump { condition: crate::prelude::JumpCondition::None, addr: Value::RelPointer("_msg".to_string(), 0) });
+        program.switch_segment("data");
+        program.new_block("_msg").push(Instruction::RawData(vec![0x41; 3]));
+        program.new_block("_ptr").push(Instruction::MovImmediate { register: crate::prelude::Register::EAX, value: Value::Pointer("_msg".to_string(), 0) });
+        program.declare("_start", location.clone());
+        program.externs.push(Extern { name: "puts".to_string(), location, addr: Addr::default() });
+
+        let object = ObjectFile::from_program(program, "test.o").unwrap();
+        let object = ObjectFile::parse("test.o", &object.as_vec()).unwrap();
+        let names: Vec<&str> = object.sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec![".text", ".data"]);
+        assert_eq!(object.sections[0].header.sh_flags, SHF_ALLOC | SHF_EXECINSTR);
+
+        // null, the two section symbols, the local labels, then _start and puts.
+        let symbols: Vec<(&str, ELFSymbolBinding, u16)> = object.symbols.iter()
+            .map(|(name, s)| (name.as_str(), s.binding, s.st_shndx))
+            .collect();
+        assert_eq!(symbols, vec![
+            ("", ELFSymbolBinding::Local, 0),
+            (".text", ELFSymbolBinding::Local, 1),
+            (".data", ELFSymbolBinding::Local, 2),
+            ("_msg", ELFSymbolBinding::Local, 2),
+            ("_ptr", ELFSymbolBinding::Local, 2),
+            ("_start", ELFSymbolBinding::Global, 1),
+            ("puts", ELFSymbolBinding::Global, 0),
+        ]);
+
+        // The call to puts and the jump to _msg are relocated, the call to _start isn't. The
+        // pointer to _msg is relocated against .data with the label's offset as the addend.
+        let relocation = |r_offset, r_sym, r_type| ELFRelocation { class: ELFClass::X86, r_offset, r_sym, r_type };
+        assert_eq!(object.sections[0].relocations, vec![relocation(1, 6, R_386_PC32), relocation(11, 2, R_386_PC32)]);
+        assert_eq!(object.sections[0].data[1..5], dump_dword(-4i32 as u32, Endianness::Little));
+        assert_eq!(object.sections[0].data[11..15], dump_dword(-4i32 as u32, Endianness::Little));
+        assert_eq!(object.sections[1].relocations, vec![relocation(4, 2, R_386_32)]);
+        assert_eq!(object.sections[1].data[4..8], dump_dword(0, Endianness::Little));
+    }
+
+    #[test]
+    fn object_malformed() {
+        let mut program = Program::new();
+        program.new_block("_start").push(Instruction::Return);
+        let data = ObjectFile::from_program(program, "test.o").unwrap().as_vec();
+
+        // Finds the offset of a field of the first section header of type 'sh_type'.
+        let shoff = read_dword(&data, 0x20, Endianness::Little) as usize;
+        let header = |sh_type: u32, field: usize| (0..).map(|idx| shoff + idx * 40)
+            .find(|at| read_dword(&data, at + 4, Endianness::Little) == sh_type).unwrap() + field;
+        let write = |data: &mut Vec<u8>, at: usize, value: u32| data[at..at + 4].copy_from_slice(&dump_dword(value, Endianness::Little));
+
+        // A symbol table ending part way through a symbol, at the end of the file.
+        let mut bad = data.clone();
+        let size = read_dword(&data, header(SHT_SYMTAB, 0x14), Endianness::Little);
+        write(&mut bad, header(SHT_SYMTAB, 0x14), size - 4);
+        write(&mut bad, header(SHT_SYMTAB, 0x10), data.len() as u32 - (size - 4));
+        assert_eq!(ObjectFile::parse("bad.o", &bad).err().unwrap(), "\"bad.o\": symbol table size isn't a multiple of its entry size.");
+
+        // Symbols named past the end of the string table have no name.
+        let mut bad = data.clone();
+        let symtab = read_dword(&data, header(SHT_SYMTAB, 0x10), Endianness::Little) as usize;
+        write(&mut bad, symtab + 32, 0xFFFF);
+        assert_eq!(ObjectFile::parse("bad.o", &bad).unwrap().symbols[2].0, "");
+
+        let mut bad = data.clone();
+        write(&mut bad, header(SHT_SYMTAB, 0x18), 0);
+        assert_eq!(ObjectFile::parse("bad.o", &bad).err().unwrap(), "\"bad.o\": symbol table isn't linked to a string table.");
+    }
+}
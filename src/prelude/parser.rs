@@ -3,7 +3,7 @@ use std::u32;
 use logos::{Logos, Lexer};
 
 use super::lexer::Token;
-use super::{Register, JumpCondition, Node};
+use super::{Register, JumpCondition, Node, macros::split_args};
 
 #[derive(Debug, Clone)]
 pub struct Error {
@@ -16,6 +16,8 @@ pub struct Parser<'a> {
     lexer: Lexer<'a, Token>,
     next: Option<Token>,
     line_no: usize,
+    /// How many conditional blocks the parser is inside.
+    conditional_depth: usize,
 }
 
 impl<'a> Parser<'a> {
@@ -25,7 +27,8 @@ impl<'a> Parser<'a> {
         let mut parser = Parser { 
             lexer,
             next: None,
-            line_no: 1 
+            line_no: 1,
+            conditional_depth: 0,
         };  
 
         parser.march(); // Feed the first token in
@@ -39,7 +42,7 @@ impl<'a> Parser<'a> {
 
     fn march(&mut self) -> Option<Token> {
         let ret = self.next.clone();
-        self.next = self.lexer.next().map(|x| x.unwrap());
+        self.next = self.lexer.next().map(|x| x.unwrap_or_else(|_| Token::Invalid(self.lexer.slice().to_string())));
         ret
     }
 
@@ -53,39 +56,90 @@ impl<'a> Parser<'a> {
 
 
     ///// Recursive descent parser /////
-    // program ::= (whitespace statement newline | whitespace newline)*
+    // program ::= block
     fn program(&mut self) -> Result<Node, Error> {
+        let stmts = self.block()?;
+
+        match self.peek() {
+            Some(_) => self.unmatched(),
+            None => Ok(Node::Program(stmts)),
+        }
+    }
+
+    /// Reports the directive ending a block that wasn't expected.
+    fn unmatched(&self) -> Result<Node, Error> {
+        match self.peek() {
+            Some(Token::Elif) => self.error("'%elif' without '%if'."),
+            Some(Token::Else) => self.error("'%else' without '%if'."),
+            Some(Token::EndIf) => self.error("'%endif' without '%if'."),
+            Some(Token::EndRep) => self.error("'%endrep' without '%rep'."),
+            token => self.error(&format!("unexpected token '{:?}'.", token)),
+        }
+    }
+
+    // block ::= (whitespace statement newline | whitespace newline)*
+    //      each statement is wrapped in a Node::Statement recording its line number, the block
+    //      ends at the end of the file or a line starting with ELIF, ELSE, ENDIF or ENDREP
+    fn block(&mut self) -> Result<Vec<Node>, Error> {
         let mut stmts = Vec::new();
 
         // repeat while there are still tokens left
-        while let Some(token) = self.peek() {
+        while self.peek().is_some() {
             self.whitespace(); 
+            if matches!(self.peek(), Some(Token::Elif) | Some(Token::Else) | Some(Token::EndIf) | Some(Token::EndRep)) {
+                break;
+            }
+
             match self.newline() {
                 Ok(_) => (),
                 Err(_) => {
-                    let node = self.statement()?;
-                    self.newline()?;
-                    stmts.push(node);
+                    let line_no = self.line_no;
+                    let node = match self.statement().and_then(|node| self.newline().map(|_| node)) {
+                        Ok(node) => node,
+                        // Inside a conditional the error is only reported if the branch is
+                        // assembled, so branches can hold code for other targets.
+                        Err(e) if self.conditional_depth > 0 => {
+                            while !matches!(self.peek(), Some(Token::Newline) | None) {
+                                self.march();
+                            }
+                            self.newline()?;
+                            Node::Invalid(e.message)
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    stmts.push(Node::Statement(line_no, Box::new(node)));
                 }
             }
         }
 
-        Ok(Node::Program(stmts))
+        Ok(stmts)
     }
 
     // statement ::= label_statement | instruction
     fn statement(&mut self) -> Result<Node, Error> {
         let token = self.peek();
         match token {
-            Some(Token::Identifier(ident)) => match self.label_statement() {
-                Ok(x) => Ok(x),
-                Err(_) => self.error(&format!("unknown instruction '{}'.", ident)),
-            },
+            Some(Token::Identifier(_)) => self.identifier_statement(),
+            Some(Token::Number(_)) => self.numeric_label_statement(),
+            Some(Token::Macro) => self.macro_statement(),
+            Some(Token::EndMacro) => self.error("'%endmacro' without '%macro'."),
+            Some(Token::If) | Some(Token::IfDef) | Some(Token::IfNDef) => self.if_statement(),
+            Some(Token::Rep) => self.rep_statement(),
+            Some(Token::Times) => self.times_statement(),
+            Some(Token::Align) => self.align_statement(),
             Some(Token::Entry) => self.entry_statement(),
+            Some(Token::Org) => self.org_statement(),
+            Some(Token::Segment) => self.segment_statement(),
+            Some(Token::Extern) => self.extern_statement(),
+            Some(Token::Needed) => self.needed_statement(),
+            Some(Token::Export) | Some(Token::Global) | Some(Token::Weak) | Some(Token::Hidden) => self.symbol_statement(),
             Some(Token::DS) => self.ds_statement(),
             Some(Token::Db) => self.db_statement(),
             Some(Token::DW) => self.dw_statement(),
             Some(Token::DLPseudo) => self.dl_statement(),
+            Some(Token::DQ) => self.dq_statement(),
+            Some(Token::DT) => self.dt_statement(),
+            Some(Token::Ascii) | Some(Token::Asciz) => self.ascii_statement(),
             Some(Token::Int) => self.int_statement(),
             Some(Token::Inc) => self.inc_statement(),
             Some(Token::Dec) => self.dec_statement(),
@@ -126,25 +180,266 @@ impl<'a> Parser<'a> {
             Some(Token::SHR) => self.shr_statement(),
             Some(Token::EQU) => self.equ_statement(),
             Some(Token::Include) => self.include_statement(),
+            Some(Token::IncBin) => self.incbin_statement(),
             _ => self.error(&format!("unexpected token '{:?}'.", token)),
         }
     }
 
-    // label_statement ::= identifier whitespace COLON
-    fn label_statement(&mut self) -> Result<Node, Error> {
+    // identifier_statement ::= identifier whitespace COLON | identifier whitespace rest_of_line
+    //      a label, or otherwise a macro call taking comma separated arguments
+    fn identifier_statement(&mut self) -> Result<Node, Error> {
         let ident = match self.march() {
             Some(Token::Identifier(x)) => x,
-            _ => { self.error("label requires a name.")?; String::new() } ,
+            _ => return self.error("label requires a name."),
         };
 
         self.whitespace();
 
+        match self.peek() {
+            Some(Token::Colon) => { self.march(); Ok(Node::Label(ident)) }
+            _ => Ok(Node::MacroCall(ident, split_args(&self.rest_of_line()))),
+        }
+    }
+
+    // numeric_label_statement ::= NUMBER whitespace COLON
+    //      an anonymous label referred to as 1b or 1f from the lines after or before it
+    fn numeric_label_statement(&mut self) -> Result<Node, Error> {
+        let number = match self.march() {
+            Some(Token::Number(x)) => x,
+            _ => return self.error("label requires a number."),
+        };
+
+        self.whitespace();
         match self.march() {
-            Some(Token::Colon) => Ok(Node::Label(ident)),
-            _ => self.error("expected ':' in label statement."),
+            Some(Token::Colon) => Ok(Node::Label(number.to_string())),
+            _ => self.error(&format!("expected ':' after numeric label '{}'.", number)),
         }
     }
 
+    // macro_statement ::= MACRO required_whitespace identifier required_whitespace macro_params
+    //                     rest_of_line newline (line newline)* ENDMACRO
+    //      the body lines are kept as text, they're parsed once the parameters are substituted
+    fn macro_statement(&mut self) -> Result<Node, Error> {
+        self.march();
+        if !self.required_whitespace() { return self.error("expected whitespace after '%macro'."); }
+
+        let name = match self.march() {
+            Some(Token::Identifier(x)) => x,
+            _ => return self.error("invalid argument passed to '%macro', expected name."),
+        };
+        if !self.required_whitespace() { return self.error("expected number of parameters after macro name."); }
+
+        let (min_args, max_args) = match self.macro_params() {
+            Ok(params) => params,
+            Err(e) => return self.error(&format!("invalid macro parameters ({}).", e)),
+        };
+        self.whitespace();
+        let defaults = split_args(&self.rest_of_line());
+        self.newline()?;
+
+        // Collect the source up to the start of the line ending the macro.
+        let macro_line_no = self.line_no - 1;
+        let source = self.lexer.source();
+        let start = self.lexer.span().start;
+        loop {
+            match self.peek() {
+                Some(Token::EndMacro) => break,
+                Some(Token::Macro) => return self.error("macros can't be defined inside macros."),
+                Some(Token::Newline) => self.line_no += 1,
+                None => {
+                    self.line_no = macro_line_no;
+                    return self.error(&format!("'%macro {}' without '%endmacro'.", name));
+                }
+                _ => (),
+            }
+            self.march();
+        }
+        let end = self.lexer.span().start;
+        let text = &source[start.min(end)..end];
+        let body = text[..text.rfind('\n').map(|idx| idx + 1).unwrap_or(0)].lines().map(String::from).collect();
+        self.march();
+
+        Ok(Node::MacroDef { name, min_args, max_args, defaults, body })
+    }
+
+    // if_statement ::= if_condition newline block (ELIF required_whitespace expr newline block)*
+    //                  (ELSE newline block)? ENDIF
+    fn if_statement(&mut self) -> Result<Node, Error> {
+        self.conditional_depth += 1;
+        let node = self.if_branches();
+        self.conditional_depth -= 1;
+        node
+    }
+
+    fn if_branches(&mut self) -> Result<Node, Error> {
+        let if_line_no = self.line_no;
+        let mut branches = Vec::new();
+        let mut otherwise = Vec::new();
+
+        let mut condition = self.if_condition()?;
+        loop {
+            self.newline()?;
+            branches.push((condition, self.block()?));
+
+            condition = match self.peek() {
+                Some(Token::Elif) => { self.march(); self.if_condition_expr("%elif")? }
+                Some(Token::Else) => {
+                    self.march();
+                    self.newline()?;
+                    otherwise = self.block()?;
+                    match self.peek() {
+                        Some(Token::EndIf) => { self.march(); break }
+                        Some(Token::Elif) | Some(Token::Else) => return self.error("'%else' must be the last branch of '%if'."),
+                        Some(_) => return self.unmatched(),
+                        None => (),
+                    }
+                    self.line_no = if_line_no;
+                    return self.error("'%if' without '%endif'.");
+                }
+                Some(Token::EndIf) => { self.march(); break }
+                Some(_) => return self.unmatched(),
+                None => {
+                    self.line_no = if_line_no;
+                    return self.error("'%if' without '%endif'.");
+                }
+            };
+        }
+
+        Ok(Node::If { branches, otherwise })
+    }
+
+    // if_condition ::= IF required_whitespace expr | (IFDEF | IFNDEF) required_whitespace identifier
+    fn if_condition(&mut self) -> Result<Node, Error> {
+        let directive = match self.march() {
+            Some(Token::IfDef) => "%ifdef",
+            Some(Token::IfNDef) => "%ifndef",
+            _ => return self.if_condition_expr("%if"),
+        };
+        if !self.required_whitespace() { return self.error(&format!("expected symbol name after '{}'.", directive)); }
+
+        match self.march() {
+            Some(Token::Identifier(x)) if directive == "%ifdef" => Ok(Node::IfDef(x)),
+            Some(Token::Identifier(x)) => Ok(Node::IfNDef(x)),
+            _ => self.error(&format!("expected symbol name after '{}'.", directive)),
+        }
+    }
+
+    // if_condition_expr ::= required_whitespace expr
+    fn if_condition_expr(&mut self, directive: &str) -> Result<Node, Error> {
+        if !self.required_whitespace() { return self.error(&format!("expected condition after '{}'.", directive)); }
+
+        match self.expr() {
+            Ok(expr) => Ok(expr),
+            Err(e) => self.error(&format!("invalid condition after '{}' ({}).", directive, e)),
+        }
+    }
+
+    // rep_statement ::= REP required_whitespace expr (COMMA whitespace identifier)? newline block ENDREP
+    //      the identifier counts the repetitions from 0
+    fn rep_statement(&mut self) -> Result<Node, Error> {
+        let rep_line_no = self.line_no;
+        self.march();
+        if !self.required_whitespace() { return self.error("expected count after '%rep'."); }
+
+        let count = match self.expr() {
+            Ok(expr) => expr,
+            Err(e) => return self.error(&format!("invalid count after '%rep' ({}).", e)),
+        };
+        let counter = match self.peek() {
+            Some(Token::Comma) => {
+                self.march();
+                self.whitespace();
+                match self.march() {
+                    Some(Token::Identifier(x)) => Some(x),
+                    _ => return self.error("expected counter name after '%rep' count."),
+                }
+            }
+            _ => None,
+        };
+        self.newline()?;
+
+        let body = self.block()?;
+        match self.peek() {
+            Some(Token::EndRep) => { self.march(); }
+            Some(_) => return self.unmatched(),
+            None => {
+                self.line_no = rep_line_no;
+                return self.error("'%rep' without '%endrep'.");
+            }
+        }
+
+        Ok(Node::Rep { count: Box::new(count), counter, body })
+    }
+
+    // times_statement ::= TIMES required_whitespace expr statement
+    fn times_statement(&mut self) -> Result<Node, Error> {
+        self.march();
+        if !self.required_whitespace() { return self.error("expected count after 'TIMES'."); }
+
+        let count = match self.expr() {
+            Ok(expr) => expr,
+            Err(e) => return self.error(&format!("invalid count after 'TIMES' ({}).", e)),
+        };
+        let stmt = match self.peek() {
+            Some(Token::Identifier(_)) | Some(Token::Times) | Some(Token::Macro) | Some(Token::If) | Some(Token::IfDef)
+                | Some(Token::IfNDef) | Some(Token::Rep) => return self.error("'TIMES' can only repeat an instruction or data."),
+            _ => self.statement()?,
+        };
+
+        Ok(Node::Times(Box::new(count), Box::new(stmt)))
+    }
+
+    // align_statement ::= ALIGN required_whitespace expr (COMMA whitespace expr)?
+    fn align_statement(&mut self) -> Result<Node, Error> {
+        self.march();
+        if !self.required_whitespace() { return self.error("expected alignment after 'ALIGN'."); }
+
+        let align = match self.expr() {
+            Ok(expr) => expr,
+            Err(e) => return self.error(&format!("invalid alignment after 'ALIGN' ({}).", e)),
+        };
+        if self.peek() != Some(Token::Comma) {
+            return Ok(Node::Align(Box::new(align), None));
+        }
+        self.march();
+        self.whitespace();
+        match self.expr() {
+            Ok(fill) => Ok(Node::Align(Box::new(align), Some(Box::new(fill)))),
+            Err(e) => self.error(&format!("invalid fill after 'ALIGN' ({}).", e)),
+        }
+    }
+
+    // macro_params ::= integer (MINUS (integer | MULTIPLY))?
+    fn macro_params(&mut self) -> Result<(usize, Option<usize>), String> {
+        let min = self.integer()? as usize;
+        match self.peek() {
+            Some(Token::Minus) => {
+                self.march();
+                match self.peek() {
+                    Some(Token::Multiply) => { self.march(); Ok((min, None)) }
+                    _ => Ok((min, Some(self.integer()? as usize))),
+                }
+            }
+            _ => Ok((min, Some(min))),
+        }
+    }
+
+    // rest_of_line ::= (!NEWLINE)*
+    //      returns the source text up to the end of the line or a comment
+    fn rest_of_line(&mut self) -> String {
+        if self.peek().is_none() { return String::new(); }
+
+        let start = self.lexer.span().start;
+        let mut end = start;
+        while let Some(token) = self.peek() {
+            if token == Token::Newline { break; }
+            end = self.lexer.span().end;
+            self.march();
+        }
+
+        self.lexer.source()[start..end].to_string()
+    }
+
     // entry_statement ::= ENTRY required_whitespace identifier
     fn entry_statement(&mut self) -> Result<Node, Error> {
         self.march();
@@ -155,130 +450,218 @@ impl<'a> Parser<'a> {
         }
     }
 
-    // ds_statement ::= DS required_whitespace integer
-    fn ds_statement(&mut self) -> Result<Node, Error> {
+    // org_statement ::= ORG required_whitespace integer
+    fn org_statement(&mut self) -> Result<Node, Error> {
         self.march();
-        if !self.required_whitespace() { return self.error("expected whitespace after 'ds'."); }
+        if !self.required_whitespace() { return self.error("expected whitespace after 'org'."); }
 
         match self.integer() {
-            Ok(x) => Ok(Node::DS(x)),
-            Err(e) => self.error(&format!("invalid argument passed to ds ({}).", e)),
+            Ok(x) => Ok(Node::Org(x)),
+            Err(e) => self.error(&format!("invalid argument passed to org ({}).", e)),
         }
     }
 
-    // db_statement ::= DB required_whitespace db_argument (COMMA whitespace db_argument)*
-    fn db_statement(&mut self) -> Result<Node, Error> {
+    // segment_statement ::= SEGMENT required_whitespace (identifier | string) (required_whitespace ALIGN EQUALS integer)?
+    fn segment_statement(&mut self) -> Result<Node, Error> {
         self.march();
-        if !self.required_whitespace() { return self.error("expected whitespace after 'db'."); }
-        let mut data = Vec::new();
+        if !self.required_whitespace() { return self.error("expected whitespace after 'segment'."); }
 
-        match self.db_argument() {
-            Ok(bytes) => data.extend_from_slice(&bytes),
-            Err(msg) => return self.error(&msg),
-        }
+        let name = match self.march() {
+            Some(Token::Identifier(x)) => x,
+            Some(Token::String(x)) => String::from_utf8_lossy(&x).into_owned(),
+            _ => return self.error("invalid argument passed to 'segment', expected name."),
+        };
 
-        while self.peek() == Some(Token::Comma) {
-            self.march();
-            self.whitespace();
-            match self.db_argument() {
-                Ok(bytes) => data.extend_from_slice(&bytes),
-                Err(msg) => return self.error(&msg),
-            }
+        self.whitespace();
+        if self.peek() != Some(Token::Align) {
+            return Ok(Node::Segment(name, None));
+        }
+        self.march();
+        if self.march() != Some(Token::Equals) { return self.error("expected '=' after 'ALIGN'."); }
+        match self.integer() {
+            Ok(align) => Ok(Node::Segment(name, Some(align))),
+            Err(e) => self.error(&format!("invalid segment alignment ({}).", e)),
         }
-
-        Ok(Node::Db(data))
     }
 
-    // db_argument ::= string | byte 
-    fn db_argument(&mut self) -> Result<Vec<u8>, String> {
-        match self.peek() {
-            Some(Token::String(s)) => { self.march(); Ok(s.as_bytes().to_vec()) },
-            Some(Token::Number(_)) | Some(Token::HexNumber(_)) => match self.byte() {
-                Ok(x) => Ok(vec![x]),
-                Err(e) => Err(format!("invalid argument passed to 'db' ({})", e))
-            }
-            _ => Err("invalid argument passed to 'db'.".to_string()),
+    // extern_statement ::= EXTERN required_whitespace identifier
+    fn extern_statement(&mut self) -> Result<Node, Error> {
+        self.march();
+        if !self.required_whitespace() { return self.error("expected whitespace after 'extern'."); }
+
+        match self.march() {
+            Some(Token::Identifier(x)) => Ok(Node::Extern(x)),
+            _ => self.error("invalid argument passed to 'extern', expected symbol name."),
         }
     }
 
-    // dw_statement ::= DW required_whitespace dw_argument (COMMA whitespace dw_argument)*
-    fn dw_statement(&mut self) -> Result<Node, Error> {
+    // needed_statement ::= NEEDED required_whitespace string
+    fn needed_statement(&mut self) -> Result<Node, Error> {
         self.march();
-        if !self.required_whitespace() { return self.error("expected whitespace after 'dw'."); }
-        let mut data = Vec::new();
+        if !self.required_whitespace() { return self.error("expected whitespace after 'needed'."); }
 
-        match self.dw_argument() {
-            Ok(bytes) => data.extend_from_slice(&bytes),
-            Err(msg) => return self.error(&msg),
+        match self.march() {
+            Some(Token::String(x)) => Ok(Node::Needed(String::from_utf8_lossy(&x).into_owned())),
+            _ => self.error("invalid argument passed to 'needed', expected library name."),
         }
+    }
 
-        while self.peek() == Some(Token::Comma) {
-            self.march();
-            self.whitespace();
-            match self.dw_argument() {
-                Ok(bytes) => data.extend_from_slice(&bytes),
-                Err(msg) => return self.error(&msg),
-            }
-        }
+    // symbol_statement ::= (EXPORT | GLOBAL | WEAK | HIDDEN) required_whitespace identifier
+    fn symbol_statement(&mut self) -> Result<Node, Error> {
+        let (directive, node): (&str, fn(String) -> Node) = match self.march() {
+            Some(Token::Export) => ("export", Node::Export),
+            Some(Token::Global) => ("global", Node::Global),
+            Some(Token::Weak) => ("weak", Node::Weak),
+            _ => ("hidden", Node::Hidden),
+        };
+        if !self.required_whitespace() { return self.error(&format!("expected whitespace after '{}'.", directive)); }
 
-        Ok(Node::DW(data))
+        match self.march() {
+            Some(Token::Identifier(x)) => Ok(node(x)),
+            _ => self.error(&format!("invalid argument passed to '{}', expected symbol name.", directive)),
+        }
     }
 
-    // dw_argument ::= string | word 
-    fn dw_argument(&mut self) -> Result<Vec<u16>, String> {
-        match self.peek() {
-            Some(Token::String(s)) => { self.march(); Ok(s.as_bytes().to_vec().iter().map(|x| *x as u16).collect()) },
-            Some(Token::Number(_)) | Some(Token::HexNumber(_)) => match self.word() {
-                Ok(x) => Ok(vec![x]),
-                Err(e) => Err(format!("invalid argument passed to 'dw' ({})", e))
-            }
-            _ => Err("invalid argument passed to 'dw'.".to_string()),
+    // ds_statement ::= DS required_whitespace expr
+    fn ds_statement(&mut self) -> Result<Node, Error> {
+        self.march();
+        if !self.required_whitespace() { return self.error("expected whitespace after 'ds'."); }
+
+        match self.expr() {
+            Ok(len) => Ok(Node::DS(Box::new(len))),
+            Err(e) => self.error(&format!("invalid argument passed to ds ({}).", e)),
         }
     }
 
-    // dl_statement ::= DL required_whitespace dl_argument (COMMA whitespace dl_argument)*
+    // db_statement ::= DB required_whitespace data_arguments
+    fn db_statement(&mut self) -> Result<Node, Error> {
+        self.march();
+        if !self.required_whitespace() { return self.error("expected whitespace after 'db'."); }
+        Ok(Node::Db(self.data_arguments("db")?))
+    }
+
+    // dw_statement ::= DW required_whitespace data_arguments
+    fn dw_statement(&mut self) -> Result<Node, Error> {
+        self.march();
+        if !self.required_whitespace() { return self.error("expected whitespace after 'dw'."); }
+        Ok(Node::DW(self.data_arguments("dw")?))
+    }
+
+    // dl_statement ::= (DL | DD) required_whitespace data_arguments
+    //      DD is the same as DL
     fn dl_statement(&mut self) -> Result<Node, Error> {
         self.march();
         if !self.required_whitespace() { return self.error("expected whitespace after 'dl'."); }
+        Ok(Node::DL(self.data_arguments("dl")?))
+    }
+
+    // dq_statement ::= DQ required_whitespace data_arguments
+    fn dq_statement(&mut self) -> Result<Node, Error> {
+        self.march();
+        if !self.required_whitespace() { return self.error("expected whitespace after 'dq'."); }
+        Ok(Node::DQ(self.data_arguments("dq")?))
+    }
+
+    // dt_statement ::= DT required_whitespace data_arguments
+    fn dt_statement(&mut self) -> Result<Node, Error> {
+        self.march();
+        if !self.required_whitespace() { return self.error("expected whitespace after 'dt'."); }
+        Ok(Node::DT(self.data_arguments("dt")?))
+    }
+
+    // ascii_statement ::= (ASCII | ASCIZ) required_whitespace STRING whitespace (COMMA whitespace STRING whitespace)*
+    //      ASCIZ ends each string with a 0
+    fn ascii_statement(&mut self) -> Result<Node, Error> {
+        let (directive, terminated) = match self.march() {
+            Some(Token::Asciz) => ("asciz", true),
+            _ => ("ascii", false),
+        };
+        if !self.required_whitespace() { return self.error(&format!("expected whitespace after '{}'.", directive)); }
         let mut data = Vec::new();
 
-        match self.dl_argument() {
-            Ok(bytes) => data.extend_from_slice(&bytes),
-            Err(msg) => return self.error(&msg),
-        }
+        loop {
+            match self.march() {
+                Some(Token::String(s)) => data.push(Node::String(s)),
+                _ => return self.error(&format!("invalid argument passed to '{}', expected string.", directive)),
+            }
+            if terminated { data.push(Node::Integer(0)); }
+            self.whitespace();
 
-        while self.peek() == Some(Token::Comma) {
+            if self.peek() != Some(Token::Comma) { break; }
             self.march();
             self.whitespace();
-            match self.dl_argument() {
-                Ok(bytes) => data.extend_from_slice(&bytes),
-                Err(msg) => return self.error(&msg),
+        }
+
+        Ok(Node::Db(data))
+    }
+
+    // data_arguments ::= data_argument whitespace (COMMA whitespace data_argument whitespace)*
+    fn data_arguments(&mut self, directive: &str) -> Result<Vec<Node>, Error> {
+        let mut data = Vec::new();
+
+        loop {
+            match self.data_argument(directive) {
+                Ok(nodes) => data.extend(nodes),
+                Err(e) => {
+                    self.error(&format!("invalid argument passed to '{}' ({}).", directive, e))?;
+                }
             }
+            self.whitespace();
+
+            if self.peek() != Some(Token::Comma) { break; }
+            self.march();
+            self.whitespace();
         }
 
-        Ok(Node::DL(data))
+        Ok(data)
     }
 
-    // dl_argument ::= string | word 
-    fn dl_argument(&mut self) -> Result<Vec<u32>, String> {
+    // data_argument ::= STRING | PASCAL required_whitespace STRING | (MINUS whitespace)? FLOAT | expr
+    //      PASCAL puts the length of the string before it
+    fn data_argument(&mut self, directive: &str) -> Result<Vec<Node>, String> {
         match self.peek() {
-            Some(Token::String(s)) => { self.march(); Ok(s.as_bytes().to_vec().iter().map(|x| *x as u32).collect()) },
-            Some(Token::Number(_)) | Some(Token::HexNumber(_)) => match self.integer() {
-                Ok(x) => Ok(vec![x]),
-                Err(e) => Err(format!("invalid argument passed to 'dw' ({})", e))
+            Some(Token::Float(x)) => { self.march(); Ok(vec![Node::Float(x)]) }
+            // A '-' could be the start of a negative float or an expression.
+            Some(Token::Minus) => {
+                self.march();
+                self.whitespace();
+                if let Some(Token::Float(x)) = self.peek() {
+                    self.march();
+                    return Ok(vec![Node::Float(-x)]);
+                }
+                let term = self.expr_term()?;
+                self.whitespace();
+                self.expr_rest(Node::Unary(Token::Minus, Box::new(term))).map(|expr| vec![expr])
+            }
+            // A string followed by an operator is a number in an expression.
+            Some(Token::String(s)) => {
+                self.march();
+                self.whitespace();
+                if self.operator().is_none() { return Ok(vec![Node::String(s)]); }
+                self.expr_term_string(s).and_then(|first| self.expr_rest(first)).map(|expr| vec![expr])
+            }
+            Some(Token::Pascal) => {
+                self.march();
+                self.whitespace();
+                let max_len = match directive { "db" => 0xFF, "dw" => 0xFFFF, _ => u32::MAX as usize };
+                match self.march() {
+                    Some(Token::String(s)) if s.len() > max_len => Err(format!("string is longer than {} characters", max_len)),
+                    Some(Token::String(s)) => Ok(vec![Node::Integer(s.len() as i64), Node::String(s)]),
+                    _ => Err("expected string after 'PASCAL'".to_string()),
+                }
             }
-            _ => Err("invalid argument passed to 'dw'.".to_string()),
+            _ => Ok(vec![self.expr()?]),
         }
     }
 
-    // int_statement ::= INT required_whitespace number
+    // int_statement ::= INT required_whitespace expr
     fn int_statement(&mut self) -> Result<Node, Error> {
         self.march(); // INT 
         if !self.required_whitespace() {
             return self.error("expected whitespace after 'int'.");
         }
-        match self.byte() {
-            Ok(x) => Ok(Node::Int(x)),
+        match self.expr() {
+            Ok(x) => Ok(Node::Int(Box::new(x))),
             Err(e) => self.error(&format!("invalid argument for 'int' ({}).", e)),
         }
     }
@@ -305,45 +688,27 @@ impl<'a> Parser<'a> {
         }
     }
 
-    // jump_statement ::= (JMP..) required_whitespace (IDENTIFIER | integer)
+    // jump_statement ::= (JMP..) required_whitespace expr
     fn jump_statement(&mut self, condition: JumpCondition) -> Result<Node, Error> {
         self.march();
         if !self.required_whitespace() { return self.error("expected whitespace after 'jmp'."); }
 
-        match self.peek() {
-            Some(Token::Identifier(label)) => { self.march(); Ok(Node::Jump { condition, label }) }
-            _ => match self.integer() {
-                Ok(addr) => Ok(Node::JumpImm { condition , addr }),
-                Err(e) => self.error(&format!("invalid argument for 'jmp' ({})", e)),
-            }
+        match self.expr() {
+            Ok(addr) => Ok(Node::Jump { condition, addr: Box::new(addr) }),
+            Err(e) => self.error(&format!("invalid argument for 'jmp' ({})", e)),
         }
     }
 
-    // mov_statement ::= MOV req_ws register ws , ws (register | pointer | integer)
-    //              | MOV req_ws register ws , ws [ ws (register | pointer | integer) ws ]
-    //              | MOV requied_whitespace [ ws (register | pointer | integer) ws ] ws , ws register
+    // mov_statement ::= MOV req_ws register ws , ws (register | expr)
+    //              | MOV req_ws register ws , ws [ ws (register | expr) ws ]
+    //              | MOV requied_whitespace [ ws (register | expr) ws ] ws , ws register
     fn mov_statement(&mut self) -> Result<Node, Error> {
         self.march();
         if !self.required_whitespace() { return self.error("expected whitespace after 'mov'."); }
 
         match self.peek() {
             Some(Token::LeftBracket) => {
-                self.march();
-                self.whitespace();
-                let n = match self.peek() {
-                    Some(Token::Identifier(x)) => { self.march(); Ok(Node::Pointer(x)) },
-                    Some(Token::Number(_)) | Some(Token::HexNumber(_)) => match self.integer() {
-                        Ok(x) => Ok(Node::Integer(x)),
-                        Err(e) => self.error(&format!("invalid memory address in mov ({})", e)),
-                    }
-                    _ => match self.register() {
-                        Some(x) => Ok(Node::Register(x)),
-                        None => self.error(&format!("invalid memory address in mov (unknown register)")),
-                    }
-                }?;
-
-                self.whitespace();
-                if self.march() != Some(Token::RightBracket) { return self.error("expected ']'"); }
+                let n = self.memory_operand()?;
                 self.whitespace();
                 if self.march() != Some(Token::Comma) { return self.error("expected ','"); }
                 self.whitespace();
@@ -352,10 +717,8 @@ impl<'a> Parser<'a> {
                 if register.is_none() { return self.error("unknown register in mov."); }
 
                 match n {
-                    Node::Integer(x) => Ok(Node::MovMemory(x, register.unwrap())),
-                    Node::Pointer(x) => Ok(Node::MovMemoryPointer(x.clone(), register.unwrap())),
                     Node::Register(x) => Ok(Node::MovMemoryRegister(x, register.unwrap())),
-                    _ => self.error("unknown error occured."),
+                    addr => Ok(Node::MovMemory(Box::new(addr), register.unwrap())),
                 }
             }
 
@@ -367,52 +730,19 @@ impl<'a> Parser<'a> {
                     self.whitespace();
 
                     match self.peek() {
-                       Some(Token::LeftBracket) => {
-                           self.march();
-                           self.whitespace();
-                           let n = match self.peek() {
-                                Some(Token::Identifier(x)) => { self.march(); Ok(Node::Pointer(x)) },
-                                Some(Token::Number(_)) | Some(Token::HexNumber(_)) => match self.integer() {
-                                    Ok(x) => Ok(Node::Integer(x)),
-                                    Err(e) => self.error(&format!("invalid memory address in mov ({})", e)),
-                                }
-                                _ => match self.register() {
-                                    Some(x) => Ok(Node::Register(x)),
-                                    None => self.error(&format!("invalid memory address in mov (unknown register)")),
-                                }
-                           }?;
-
-                           self.whitespace();
-                           if self.march() != Some(Token::RightBracket) { return self.error("expected ']'"); }
-
-                           match n {
-                                Node::Integer(x) => Ok(Node::MovFromMemory(register, x)),
-                                Node::Pointer(x) => Ok(Node::MovFromMemoryPointer(register, x)),
-                                Node::Register(x) => Ok(Node::MovFromMemoryRegister(register, x)),
-                               _ => self.error("unknown error occured."),
-                           }
-                       }
-
-                       // Not reading from memory
-                       _ => {
-                           let n = match self.peek() {
-                                Some(Token::Identifier(x)) => { self.march(); Ok(Node::Pointer(x)) },
-                                Some(Token::Number(_)) | Some(Token::HexNumber(_)) => match self.integer() {
-                                    Ok(x) => Ok(Node::Integer(x)),
-                                    Err(e) => self.error(&format!("invalid memory address in mov ({})", e)),
-                                }
-                                _ => match self.register() {
-                                    Some(x) => Ok(Node::Register(x)),
-                                    None => self.error(&format!("invalid memory address in mov (unknown register)")),
-                                }
-                           }?;
-                           match n {
-                               Node::Integer(x) => Ok(Node::MovImm(register, x)),
-                               Node::Pointer(x) => Ok(Node::MovImmPointer(register, x.clone())),
-                               Node::Register(x) => Ok(Node::Mov(register, x)),
-                               _ => self.error("unknown error occured."),
-                           }
-                       }
+                        Some(Token::LeftBracket) => match self.memory_operand()? {
+                            Node::Register(x) => Ok(Node::MovFromMemoryRegister(register, x)),
+                            addr => Ok(Node::MovFromMemory(register, Box::new(addr))),
+                        }
+
+                        // Not reading from memory
+                        _ => match self.register() {
+                            Some(x) => Ok(Node::Mov(register, x)),
+                            None => match self.expr() {
+                                Ok(x) => Ok(Node::MovImm(register, Box::new(x))),
+                                Err(e) => self.error(&format!("invalid argument to mov ({}).", e)),
+                            }
+                        }
                     }
                 }
                 None => self.error("invalid argument to mov (expected register)."),
@@ -420,6 +750,23 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // memory_operand ::= [ ws (register | expr) ws ]
+    fn memory_operand(&mut self) -> Result<Node, Error> {
+        self.march();
+        self.whitespace();
+        let n = match self.register() {
+            Some(x) => Node::Register(x),
+            None => match self.expr() {
+                Ok(x) => x,
+                Err(e) => return self.error(&format!("invalid memory address in mov ({})", e)),
+            }
+        };
+
+        self.whitespace();
+        if self.march() != Some(Token::RightBracket) { return self.error("expected ']'"); }
+        Ok(n)
+    }
+
     // add_statement ::= ADD req_ws reg_imm_or_reg_reg
     fn add_statement(&mut self) -> Result<Node, Error> {
         self.march();
@@ -428,9 +775,7 @@ impl<'a> Parser<'a> {
         match self.reg_imm_or_reg_reg() {
             Ok((reg, n)) => match n {
                 Node::Register(reg2) => Ok(Node::Add(reg, reg2)),
-                Node::Pointer(label) => Ok(Node::AddImmPointer(reg, label)),
-                Node::Integer(x) => Ok(Node::AddImm(reg, x)),
-                _ => self.error("invalid arguments to add (unknown error)."),
+                x => Ok(Node::AddImm(reg, Box::new(x))),
             }
             Err(e) => self.error(&format!("invalid arguments to add ({}).", e)),
         }
@@ -444,9 +789,7 @@ impl<'a> Parser<'a> {
         match self.reg_imm_or_reg_reg() {
             Ok((reg, n)) => match n {
                 Node::Register(reg2) => Ok(Node::Sub(reg, reg2)),
-                Node::Pointer(label) => Ok(Node::SubImmPointer(reg, label)),
-                Node::Integer(x) => Ok(Node::SubImm(reg, x)),
-                _ => self.error("invalid arguments to sub (unknown error)."),
+                x => Ok(Node::SubImm(reg, Box::new(x))),
             }
             Err(e) => self.error(&format!("invalid arguments to sub ({}).", e)),
         }
@@ -515,9 +858,7 @@ impl<'a> Parser<'a> {
         match self.reg_imm_or_reg_reg() {
             Ok((reg, n)) => match n {
                 Node::Register(reg2) => Ok(Node::CMP(reg, reg2)),
-                Node::Pointer(label) => Ok(Node::CMPImmPointer(reg, label)),
-                Node::Integer(x) => Ok(Node::CMPImm(reg, x)),
-                _ => self.error("invalid arguments to cmp (unknown error)."),
+                x => Ok(Node::CMPImm(reg, Box::new(x))),
             }
             Err(e) => self.error(&format!("invalid arguments to cmp ({}).", e)),
         }
@@ -556,21 +897,17 @@ impl<'a> Parser<'a> {
         }
     }
 
-    // call_statement ::= POP required_whitespace (identifier | integer | register)
+    // call_statement ::= CALL required_whitespace (register | expr)
     fn call_statement(&mut self) -> Result<Node, Error> {
         self.march(); 
         if !self.required_whitespace() { return self.error("expected whitespace after 'call'."); }
  
-        match self.peek() {
-            Some(Token::Identifier(label)) => { self.march(); Ok(Node::CallPointer(label)) },
-            Some(Token::Number(_)) | Some(Token::HexNumber(_)) => match self.integer() {
-                Ok(x) => Ok(Node::Call(x as u32)),
+        match self.register() {
+            Some(register) => Ok(Node::CallRegister(register)),
+            None => match self.expr() {
+                Ok(x) => Ok(Node::Call(Box::new(x))),
                 Err(e) => self.error(&format!("invalid argument to call ({}).", e)),
             }
-            _ => match self.register() {
-                Some(register) => Ok(Node::CallRegister(register)),
-                _ => self.error("invalid argument to call."),
-            }
         }
     }
 
@@ -637,8 +974,9 @@ impl<'a> Parser<'a> {
     }
 
     // register
+    //      only consumes the token when it's a register
     fn register(&mut self) -> Option<Register> {
-        match self.march() {
+        let register = match self.peek() {
             Some(Token::AH) => Some(Register::AH),
             Some(Token::AL) => Some(Register::AL), 
             Some(Token::BH) => Some(Register::BH), 
@@ -664,35 +1002,10 @@ impl<'a> Parser<'a> {
             Some(Token::ESI) => Some(Register::ESI),
             Some(Token::EDX) => Some(Register::EDX),
             _ => None,
-        }
-    }
-
-    // byte ::= NUMBER | HEXNUMBER
-    //      checks that its between 0 and 0xFF
-    fn byte(&mut self) -> Result<u8, String> {
-        match self.march() {
-            Some(Token::Number(x)) => if (x as u64) > 0xFF { 
-                Err(format!("{} > 255", x as u64))     
-            } else { Ok(x as u8) }
-            Some(Token::HexNumber(x)) => if (x as u64) > 0xFF { 
-                Err(format!("{} > 255", x as u64))     
-            } else { Ok(x as u8) }
-            _ => Err("not a number".to_string()),
-        }
-    }
+        };
 
-    // word ::= NUMBER | HEXNUMBER
-    //      checks that its between 0 and 0xFFFF
-    fn word(&mut self) -> Result<u16, String> {
-        match self.march() {
-            Some(Token::Number(x)) => if (x as u64) > 0xFFFF { 
-                Err(format!("{} > 0xFFFF", x as u64))     
-            } else { Ok(x as u16) }
-            Some(Token::HexNumber(x)) => if (x as u64) > 0xFFFF { 
-                Err(format!("{} > 0xFFFF", x as u64))     
-            } else { Ok(x as u16) }
-            _ => Err("not a number".to_string()),
-        }
+        if register.is_some() { self.march(); }
+        register
     }
 
     // integer ::= NUMBER | HEXNUMBER
@@ -721,7 +1034,7 @@ impl<'a> Parser<'a> {
         Ok((a.unwrap(), b.unwrap()))
     }
 
-    // reg_imm ::= register ws COMMA ws (integer | identifier)
+    // reg_imm ::= register ws COMMA ws expr
     fn reg_imm(&mut self) -> Result<(Register, Node), String> {
         let a = self.register();
         self.whitespace();
@@ -729,14 +1042,7 @@ impl<'a> Parser<'a> {
         self.whitespace();
 
         if a.is_none() { return Err("unknown register".to_string()); }
-
-        match self.peek() {
-            Some(Token::Identifier(x)) => { self.march(); Ok((a.unwrap(), Node::Pointer(x.clone()))) }
-            _ => match self.integer() {
-                Ok(x) => Ok((a.unwrap(), Node::Integer(x))),
-                Err(e) => Err("not a integer".to_string()),
-            }
-        }
+        Ok((a.unwrap(), self.expr()?))
     }
 
     // reg_imm_or_reg_reg ::= reg_reg | reg_imm
@@ -748,16 +1054,9 @@ impl<'a> Parser<'a> {
 
         if a.is_none() { return Err("unknown register".to_string()); }
 
-        match self.peek() {
-            Some(Token::Identifier(x)) => { self.march(); Ok((a.unwrap(), Node::Pointer(x.clone()))) },
-            Some(Token::Number(_)) | Some(Token::HexNumber(_)) => match self.integer() {
-                Ok(x) => Ok((a.unwrap(), Node::Integer(x))),
-                Err(e) => Err(e),
-            } 
-            _ => match self.register() {
-                Some(reg) => Ok((a.unwrap(), Node::Register(reg))), 
-                None => Err("unknown register".to_string()),
-            }
+        match self.register() {
+            Some(reg) => Ok((a.unwrap(), Node::Register(reg))), 
+            None => Ok((a.unwrap(), self.expr()?)),
         }
     }
 
@@ -802,8 +1101,13 @@ impl<'a> Parser<'a> {
             Err(e) => return Err(format!("invalid expression: {}", e)),
         }
         self.whitespace();
+        self.expr_rest(first.unwrap())
+    }
 
-        let mut parts = vec![first.unwrap()];
+    // expr_rest ::= (operator whitespace expr_term whitespace)*
+    //      the rest of an expression after its first term
+    fn expr_rest(&mut self, first: Node) -> Result<Node, String> {
+        let mut parts = vec![first];
 
         // While the next token is an operator...
         while let Some(op) = self.operator() {
@@ -822,19 +1126,39 @@ impl<'a> Parser<'a> {
         Ok(Node::Expr(parts))
     }
 
-    // expr_term ::= $ | IDENTIFIER | integer | paren_expr
+    // expr_term ::= (MINUS | BIT_NOT | LOGICAL_NOT) whitespace expr_term | $ | $$ | IDENTIFIER | NUMERIC_LABEL | STRING | NUMBER | HEXNUMBER | paren_expr
+    //      a string of up to 4 characters is a number with the first character in the lowest byte
     fn expr_term(&mut self) -> Result<Node, String> {
         match self.peek() {
+            Some(op @ (Token::Minus | Token::BitNot | Token::LogicalNot)) => {
+                self.march();
+                self.whitespace();
+                Ok(Node::Unary(op, Box::new(self.expr_term()?)))
+            }
             Some(Token::Dollar) =>{ self.march(); Ok(Node::Dollar) },
+            Some(Token::DoubleDollar) => { self.march(); Ok(Node::DoubleDollar) }
             Some(Token::LeftParen) => self.paren_expr(),
-            Some(Token::Identifier(x)) => { self.march(); Ok(Node::Pointer(x)) }
-            _ => match self.integer() {
-                Ok(x) => Ok(Node::Integer(x)),
-                Err(e) => Err(e), 
-            }
+            Some(Token::Identifier(x) | Token::NumericLabel(x)) => { self.march(); Ok(Node::Pointer(x)) }
+            Some(Token::String(s)) => { self.march(); self.expr_term_string(s) }
+            // Literals are 64 bits, whether they fit is up to where they're used.
+            Some(Token::Number(x)) => { self.march(); Ok(Node::Integer(x)) }
+            Some(Token::HexNumber(x)) if x > i64::MAX as u64 => Err(format!("0x{:X} doesn't fit in 64 bits as a signed number", x)),
+            Some(Token::HexNumber(x)) => { self.march(); Ok(Node::Integer(x as i64)) }
+            Some(Token::Invalid(text)) if text.starts_with(|c: char| c.is_ascii_digit()) => Err(format!("{} doesn't fit in 64 bits", text)),
+            Some(Token::Invalid(text)) => Err(format!("invalid character '{}'", text)),
+            Some(Token::Float(_)) => Err("floats can only be used in 'DD', 'DQ' and 'DT'".to_string()),
+            _ => Err("not a number".to_string()),
         }
     }
 
+    // Gets the value of a string used as a number.
+    fn expr_term_string(&self, s: Vec<u8>) -> Result<Node, String> {
+        if !(1..=4).contains(&s.len()) {
+            return Err("only strings of 1 to 4 characters can be used as numbers".to_string());
+        }
+        Ok(Node::Integer(s.iter().rev().fold(0, |value, byte| (value << 8) | *byte as i64)))
+    }
+
     // paren_expr ::= ( whitespace expr whitespace )
     fn paren_expr(&mut self) -> Result<Node, String> {
         self.march();
@@ -848,11 +1172,14 @@ impl<'a> Parser<'a> {
         }
     }
     
-    // operator ::= + | - | * | /
+    // operator ::= + | - | * | / | // | % | %% | & | '|' | ^ | << | >> | && | '||' | == | != | < | <= | > | >=
     fn operator(&mut self) -> Option<Token> {
         let token = self.peek();
         match token {
-            Some(Token::Plus) | Some(Token::Minus) | Some(Token::Multiply) | Some(Token::Divide) => Some(token.clone().unwrap()),
+            Some(Token::Plus | Token::Minus | Token::Multiply | Token::Divide | Token::SignedDivide | Token::Modulo
+                | Token::SignedModulo | Token::BitAnd | Token::BitOr | Token::BitXor | Token::LeftShift | Token::RightShift
+                | Token::LogicalAnd | Token::LogicalOr | Token::Equal | Token::NotEqual | Token::Less | Token::LessEqual
+                | Token::Greater | Token::GreaterEqual) => token,
             _ => None,
         }
     }
@@ -863,10 +1190,36 @@ impl<'a> Parser<'a> {
         if !self.required_whitespace() { return self.error("expected whitespace after 'EQU'."); }
 
         match self.march() {
-            Some(Token::String(x)) => Ok(Node::Include(x)),
+            Some(Token::String(x)) => Ok(Node::Include(String::from_utf8_lossy(&x).into_owned())),
             _ => self.error("invalid argument passed to INCLUDE, expected string."),
         }
     }
+
+    // incbin_statement ::= INCBIN required_whitespace STRING whitespace (COMMA whitespace expr (COMMA whitespace expr)?)?
+    //      the optional offset and length of the part of the file to include
+    fn incbin_statement(&mut self) -> Result<Node, Error> {
+        self.march();
+        if !self.required_whitespace() { return self.error("expected whitespace after 'INCBIN'."); }
+
+        let filename = match self.march() {
+            Some(Token::String(x)) => String::from_utf8_lossy(&x).into_owned(),
+            _ => return self.error("invalid argument passed to INCBIN, expected string."),
+        };
+        self.whitespace();
+
+        let mut args = Vec::new();
+        for name in ["offset", "length"] {
+            if self.peek() != Some(Token::Comma) { break; }
+            self.march();
+            self.whitespace();
+            match self.expr() {
+                Ok(expr) => args.push(Box::new(expr)),
+                Err(e) => return self.error(&format!("invalid {} after 'INCBIN' ({}).", name, e)),
+            }
+        }
+        let mut args = args.into_iter();
+        Ok(Node::IncBin(filename, args.next(), args.next()))
+    }
 }
 
 #[cfg(test)]
@@ -903,4 +1256,151 @@ _exit:
         let node = Parser::parse(code).unwrap();
         node.print();
     }
+
+    #[test]
+    fn macros() {
+        let code = "
+%macro write 1-2 1 ; label, fd
+    mov ecx, %1
+%%done:
+%endmacro
+    write {msg}, 2
+";
+
+        let Node::Program(stmts) = Parser::parse(code).unwrap() else { panic!() };
+        match &stmts[0] {
+            Node::Statement(2, node) => match node.as_ref() {
+                Node::MacroDef { name, min_args, max_args, defaults, body } => {
+                    assert_eq!((name.as_str(), *min_args, *max_args), ("write", 1, Some(2)));
+                    assert_eq!(defaults, &vec!["1".to_string()]);
+                    assert_eq!(body, &vec!["    mov ecx, %1".to_string(), "%%done:".to_string()]);
+                }
+                node => panic!("expected a macro, got {:?}.", node),
+            },
+            node => panic!("expected a statement, got {:?}.", node),
+        }
+        match &stmts[1] {
+            Node::Statement(6, node) => assert!(matches!(node.as_ref(), Node::MacroCall(name, args) if name == "write" && args == &vec!["msg", "2"])),
+            node => panic!("expected a statement, got {:?}.", node),
+        }
+
+        assert_eq!(Parser::parse("%macro write 1\n").err().unwrap().message, "'%macro write' without '%endmacro'.");
+        assert_eq!(Parser::parse("%endmacro\n").err().unwrap().message, "'%endmacro' without '%macro'.");
+    }
+
+    #[test]
+    fn conditionals() {
+        let code = "
+%ifdef DEBUG
+    mov eax, 1
+%elif ARCH - 32
+    mov rax, 1
+%else
+%endif
+";
+
+        let Node::Program(stmts) = Parser::parse(code).unwrap() else { panic!() };
+        let Node::Statement(2, node) = &stmts[0] else { panic!("expected a statement, got {:?}.", stmts[0]) };
+        let Node::If { branches, otherwise } = node.as_ref() else { panic!("expected '%if', got {:?}.", node) };
+        assert!(matches!(&branches[0], (Node::IfDef(name), block) if name == "DEBUG" && block.len() == 1));
+        assert!(matches!(&branches[1], (Node::Expr(_), block) if block.len() == 1));
+        // The error is kept until the branch is assembled.
+        assert!(matches!(&branches[1].1[0], Node::Statement(5, node) if matches!(node.as_ref(), Node::Invalid(_))));
+        assert!(otherwise.is_empty());
+
+        assert_eq!(Parser::parse("%if 1\n").err().unwrap().message, "'%if' without '%endif'.");
+        assert_eq!(Parser::parse("%endif\n").err().unwrap().message, "'%endif' without '%if'.");
+        assert_eq!(Parser::parse("%if 1\n%else\n%elif 2\n%endif\n").err().unwrap().message, "'%else' must be the last branch of '%if'.");
+    }
+
+    #[test]
+    fn repetition() {
+        let code = "
+%rep 4, i
+    TIMES 510-($-$$) DB 0
+%endrep
+";
+
+        let Node::Program(stmts) = Parser::parse(code).unwrap() else { panic!() };
+        let Node::Statement(2, node) = &stmts[0] else { panic!("expected a statement, got {:?}.", stmts[0]) };
+        let Node::Rep { count, counter, body } = node.as_ref() else { panic!("expected '%rep', got {:?}.", node) };
+        assert!(matches!(count.as_ref(), Node::Expr(parts) if matches!(parts[..], [Node::Integer(4)])));
+        assert_eq!(counter.as_deref(), Some("i"));
+        let Node::Statement(3, node) = &body[0] else { panic!("expected a statement, got {:?}.", body[0]) };
+        assert!(matches!(node.as_ref(), Node::Times(_, stmt) if matches!(stmt.as_ref(), Node::Db(data) if matches!(data[..], [Node::Expr(_)]))));
+
+        assert_eq!(Parser::parse("%rep 2\n").err().unwrap().message, "'%rep' without '%endrep'.");
+        assert_eq!(Parser::parse("%if 1\n%endrep\n").err().unwrap().message, "'%endrep' without '%rep'.");
+        assert_eq!(Parser::parse("TIMES 2 _start:\n").err().unwrap().message, "'TIMES' can only repeat an instruction or data.");
+    }
+
+    #[test]
+    fn alignment() {
+        let Node::Program(stmts) = Parser::parse("SEGMENT data ALIGN=16\n    ALIGN 8, 0xCC\n    ALIGN 4\n").unwrap() else { panic!() };
+        let nodes: Vec<&Node> = stmts.iter().map(|stmt| match stmt {
+            Node::Statement(_, node) => node.as_ref(),
+            node => panic!("expected a statement, got {:?}.", node),
+        }).collect();
+        assert!(matches!(nodes[0], Node::Segment(name, Some(16)) if name == "data"));
+        assert!(matches!(nodes[1], Node::Align(_, Some(_))));
+        assert!(matches!(nodes[2], Node::Align(_, None)));
+
+        assert_eq!(Parser::parse("SEGMENT data ALIGN 16\n").err().unwrap().message, "expected '=' after 'ALIGN'.");
+    }
+
+    #[test]
+    fn operands() {
+        let code = "
+    mov eax, _msg + 4
+    mov [buffer + 2 * 4], ebx
+    add ecx, (end - start) / 2
+    call eax
+    DL _msg, 1, \"ab\"
+";
+
+        let Node::Program(stmts) = Parser::parse(code).unwrap() else { panic!() };
+        let nodes: Vec<&Node> = stmts.iter().map(|stmt| match stmt {
+            Node::Statement(_, node) => node.as_ref(),
+            node => panic!("expected a statement, got {:?}.", node),
+        }).collect();
+        assert!(matches!(nodes[0], Node::MovImm(Register::EAX, expr) if matches!(expr.as_ref(), Node::Expr(parts) if parts.len() == 3)));
+        assert!(matches!(nodes[1], Node::MovMemory(_, Register::EBX)));
+        assert!(matches!(nodes[2], Node::AddImm(Register::ECX, _)));
+        assert!(matches!(nodes[3], Node::CallRegister(Register::EAX)));
+        assert!(matches!(nodes[4], Node::DL(data) if matches!(data[..], [Node::Expr(_), Node::Expr(_), Node::String(_)])));
+    }
+
+    #[test]
+    fn strings() {
+        let code = "
+    ASCIZ \"a\", 'b'
+    DB PASCAL `hi\\n`, 'c' + 1
+";
+
+        let Node::Program(stmts) = Parser::parse(code).unwrap() else { panic!() };
+        let Node::Statement(2, node) = &stmts[0] else { panic!("expected a statement, got {:?}.", stmts[0]) };
+        let Node::Db(data) = node.as_ref() else { panic!("expected data, got {:?}.", node) };
+        assert!(matches!(&data[..], [Node::String(a), Node::Integer(0), Node::String(b), Node::Integer(0)] if a == b"a" && b == b"b"));
+
+        let Node::Statement(3, node) = &stmts[1] else { panic!("expected a statement, got {:?}.", stmts[1]) };
+        let Node::Db(data) = node.as_ref() else { panic!("expected data, got {:?}.", node) };
+        assert!(matches!(&data[..], [Node::Integer(3), Node::String(s), Node::Expr(parts)] if s == b"hi\n" && matches!(parts[0], Node::Integer(0x63))));
+    }
+
+    #[test]
+    fn wide_data() {
+        let code = "
+    DD 1.5, - 2.5, -x + 1
+    DQ 1
+    DT 3.
+";
+
+        let Node::Program(stmts) = Parser::parse(code).unwrap() else { panic!() };
+        let Node::Statement(2, node) = &stmts[0] else { panic!("expected a statement, got {:?}.", stmts[0]) };
+        let Node::DL(data) = node.as_ref() else { panic!("expected data, got {:?}.", node) };
+        assert!(matches!(&data[..], [Node::Float(a), Node::Float(b), Node::Expr(parts)]
+            if *a == 1.5 && *b == -2.5 && matches!(parts[..], [Node::Unary(Token::Minus, _), Node::Operator(Token::Plus), Node::Integer(1)])));
+        assert!(matches!(stmts[1], Node::Statement(3, ref node) if matches!(node.as_ref(), Node::DQ(_))));
+        assert!(matches!(stmts[2], Node::Statement(4, ref node) if matches!(node.as_ref(), Node::DT(_))));
+    }
 }
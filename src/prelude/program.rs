@@ -1,16 +1,129 @@
 use std::fmt::Pointer;
 
-use super::{Addr, Instruction};
+use super::{Addr, Instruction, Value, parse_address};
+use super::macros::LOCAL_LABEL_PREFIX;
+
+/// Whether a label was named by the assembler rather than the source: a definition of a numeric
+/// label ('1@2' is the second '1:') or a '%%' label of a macro expansion.
+pub fn is_internal_label(label: &str) -> bool {
+    label.starts_with(|c: char| c.is_ascii_digit()) || label.starts_with(LOCAL_LABEL_PREFIX)
+}
+
+/// The source file and line an instruction was assembled from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line_no: usize,
+}
+
+/// The line of a macro's body an instruction was expanded from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MacroLocation {
+    pub name: String,
+    pub location: SourceLocation,
+}
+
+impl MacroLocation {
+    /// Describes the macro line, to follow an error reported at the line calling the macro.
+    pub fn note(&self) -> String {
+        format!(" (in macro '{}' at \"{}\" line {})", self.name, self.location.file, self.location.line_no)
+    }
+}
+
+/// A constant defined by EQU.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Constant {
+    pub name: String,
+    pub value: u32,
+    pub location: SourceLocation,
+}
+
+/// A function defined by a shared library, declared by EXTERN.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Extern {
+    pub name: String,
+    pub location: SourceLocation,
+    /// The address of the PLT entry calls go through, assigned when laid out.
+    pub addr: Addr,
+}
+
+/// How a symbol is bound when linking.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SymbolBinding {
+    Local,
+    Global,
+    Weak,
+}
+
+/// Whether a symbol is visible outside of the binary it's defined in.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SymbolVisibility {
+    Default,
+    Hidden,
+}
+
+/// The binding and visibility declared for a label or external by GLOBAL, WEAK or HIDDEN.
+/// Declared symbols are global unless WEAK.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub binding: SymbolBinding,
+    pub visibility: SymbolVisibility,
+    pub location: SourceLocation,
+}
+
+/// A run of blocks loaded together at its own address, becoming a loadable segment in the ELF.
+pub struct Segment {
+    pub name: String,
+    /// The address requested for the segment by the layout. When `None` the segment follows on
+    /// from the previous one.
+    pub vaddr: Option<u64>,
+    /// The file offset and address of the start of the segment, assigned when laid out.
+    pub start: Addr,
+    /// What the start of the segment is aligned to, raised by SEGMENT's ALIGN= and ALIGN so
+    /// padding within the segment lines up with addresses.
+    pub align: u64,
+}
+
+impl Segment {
+    /// Checks whether the segment holds code, i.e. is named text, .text or .text.<name>.
+    pub fn is_code(&self) -> bool {
+        let name = self.name.strip_prefix('.').unwrap_or(&self.name);
+        name == "text" || name.starts_with("text.")
+    }
+}
 
 pub struct ProgramBlock {
     label: String,
     len: usize,
+    segment: usize,
+    location: Option<SourceLocation>,
     instrs: Vec<Instruction>,
+    locations: Vec<Option<SourceLocation>>,
+    macro_locations: Vec<Option<MacroLocation>>,
 }
 
 pub struct Program {
-    pub offset: Addr,
-    pub entry_point: Addr,
+    /// The address the program is loaded at, set by ORG.
+    pub base: Option<u64>,
+    /// The label execution starts at.
+    pub entry: String,
+    /// Constants defined by EQU in the order they were defined.
+    pub constants: Vec<Constant>,
+    /// The INCLUDE statements processed along with the path of the included file.
+    pub includes: Vec<(SourceLocation, String)>,
+    /// Functions declared by EXTERN, resolved by the dynamic linker when loaded.
+    pub externs: Vec<Extern>,
+    /// Shared libraries named by NEEDED.
+    pub needed: Vec<String>,
+    /// Symbols declared by GLOBAL, WEAK and HIDDEN in the order they were first declared.
+    pub symbols: Vec<Symbol>,
+    /// The name a shared library is loaded by (DT_SONAME).
+    pub soname: Option<String>,
+    /// Labels nothing refers to, with where they're defined.
+    pub unused_labels: Vec<(String, SourceLocation)>,
+    segments: Vec<Segment>,
+    current_segment: usize,
     blocks: Vec<ProgramBlock> 
 }
 
@@ -19,35 +132,175 @@ impl ProgramBlock {
     pub fn push(&mut self, instr: Instruction) {
         self.len += instr.len();
         self.instrs.push(instr);
+        self.locations.push(None);
+        self.macro_locations.push(None);
+    }
+
+    /// Pushes an instruction to the block recording where in the source it came from.
+    pub fn push_at(&mut self, instr: Instruction, location: SourceLocation) {
+        self.push(instr);
+        *self.locations.last_mut().unwrap() = Some(location);
+    }
+
+    /// Pushes an instruction expanded from a macro called at 'location', recording the line of
+    /// the macro's body too.
+    pub fn push_expanded(&mut self, instr: Instruction, location: SourceLocation, macro_location: MacroLocation) {
+        self.push_at(instr, location);
+        *self.macro_locations.last_mut().unwrap() = Some(macro_location);
     }
 
     /// Gets the length of the block
     pub fn len(&self) -> usize {
         self.len
     }
+
+    /// Gets the label of the block
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Gets the index of the segment the block belongs to
+    pub fn segment(&self) -> usize {
+        self.segment
+    }
+
+    /// Gets where the block's label was defined
+    pub fn location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
 }
 
 impl Program {
-    /// Constructs a new program with no blocks.
+    /// Constructs a new program with no blocks and a single .text segment.
     pub fn new() -> Program {
         Program {
-            offset: Addr::default(),
-            entry_point: Addr::default(),
+            base: None,
+            entry: "__entry_point__".to_string(),
+            constants: Vec::new(),
+            includes: Vec::new(),
+            externs: Vec::new(),
+            needed: Vec::new(),
+            symbols: Vec::new(),
+            soname: None,
+            unused_labels: Vec::new(),
+            segments: vec![Segment { name: ".text".to_string(), vaddr: None, start: Addr::default(), align: 1 }],
+            current_segment: 0,
             blocks: Vec::new(),
         }
     }
 
+    /// Switches to the segment named 'name', creating it if it doesn't exist. Blocks pushed
+    /// afterwards belong to it.
+    pub fn switch_segment(&mut self, name: &str) {
+        self.current_segment = match self.segments.iter().position(|s| s.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.segments.push(Segment { name: name.to_string(), vaddr: None, start: Addr::default(), align: 1 });
+                self.segments.len() - 1
+            }
+        };
+    }
+
+    /// Gets the segment new blocks are pushed to.
+    pub fn current_segment(&self) -> &Segment {
+        &self.segments[self.current_segment]
+    }
+
+    /// Raises the alignment of the current segment to at least 'align'.
+    pub fn align_segment(&mut self, align: u64) {
+        let segment = &mut self.segments[self.current_segment];
+        segment.align = segment.align.max(align);
+    }
+
+    /// Gets every segment in order
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Gets a mutable reference to a segment by name
+    pub fn get_segment_mut(&mut self, name: &str) -> Option<&mut Segment> {
+        self.segments.iter_mut().find(|s| s.name == name)
+    }
+
+    /// Applies a layout placing segments at chosen addresses. Each non-empty line of the layout
+    /// names a segment followed by its address, '#' starts a comment:
+    ///
+    ///     .text 0x08048000
+    ///     .data 0x0804A000  # writable data on its own page
+    pub fn apply_layout(&mut self, layout: &str) -> Result<(), String> {
+        for (line_no, line) in layout.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() { continue; }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 2 {
+                return Err(format!("line {}: expected '<segment> <address>'.", line_no + 1));
+            }
+
+            let vaddr = parse_address(parts[1]).map_err(|e| format!("line {}: {}.", line_no + 1, e))?;
+            match self.get_segment_mut(parts[0]) {
+                Some(segment) => segment.vaddr = Some(vaddr),
+                None => return Err(format!("line {}: unknown segment '{}'.", line_no + 1, parts[0])),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets where the segment at index 'idx' starts. Called when laying out the binary.
+    pub fn set_segment_start(&mut self, idx: usize, start: Addr) {
+        self.segments[idx].start = start;
+    }
+
+    /// Gets the length of a segment in bytes.
+    pub fn segment_len(&self, idx: usize) -> usize {
+        self.blocks.iter().filter(|b| b.segment == idx).map(|b| b.len()).sum()
+    }
+
+    /// Gets the length of the current segment in bytes, i.e. the offset of the next instruction
+    /// from the start of the segment.
+    pub fn current_len(&self) -> usize {
+        self.segment_len(self.current_segment)
+    }
+
     /// Pushes an instruction block to the program labeled by 'label'.
     pub fn new_block(&mut self, label: &str) -> &mut ProgramBlock {
         self.blocks.push(ProgramBlock {
             label: label.to_string(),
             len: 0,
+            segment: self.current_segment,
+            location: None,
             instrs: Vec::new(),
+            locations: Vec::new(),
+            macro_locations: Vec::new(),
         });
 
         self.blocks.last_mut().unwrap()
     }
 
+    /// Pushes an instruction block to the program labeled by 'label' recording where the label
+    /// was defined.
+    pub fn new_block_at(&mut self, label: &str, location: SourceLocation) -> &mut ProgramBlock {
+        let block = self.new_block(label);
+        block.location = Some(location);
+        block
+    }
+
+    /// Gets every block along with its starting address, ordered segment by segment.
+    pub fn blocks(&self) -> Vec<(&ProgramBlock, Addr)> {
+        let mut blocks = Vec::new();
+
+        for (idx, segment) in self.segments.iter().enumerate() {
+            let mut addr = segment.start;
+            for block in self.blocks.iter().filter(|b| b.segment == idx) {
+                blocks.push((block, addr));
+                addr += block.len() as u64;
+            }
+        }
+
+        blocks
+    }
+
     /// Gets a mutable refence to a block by index
     pub fn get_block_mut(&mut self, idx: usize) -> Option<&mut ProgramBlock> {
         self.blocks.get_mut(idx)
@@ -62,31 +315,170 @@ impl Program {
         len
     }
 
-    pub fn set_entrypoint(&mut self, label: &str) {
-        let addr = self.get_addr(label).unwrap_or_default();
-        self.entry_point = addr;
+    /// Looks up the address of the entry point.
+    pub fn entry_point(&self) -> Option<Addr> {
+        self.get_addr(&self.entry)
     }
 
-    /// Looks up address of the start of the block labeled by 'label'.
+    /// Looks up address of the start of the block labeled by 'label', or the PLT entry of the
+    /// external function named 'label'.
     pub fn get_addr(&self, label: &str) -> Option<Addr> {
-        let mut addr = self.offset;
+        match self.blocks().into_iter().find(|(block, _)| block.label == label) {
+            Some((_, addr)) => Some(addr),
+            None => self.externs.iter().find(|e| e.name == label).map(|e| e.addr),
+        }
+    }
+
+    /// Gets the index of the segment the block labeled by 'label' belongs to.
+    pub fn segment_of(&self, label: &str) -> Option<usize> {
+        self.blocks.iter().find(|b| b.label == label).map(|b| b.segment)
+    }
+
+    /// Declares the symbol named 'name', returning the declaration to update.
+    pub fn declare(&mut self, name: &str, location: SourceLocation) -> &mut Symbol {
+        let idx = match self.symbols.iter().position(|s| s.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.symbols.push(Symbol {
+                    name: name.to_string(),
+                    binding: SymbolBinding::Global,
+                    visibility: SymbolVisibility::Default,
+                    location,
+                });
+                self.symbols.len() - 1
+            }
+        };
+
+        &mut self.symbols[idx]
+    }
+
+    /// Gets the binding of a label or external. Labels are local and externals global unless
+    /// declared otherwise.
+    pub fn binding(&self, name: &str) -> SymbolBinding {
+        match self.symbols.iter().find(|s| s.name == name) {
+            Some(symbol) => symbol.binding,
+            None if self.externs.iter().any(|e| e.name == name) => SymbolBinding::Global,
+            None => SymbolBinding::Local,
+        }
+    }
+
+    /// Gets the visibility of a label or external.
+    pub fn visibility(&self, name: &str) -> SymbolVisibility {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.visibility).unwrap_or(SymbolVisibility::Default)
+    }
+
+    /// Lists the labels exported from a shared library: those declared global or weak that
+    /// aren't hidden.
+    pub fn exported(&self) -> Vec<&str> {
+        self.symbols.iter()
+            .filter(|s| s.visibility == SymbolVisibility::Default && self.segment_of(&s.name).is_some())
+            .map(|s| s.name.as_str())
+            .collect()
+    }
+
+    /// Lists every label referenced by an instruction along with the instruction's source
+    /// location, and the line of the macro it was expanded from if any.
+    pub fn references(&self) -> Vec<(&str, Option<&SourceLocation>, Option<&MacroLocation>)> {
+        let mut references = Vec::new();
 
         for block in &self.blocks {
-            if block.label == label {
-                return Some(addr);
+            let locations = block.locations.iter().zip(&block.macro_locations);
+            for (instr, (location, macro_location)) in block.instrs.iter().zip(locations) {
+                if let Some(Value::Pointer(label, _) | Value::RelPointer(label, _)) = instr.value() {
+                    references.push((label.as_str(), location.as_ref(), macro_location.as_ref()));
+                }
             }
-            addr += block.len() as u64;
         }
 
-        None
+        references
     }
 
-    /// Converts the program into a vector of bytes.
-    pub fn as_vec(&self) -> Vec<u8> {
-        let mut addr = self.offset;
+    /// Lists every labeled block with its starting address and length, skipping the implicit
+    /// entry point block, the unlabeled blocks starting each segment and the internal labels.
+    pub fn labels(&self) -> Vec<(&str, Addr, usize)> {
+        let mut labels = Vec::new();
+
+        for (block, addr) in self.blocks() {
+            if block.label != "__entry_point__" && !block.label.is_empty() && !is_internal_label(&block.label) {
+                labels.push((block.label(), addr, block.len()));
+            }
+        }
 
+        labels
+    }
+
+    /// Lists the address of every absolute pointer encoded in the program. These need relocating
+    /// when the program is loaded somewhere other than the address it was laid out at.
+    pub fn absolute_pointers(&self) -> Vec<Addr> {
+        self.absolute_references().into_iter().map(|(addr, _, _)| addr).collect()
+    }
+
+    /// Lists every absolute pointer encoded in the program along with the label it points to and
+    /// the source location of the instruction.
+    pub fn absolute_references(&self) -> Vec<(Addr, &str, Option<&SourceLocation>)> {
+        self.pointers().into_iter()
+            .filter_map(|(_, addr, value, location)| match value {
+                Value::Pointer(label, _) => Some((addr, label.as_str(), location)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Lists every absolute and relative pointer encoded in the program along with the index of
+    /// the segment it's in, the address of the field and the source location of the instruction.
+    pub fn pointers(&self) -> Vec<(usize, Addr, &Value, Option<&SourceLocation>)> {
+        let mut pointers = Vec::new();
+
+        for (block, mut addr) in self.blocks() {
+            for (instr, location) in block.instrs.iter().zip(&block.locations) {
+                if let Some(value @ (Value::Pointer(..) | Value::RelPointer(..))) = instr.value() {
+                    pointers.push((block.segment, addr + (instr.len() - value.len()) as u64, value, location.as_ref()));
+                }
+                addr += instr.len() as u64;
+            }
+        }
+
+        pointers
+    }
+
+    /// Lists the address and length of every instruction with a known source location, in
+    /// layout order.
+    pub fn line_table(&self) -> Vec<(Addr, usize, &SourceLocation)> {
+        let mut table = Vec::new();
+
+        for (block, mut addr) in self.blocks() {
+            for (instr, location) in block.instrs.iter().zip(&block.locations) {
+                if let Some(location) = location {
+                    table.push((addr, instr.len(), location));
+                }
+                addr += instr.len() as u64;
+            }
+        }
+
+        table
+    }
+
+    /// Encodes every instruction separately, returning each one's address, bytes and source
+    /// location in layout order.
+    pub fn encode_with_locations(&self) -> Vec<(Addr, Vec<u8>, Option<&SourceLocation>)> {
+        let mut encoded = Vec::new();
+
+        for (block, mut addr) in self.blocks() {
+            for (instr, location) in block.instrs.iter().zip(&block.locations) {
+                let start = addr;
+                addr += instr.len() as u64;
+                encoded.push((start, self.encode_instruction(instr, addr), location.as_ref()));
+            }
+        }
+
+        encoded
+    }
+
+    /// Converts a segment of the program into a vector of bytes.
+    pub fn segment_as_vec(&self, idx: usize) -> Vec<u8> {
         let mut data = Vec::new();
-        for block in &self.blocks {
+        for (block, mut addr) in self.blocks() {
+            if block.segment != idx { continue; }
             for instr in &block.instrs {
                 addr += instr.len() as u64;
                 let instr_data = self.encode_instruction(instr, addr);
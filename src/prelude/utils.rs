@@ -40,3 +40,86 @@ pub fn dump_qword(word: u64, endian: Endianness) -> [u8; 8] {
         Endianness::Big => [b0, b1, b2, b3, b4, b5, b6, b7],
    }
 }
+
+/// Converts a double to an x87 80 bit extended float, giving the 64 bit mantissa (with its
+/// integer bit) and the sign and 15 bit exponent.
+pub fn to_extended(value: f64) -> (u64, u16) {
+    let bits = value.to_bits();
+    let sign = ((bits >> 63) as u16) << 15;
+    let exponent = ((bits >> 52) & 0x7FF) as u16;
+    let fraction = bits & ((1 << 52) - 1);
+
+    match exponent {
+        0 if fraction == 0 => (0, sign),
+        // Denormal doubles are normal as extended floats.
+        0 => {
+            let shift = fraction.leading_zeros();
+            (fraction << shift, sign | (15372 - shift as u16))
+        }
+        0x7FF => ((1 << 63) | (fraction << 11), sign | 0x7FFF),
+        _ => ((1 << 63) | (fraction << 11), sign | (exponent - 1023 + 16383)),
+    }
+}
+
+pub fn encode_uleb128(mut x: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (x & 0x7F) as u8;
+        x >>= 7;
+        if x == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+pub fn encode_sleb128(mut x: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (x & 0x7F) as u8;
+        x >>= 7;
+        let sign_clear = byte & 0x40 == 0;
+        if (x == 0 && sign_clear) || (x == -1 && !sign_clear) {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+pub fn read_word(data: &[u8], offset: usize, endian: Endianness) -> u16 {
+    let bytes = [data[offset], data[offset + 1]];
+    match endian {
+        Endianness::Little => u16::from_le_bytes(bytes),
+        Endianness::Big => u16::from_be_bytes(bytes),
+    }
+}
+
+pub fn read_dword(data: &[u8], offset: usize, endian: Endianness) -> u32 {
+    let bytes = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
+    match endian {
+        Endianness::Little => u32::from_le_bytes(bytes),
+        Endianness::Big => u32::from_be_bytes(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leb128() {
+        assert_eq!(encode_uleb128(0), vec![0x00]);
+        assert_eq!(encode_uleb128(127), vec![0x7F]);
+        assert_eq!(encode_uleb128(128), vec![0x80, 0x01]);
+        assert_eq!(encode_uleb128(624485), vec![0xE5, 0x8E, 0x26]);
+
+        assert_eq!(encode_sleb128(0), vec![0x00]);
+        assert_eq!(encode_sleb128(127), vec![0xFF, 0x00]);
+        assert_eq!(encode_sleb128(128), vec![0x80, 0x01]);
+        assert_eq!(encode_sleb128(-1), vec![0x7F]);
+        assert_eq!(encode_sleb128(-128), vec![0x80, 0x7F]);
+        assert_eq!(encode_sleb128(-129), vec![0xFF, 0x7E]);
+    }
+}
@@ -0,0 +1,33 @@
+ENTRY _start
+
+; write(fd, label, length)
+%macro write 2-3 msg_len
+    mov eax, 4
+    mov ebx, %1
+    mov ecx, %2
+    mov edx, %3
+    int 0x80
+%endmacro
+
+; Prints a message 'count' times.
+%macro repeat_write 2
+    mov edi, %1
+%%loop:
+    write 1, %2
+    dec edi
+    jnz %%loop
+%endmacro
+
+%macro exit 0-1 0
+    mov eax, 1
+    mov ebx, %1
+    int 0x80
+%endmacro
+
+_msg: 
+    DB "Hello Macros!",0xA
+    EQU msg_len $ - _msg
+
+_start:
+    repeat_write 3, _msg
+    exit
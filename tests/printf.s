@@ -0,0 +1,20 @@
+ENTRY _start
+NEEDED "libc.so.6"
+EXTERN printf
+EXTERN exit
+
+_fmt:
+    DB "Hello %s!",0xA,0
+_name:
+    DB "libc",0
+
+_start:
+    mov eax, _name
+    push eax         ; %s
+    mov eax, _fmt
+    push eax         ; format
+    call printf
+
+    mov eax, 0
+    push eax         ; status code 0
+    call exit